@@ -0,0 +1,624 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::SaltString;
+use axum::extract::{FromRequestParts, OptionalFromRequestParts, Request, State};
+use axum::http::{header, request::Parts, HeaderMap};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use shared::models::{AuthSession, Role, UserProfile};
+use uuid::Uuid;
+use crate::config::{AuthConfig, SigningKey};
+use crate::error::ApiError;
+use crate::state::AppState;
+use crate::storage::{now_secs, UserStorage};
+
+// An account as stored. `password_hash` is an Argon2id hash in PHC string format.
+#[derive(Clone, Debug)]
+pub struct User {
+    pub id: String,
+    pub email: String,
+    pub password_hash: String,
+    pub role: Role,
+    pub created_at: u64,
+}
+
+impl User {
+    pub fn new(email: String, password_hash: String, role: Role) -> Self {
+        User {
+            id: Uuid::new_v4().to_string(),
+            email,
+            password_hash,
+            role,
+            created_at: now_secs(),
+        }
+    }
+
+    pub fn profile(&self) -> UserProfile {
+        UserProfile {
+            id: self.id.clone(),
+            email: self.email.clone(),
+            role: self.role,
+            created_at: self.created_at,
+        }
+    }
+}
+
+// Emails are matched without surrounding whitespace and ignoring case
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn hash_blocking(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+// Hashing is deliberately slow, so it runs off the async worker threads
+pub async fn hash_password(password: String) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || hash_blocking(&password))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+// Checks the password against `hash`. Without a hash (unknown email) it is checked against a
+// throwaway one, so the response takes as long and doesn't reveal whether the account exists.
+pub async fn verify_password(password: String, hash: Option<String>) -> Result<bool, String> {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+    tokio::task::spawn_blocking(move || {
+        let known = hash.is_some();
+        let hash = hash.unwrap_or_else(|| {
+            DUMMY_HASH.get_or_init(|| hash_blocking("not a real password").unwrap_or_default()).clone()
+        });
+        let matches = PasswordHash::new(&hash)
+            .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+            .unwrap_or(false);
+        known && matches
+    })
+    .await
+    .map_err(|e| e.to_string())
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buf);
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Refresh tokens are stored as their SHA-256, so a leaked database doesn't hand out logins
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// Payload of an access token. `sid` is the login session, which all refresh tokens issued
+// since logging in share; `jti` identifies this token on the revocation list.
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    iss: String,
+    iat: u64,
+    exp: u64,
+    jti: String,
+    sid: String,
+}
+
+// Issues and checks tokens. Access tokens are short-lived HS256 JWTs naming their signing key
+// in the `kid` header, so keys can be rotated while tokens signed with the old one are still
+// around. Refresh tokens are random strings that can be used once, each use handing out a new pair.
+pub struct TokenIssuer {
+    issuer: String,
+    access_ttl: Duration,
+    refresh_ttl: Duration,
+    signing_key: String,
+    keys: HashMap<String, (EncodingKey, DecodingKey)>,
+}
+
+impl TokenIssuer {
+    pub fn new(config: &AuthConfig) -> Self {
+        let mut keys = config.keys.clone();
+        if keys.is_empty() {
+            eprintln!("No auth.keys configured; signing tokens with a random key that is lost on restart");
+            keys.push(SigningKey { id: format!("ephemeral-{}", random_hex(4)), secret: random_hex(32) });
+        }
+        let signing_key = config.signing_key.clone().unwrap_or_else(|| keys[0].id.clone());
+
+        TokenIssuer {
+            issuer: config.issuer.clone(),
+            access_ttl: config.access_token_ttl,
+            refresh_ttl: config.refresh_token_ttl,
+            signing_key,
+            keys: keys
+                .into_iter()
+                .map(|key| {
+                    let secret = key.secret.as_bytes();
+                    (key.id, (EncodingKey::from_secret(secret), DecodingKey::from_secret(secret)))
+                })
+                .collect(),
+        }
+    }
+
+    // Logs the user in, starting a new login session
+    pub fn login(&self, storage: &dyn UserStorage, user: &User) -> Result<AuthSession, String> {
+        let session = Uuid::new_v4().to_string();
+        let refresh_token = random_hex(32);
+        let refresh_expires_at = now_secs() + self.refresh_ttl.as_secs();
+        storage.insert_refresh_token(&hash_token(&refresh_token), &session, &user.id, refresh_expires_at)?;
+        self.session(user, &session, refresh_token, refresh_expires_at)
+    }
+
+    // Swaps a refresh token for a new access and refresh token; None if it can't be used (any more)
+    pub fn refresh(&self, storage: &dyn UserStorage, refresh_token: &str) -> Result<Option<AuthSession>, String> {
+        let new_token = random_hex(32);
+        let now = now_secs();
+        let refresh_expires_at = now + self.refresh_ttl.as_secs();
+        let rotated =
+            storage.rotate_refresh_token(&hash_token(refresh_token), &hash_token(&new_token), refresh_expires_at, now)?;
+        let Some((user_id, session)) = rotated else {
+            return Ok(None);
+        };
+        // Refresh tokens go away with their account, so the user exists
+        let user = storage.user_by_id(&user_id)?.ok_or_else(|| format!("User {} does not exist", user_id))?;
+        self.session(&user, &session, new_token, refresh_expires_at).map(Some)
+    }
+
+    fn session(&self, user: &User, session: &str, refresh_token: String, refresh_expires_at: u64) -> Result<AuthSession, String> {
+        let now = now_secs();
+        let claims = Claims {
+            sub: user.id.clone(),
+            iss: self.issuer.clone(),
+            iat: now,
+            exp: now + self.access_ttl.as_secs(),
+            jti: Uuid::new_v4().to_string(),
+            sid: session.to_string(),
+        };
+        let (key, _) = self.keys
+            .get(&self.signing_key)
+            .ok_or_else(|| format!("Signing key {} is not configured", self.signing_key))?;
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(self.signing_key.clone());
+        let access_token = jsonwebtoken::encode(&header, &claims, key).map_err(|e| e.to_string())?;
+
+        Ok(AuthSession {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_at: claims.exp,
+            refresh_token,
+            refresh_expires_at,
+            user: user.profile(),
+            merged_cart: Vec::new(),
+        })
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, ApiError> {
+        let kid = jsonwebtoken::decode_header(token)
+            .ok()
+            .and_then(|header| header.kid)
+            .ok_or(ApiError::Unauthorized)?;
+        let (_, key) = self.keys.get(&kid).ok_or(ApiError::Unauthorized)?;
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[&self.issuer]);
+        // Tokens are checked by the server that signed them, so there is no clock skew to allow for
+        validation.leeway = 0;
+        validation.set_required_spec_claims(&["exp", "iss", "sub"]);
+        jsonwebtoken::decode::<Claims>(token, key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => ApiError::TokenExpired,
+                _ => ApiError::Unauthorized,
+            })
+    }
+}
+
+// The logged-in caller, as established by `auth_layer` from an `Authorization: Bearer` access
+// token. Extracting it fails with 401 for guests; extract `Option<AuthUser>` where guests are
+// welcome too.
+#[derive(Clone, Debug)]
+pub struct AuthUser {
+    pub id: String,
+    // The access token's `jti` and expiry, and the login session it belongs to
+    token_id: String,
+    expires_at: u64,
+    session: String,
+}
+
+impl AuthUser {
+    // Ends the login session: the access token is revoked and its refresh tokens stop working
+    pub fn logout(&self, storage: &dyn UserStorage) -> Result<(), String> {
+        storage.revoke_access_token(&self.token_id, self.expires_at)?;
+        storage.revoke_refresh_family(&self.session)
+    }
+}
+
+async fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<Option<AuthUser>, ApiError> {
+    let Some(value) = headers.get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+    let token = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim())
+        .ok_or(ApiError::Unauthorized)?;
+
+    let claims = state.tokens.verify(token)?;
+    let token_id = claims.jti.clone();
+    let revoked = state.blocking(move |state| {
+        state.storage
+            .is_access_token_revoked(&token_id)
+            .map_err(ApiError::internal("Failed to check token revocation"))
+    }).await?;
+    if revoked {
+        return Err(ApiError::Unauthorized);
+    }
+    Ok(Some(AuthUser {
+        id: claims.sub,
+        token_id: claims.jti,
+        expires_at: claims.exp,
+        session: claims.sid,
+    }))
+}
+
+// Checks the access token of every request that sends one. Requests without one pass through as
+// guests; a bad, expired or revoked token is rejected rather than silently treated as a guest.
+pub async fn auth_layer(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    match authenticate(&state, req.headers()).await {
+        Ok(Some(user)) => {
+            req.extensions_mut().insert(user);
+        }
+        Ok(None) => {}
+        Err(e) => return e.into_response(),
+    }
+    next.run(req).await
+}
+
+// Lets only accounts with at least `role` through: 401 for guests, 403 for accounts with a lower role.
+// The role is looked up on every request rather than trusted from the token, so promoting or
+// demoting an account takes effect immediately. Must run inside `auth_layer`.
+pub async fn require_role(State((state, role)): State<(AppState, Role)>, req: Request, next: Next) -> Response {
+    let Some(user_id) = req.extensions().get::<AuthUser>().map(|user| user.id.clone()) else {
+        return ApiError::Unauthorized.into_response();
+    };
+    let account = state.blocking(move |state| {
+        state.storage.user_by_id(&user_id).map_err(|e| ApiError::Internal(format!("Failed to load user: {}", e)))
+    }).await;
+    match account {
+        Ok(Some(account)) if account.role >= role => next.run(req).await,
+        Ok(Some(_)) => ApiError::Forbidden.into_response(),
+        // The account was removed while the token was still valid
+        Ok(None) => ApiError::Unauthorized.into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for AuthUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<AuthUser>().cloned().ok_or(ApiError::Unauthorized)
+    }
+}
+
+impl<S: Send + Sync> OptionalFromRequestParts<S> for AuthUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Option<Self>, Self::Rejection> {
+        Ok(parts.extensions.get::<AuthUser>().cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use shared::api::{
+        ADMIN_ORDERS_PATH, ADMIN_PREFIX, ADMIN_PRODUCTS_PATH, ADMIN_USERS_PATH, ADMIN_USER_ROLE_PATH, CART_ENDPOINT,
+        CART_ITEMS_ENDPOINT, LOGIN_ENDPOINT, LOGOUT_ENDPOINT, REFRESH_ENDPOINT, REGISTER_ENDPOINT,
+    };
+    use crate::config::DEFAULT_TOKEN_ISSUER;
+    use crate::storage::MemoryStorage;
+    use crate::test_support::{TestApp, TestOptions, TestRequest, TestResponse};
+    use super::*;
+
+    async fn register(app: &TestApp, email: &str, password: &str) -> TestResponse {
+        TestRequest::post(REGISTER_ENDPOINT).json(json!({ "email": email, "password": password })).send(app).await
+    }
+
+    async fn login(app: &TestApp, email: &str, password: &str) -> TestResponse {
+        TestRequest::post(LOGIN_ENDPOINT).json(json!({ "email": email, "password": password })).send(app).await
+    }
+
+    #[tokio::test]
+    async fn passwords_are_stored_as_argon2_hashes() {
+        let hash = hash_password("correct horse".to_string()).await.unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse".to_string(), Some(hash.clone())).await.unwrap());
+        assert!(!verify_password("wrong horse".to_string(), Some(hash)).await.unwrap());
+        // Unknown accounts never match, whatever the password
+        assert!(!verify_password("not a real password".to_string(), None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn registering_logs_the_new_account_in() {
+        let app = TestApp::new();
+        let response = register(&app, "Ferris@Example.com", "correct horse").await;
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert_eq!(response.body["user"]["email"], "ferris@example.com");
+        assert_eq!(response.body["user"]["role"], "customer");
+        assert_eq!(response.body["token_type"], "Bearer");
+
+        let token = response.body["access_token"].as_str().unwrap();
+        let cart = TestRequest::get(CART_ENDPOINT).bearer(token).send(&app).await;
+        assert_eq!(cart.status, StatusCode::OK);
+
+        let taken = register(&app, "FERRIS@example.com", "another password").await;
+        assert_eq!((taken.status, taken.code()), (StatusCode::CONFLICT, Some("email_taken")));
+    }
+
+    #[tokio::test]
+    async fn registrations_need_an_email_and_a_long_enough_password() {
+        let app = TestApp::new();
+        let response = register(&app, "not an email", "short").await;
+        assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.body["fields"], json!({ "email": ["must be an email address"], "password": ["must be 8 to 128 characters long"] }));
+    }
+
+    #[tokio::test]
+    async fn configured_admins_register_as_admins() {
+        let mut options = TestOptions::default();
+        options.auth.admin_emails = vec!["boss@example.com".to_string()];
+        let app = TestApp::with(options);
+        assert_eq!(register(&app, "Boss@example.com", "correct horse").await.body["user"]["role"], "admin");
+    }
+
+    #[tokio::test]
+    async fn logging_in_needs_the_right_password() {
+        let app = TestApp::new();
+        register(&app, "ferris@example.com", "correct horse").await;
+
+        let wrong = login(&app, "ferris@example.com", "wrong horse").await;
+        let unknown = login(&app, "nobody@example.com", "correct horse").await;
+        // Both fail the same way, so they don't reveal which accounts exist
+        assert_eq!((wrong.status, wrong.code()), (StatusCode::UNAUTHORIZED, Some("invalid_credentials")));
+        assert_eq!((unknown.status, unknown.body), (wrong.status, wrong.body));
+
+        let response = login(&app, "FERRIS@example.com ", "correct horse").await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body["user"]["email"], "ferris@example.com");
+    }
+
+    #[tokio::test]
+    async fn accounts_keep_their_cart_across_logins() {
+        let app = TestApp::new();
+        let first = app.account("ferris@example.com", Role::Customer);
+        let add = TestRequest::post(CART_ITEMS_ENDPOINT).bearer(&first.access_token).json(json!({ "product_id": 1 }));
+        assert_eq!(add.send(&app).await.status, StatusCode::OK);
+
+        let user = app.state.storage.user_by_email("ferris@example.com").unwrap().unwrap();
+        let second = app.state.tokens.login(app.state.storage.as_ref(), &user).unwrap();
+        let cart = TestRequest::get(CART_ENDPOINT).bearer(&second.access_token).send(&app).await;
+        assert_eq!(cart.body["items"][0]["product"]["id"], 1);
+
+        // Guests don't see it
+        let guest = TestRequest::get(CART_ENDPOINT).send(&app).await;
+        assert_eq!(guest.body["items"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn logging_out_ends_the_login_session() {
+        let app = TestApp::new();
+        let session = app.account("ferris@example.com", Role::Customer);
+
+        assert_eq!(TestRequest::post(LOGOUT_ENDPOINT).send(&app).await.status, StatusCode::UNAUTHORIZED);
+        let response = TestRequest::post(LOGOUT_ENDPOINT).bearer(&session.access_token).send(&app).await;
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+
+        let response = TestRequest::get(CART_ENDPOINT).bearer(&session.access_token).send(&app).await;
+        assert_eq!((response.status, response.code()), (StatusCode::UNAUTHORIZED, Some("unauthorized")));
+        assert_eq!(response.header("www-authenticate").as_deref(), Some("Bearer"));
+    }
+
+    fn key(id: &str) -> SigningKey {
+        SigningKey { id: id.to_string(), secret: format!("{}-0123456789abcdef0123456789abcdef", id) }
+    }
+
+    fn keyed_app(keys: &[&str], signing_key: &str) -> TestApp {
+        let mut options = TestOptions::default();
+        options.auth.keys = keys.iter().map(|id| key(id)).collect();
+        options.auth.signing_key = Some(signing_key.to_string());
+        TestApp::with(options)
+    }
+
+    async fn refresh(app: &TestApp, refresh_token: &str) -> TestResponse {
+        TestRequest::post(REFRESH_ENDPOINT).json(json!({ "refresh_token": refresh_token })).send(app).await
+    }
+
+    async fn cart_status(app: &TestApp, access_token: &str) -> (StatusCode, Option<String>) {
+        let response = TestRequest::get(CART_ENDPOINT).bearer(access_token).send(app).await;
+        (response.status, response.code().map(str::to_string))
+    }
+
+    // An access token for `claims` signed with `key` as if by the server
+    fn sign(key: &SigningKey, claims: &Claims) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(key.id.clone());
+        jsonwebtoken::encode(&header, claims, &EncodingKey::from_secret(key.secret.as_bytes())).unwrap()
+    }
+
+    fn claims(user_id: &str, issuer: &str, exp: u64) -> Claims {
+        Claims {
+            sub: user_id.to_string(),
+            iss: issuer.to_string(),
+            iat: now_secs(),
+            exp,
+            jti: Uuid::new_v4().to_string(),
+            sid: Uuid::new_v4().to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_are_swapped_for_a_new_pair() {
+        let app = TestApp::new();
+        let session = app.account("ferris@example.com", Role::Customer);
+
+        let response = refresh(&app, &session.refresh_token).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let access_token = response.body["access_token"].as_str().unwrap();
+        let refresh_token = response.body["refresh_token"].as_str().unwrap();
+        assert_ne!(refresh_token, session.refresh_token);
+        assert_eq!(response.body["user"]["email"], "ferris@example.com");
+        assert_eq!(cart_status(&app, access_token).await.0, StatusCode::OK);
+
+        assert_eq!(refresh(&app, refresh_token).await.status, StatusCode::OK);
+        let unknown = refresh(&app, "0123456789abcdef").await;
+        assert_eq!((unknown.status, unknown.code()), (StatusCode::UNAUTHORIZED, Some("invalid_refresh_token")));
+    }
+
+    #[tokio::test]
+    async fn reusing_a_refresh_token_revokes_its_whole_family() {
+        let app = TestApp::new();
+        let session = app.account("ferris@example.com", Role::Customer);
+        let user = app.state.storage.user_by_email("ferris@example.com").unwrap().unwrap();
+        let other_login = app.state.tokens.login(app.state.storage.as_ref(), &user).unwrap();
+
+        let rotated = refresh(&app, &session.refresh_token).await;
+        let newest = rotated.body["refresh_token"].as_str().unwrap();
+        // Only a stolen copy would present the old token again
+        assert_eq!(refresh(&app, &session.refresh_token).await.code(), Some("invalid_refresh_token"));
+        assert_eq!(refresh(&app, newest).await.code(), Some("invalid_refresh_token"));
+
+        // Other logins of the same account are not affected
+        assert_eq!(refresh(&app, &other_login.refresh_token).await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn logging_out_revokes_the_refresh_tokens_too() {
+        let app = TestApp::new();
+        let session = app.account("ferris@example.com", Role::Customer);
+        TestRequest::post(LOGOUT_ENDPOINT).bearer(&session.access_token).send(&app).await;
+        assert_eq!(refresh(&app, &session.refresh_token).await.code(), Some("invalid_refresh_token"));
+    }
+
+    #[tokio::test]
+    async fn expired_access_tokens_ask_for_a_refresh() {
+        let app = keyed_app(&["k1"], "k1");
+        let token = sign(&key("k1"), &claims("someone", DEFAULT_TOKEN_ISSUER, now_secs() - 1));
+        let response = TestRequest::get(CART_ENDPOINT).bearer(&token).send(&app).await;
+        assert_eq!((response.status, response.code()), (StatusCode::UNAUTHORIZED, Some("token_expired")));
+        assert!(response.header("www-authenticate").unwrap().contains("invalid_token"));
+    }
+
+    #[tokio::test]
+    async fn bad_access_tokens_are_rejected_rather_than_treated_as_guests() {
+        let app = keyed_app(&["k1"], "k1");
+        let later = now_secs() + 60;
+        let tokens = [
+            sign(&key("k1"), &claims("someone", "someone-else", later)),
+            sign(&SigningKey { id: "k1".to_string(), secret: "guessed".to_string() }, &claims("someone", DEFAULT_TOKEN_ISSUER, later)),
+            sign(&key("k2"), &claims("someone", DEFAULT_TOKEN_ISSUER, later)),
+            "not.a.jwt".to_string(),
+        ];
+        for token in tokens {
+            assert_eq!(cart_status(&app, &token).await, (StatusCode::UNAUTHORIZED, Some("unauthorized".to_string())));
+        }
+
+        let session = app.account("ferris@example.com", Role::Customer);
+        let basic = TestRequest::get(CART_ENDPOINT).header("authorization", &format!("Basic {}", session.access_token));
+        assert_eq!(basic.send(&app).await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn tokens_signed_with_a_retired_key_work_until_it_is_removed() {
+        let issuer = |keys: &[&str], signing_key: &str| TokenIssuer::new(&AuthConfig {
+            keys: keys.iter().map(|id| key(id)).collect(),
+            signing_key: Some(signing_key.to_string()),
+            ..AuthConfig::default()
+        });
+        let storage = MemoryStorage::new();
+        let user = User::new("ferris@example.com".to_string(), String::new(), Role::Customer);
+        let token = issuer(&["old"], "old").login(&storage, &user).unwrap().access_token;
+
+        // The new key signs, the old one still verifies
+        let rotated = issuer(&["new", "old"], "new");
+        assert_eq!(rotated.verify(&token).map(|claims| claims.sub).ok(), Some(user.id.clone()));
+        let fresh = rotated.login(&storage, &user).unwrap().access_token;
+        assert_eq!(jsonwebtoken::decode_header(&fresh).unwrap().kid.as_deref(), Some("new"));
+
+        let retired = issuer(&["new"], "new");
+        assert!(matches!(retired.verify(&token), Err(ApiError::Unauthorized)));
+        assert!(retired.verify(&fresh).is_ok());
+    }
+
+    fn admin(path: &str) -> String {
+        format!("{}{}", ADMIN_PREFIX, path)
+    }
+
+    async fn status(app: &TestApp, request: TestRequest) -> (StatusCode, Option<String>) {
+        let response = request.send(app).await;
+        (response.status, response.code().map(str::to_string))
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_a_staff_account() {
+        let app = TestApp::new();
+        let customer = app.account("customer@example.com", Role::Customer);
+        let staff = app.account("staff@example.com", Role::Staff);
+        let orders = || TestRequest::get(&admin(ADMIN_ORDERS_PATH));
+
+        assert_eq!(status(&app, orders()).await, (StatusCode::UNAUTHORIZED, Some("unauthorized".to_string())));
+        assert_eq!(status(&app, orders().bearer(&customer.access_token)).await, (StatusCode::FORBIDDEN, Some("forbidden".to_string())));
+        assert_eq!(status(&app, orders().bearer(&staff.access_token)).await, (StatusCode::OK, None));
+
+        // Guards run before the payload is looked at
+        let create = TestRequest::post(&admin(ADMIN_PRODUCTS_PATH)).bearer(&customer.access_token).json(json!({}));
+        assert_eq!(status(&app, create).await.0, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn managing_accounts_needs_an_admin() {
+        let app = TestApp::new();
+        let staff = app.account("staff@example.com", Role::Staff);
+        let admin_session = app.account("admin@example.com", Role::Admin);
+        let users = || TestRequest::get(&admin(ADMIN_USERS_PATH));
+
+        assert_eq!(status(&app, users()).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(&app, users().bearer(&staff.access_token)).await.0, StatusCode::FORBIDDEN);
+        let response = users().bearer(&admin_session.access_token).send(&app).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body.as_array().map(Vec::len), Some(2));
+        // Admins can do everything staff can
+        assert_eq!(status(&app, TestRequest::get(&admin(ADMIN_ORDERS_PATH)).bearer(&admin_session.access_token)).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn role_changes_apply_to_tokens_already_issued() {
+        let app = TestApp::new();
+        let admin_session = app.account("admin@example.com", Role::Admin);
+        let staff = app.account("staff@example.com", Role::Staff);
+        let orders = || TestRequest::get(&admin(ADMIN_ORDERS_PATH)).bearer(&staff.access_token);
+        assert_eq!(status(&app, orders()).await.0, StatusCode::OK);
+
+        let demote = TestRequest::post(&admin(&ADMIN_USER_ROLE_PATH.replace("{id}", &staff.user.id)))
+            .bearer(&admin_session.access_token)
+            .json(json!({ "role": "customer" }));
+        let response = demote.send(&app).await;
+        assert_eq!((response.status, &response.body["role"]), (StatusCode::OK, &json!("customer")));
+        assert_eq!(status(&app, orders()).await.0, StatusCode::FORBIDDEN);
+
+        let missing = TestRequest::post(&admin(&ADMIN_USER_ROLE_PATH.replace("{id}", "nobody")))
+            .bearer(&admin_session.access_token)
+            .json(json!({ "role": "staff" }));
+        assert_eq!(status(&app, missing).await, (StatusCode::NOT_FOUND, Some("user_not_found".to_string())));
+    }
+}
@@ -0,0 +1,203 @@
+use shared::models::{CartItem, MergedCartItem, MAX_ITEM_QUANTITY};
+use crate::config::CartMergePolicy;
+use crate::state::AppState;
+
+// The quantity a product ends up with; 0 means it isn't in the account's cart yet
+fn merged_quantity(policy: CartMergePolicy, account: u32, guest: u32) -> u32 {
+    if account == 0 {
+        return guest;
+    }
+    match policy {
+        CartMergePolicy::Sum => account.saturating_add(guest),
+        CartMergePolicy::Max => account.max(guest),
+        CartMergePolicy::Account => account,
+    }
+}
+
+// Moves the items of the guest session's cart into the account's cart according to the configured
+// policy, returning what was merged. The guest's reservations are released first so they don't
+// count against the account; quantities that don't fit in stock are cut down to what is available.
+pub fn merge_guest_cart(state: &AppState, guest_id: &str, account_id: &str) -> Result<Vec<MergedCartItem>, String> {
+    // Account carts are kept under the account id, so a header naming one is not a guest session
+    if guest_id == account_id || state.storage.user_by_id(guest_id)?.is_some() {
+        return Ok(Vec::new());
+    }
+
+    let guest_cart = state.sessions.with_cart(guest_id, std::mem::take)?;
+    if guest_cart.is_empty() {
+        return Ok(Vec::new());
+    }
+    for item in &guest_cart {
+        if let Err(e) = state.inventory.release(guest_id, item.product.id) {
+            eprintln!("Failed to release stock of product {}: {}", item.product.id, e);
+        }
+    }
+
+    state.sessions.with_cart(account_id, |cart| {
+        let mut merged = Vec::new();
+        for guest_item in guest_cart {
+            let product_id = guest_item.product.id;
+            let account_quantity = cart.iter().find(|i| i.product.id == product_id).map_or(0, |i| i.quantity);
+            let wanted = merged_quantity(state.cart_merge, account_quantity, guest_item.quantity).min(MAX_ITEM_QUANTITY);
+
+            let mut quantity = account_quantity;
+            if wanted > account_quantity {
+                let available = state.inventory.available(account_id, product_id)?;
+                let fits = wanted.min(available.max(account_quantity));
+                if fits > account_quantity && state.inventory.reserve(account_id, product_id, fits)? {
+                    quantity = fits;
+                }
+            }
+
+            if quantity > account_quantity {
+                match cart.iter_mut().find(|i| i.product.id == product_id) {
+                    Some(item) => item.quantity = quantity,
+                    None => cart.push(CartItem { product: guest_item.product.clone(), quantity }),
+                }
+            }
+            merged.push(MergedCartItem {
+                product_id,
+                title: guest_item.product.title,
+                guest_quantity: guest_item.quantity,
+                account_quantity,
+                quantity,
+                limited_by_stock: quantity < wanted,
+            });
+        }
+        Ok(merged)
+    })?
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use shared::api::{CART_ENDPOINT, CART_ITEMS_ENDPOINT, REGISTER_ENDPOINT};
+    use shared::models::Role;
+    use crate::test_support::{TestApp, TestOptions, TestRequest};
+    use super::*;
+
+    fn app(policy: CartMergePolicy) -> TestApp {
+        TestApp::with(TestOptions { cart_merge: policy, ..TestOptions::default() })
+    }
+
+    // Whose cart an item is added to: a guest session's, or an account's by its access token
+    enum Owner<'a> {
+        Guest(&'a str),
+        Account(&'a str),
+    }
+
+    async fn add(app: &TestApp, owner: Owner<'_>, product_id: u32, quantity: u32) {
+        let request = TestRequest::post(CART_ITEMS_ENDPOINT).json(json!({ "product_id": product_id, "quantity": quantity }));
+        let request = match owner {
+            Owner::Guest(session) => request.session(session),
+            Owner::Account(token) => request.bearer(token),
+        };
+        assert_eq!(request.send(app).await.status, StatusCode::OK);
+    }
+
+    // (product id, guest quantity, account quantity, merged quantity, limited by stock)
+    fn summary(merged: &[MergedCartItem]) -> Vec<(u32, u32, u32, u32, bool)> {
+        merged
+            .iter()
+            .map(|m| (m.product_id, m.guest_quantity, m.account_quantity, m.quantity, m.limited_by_stock))
+            .collect()
+    }
+
+    // Merges a guest cart of 3 × product 1 and 2 × product 2 into an account cart holding 4 × product 1
+    async fn merge(policy: CartMergePolicy) -> (TestApp, String, Vec<MergedCartItem>) {
+        let app = app(policy);
+        let account = app.account("ferris@example.com", Role::Customer);
+        let user_id = account.user.id.clone();
+        add(&app, Owner::Account(&account.access_token), 1, 4).await;
+        let guest = app.guest().await;
+        add(&app, Owner::Guest(&guest), 1, 3).await;
+        add(&app, Owner::Guest(&guest), 2, 2).await;
+
+        let merged = merge_guest_cart(&app.state, &guest, &user_id).unwrap();
+        assert!(app.state.sessions.with_cart(&guest, |cart| cart.is_empty()).unwrap());
+        (app, user_id, merged)
+    }
+
+    #[test]
+    fn policies_decide_the_quantity_of_products_in_both_carts() {
+        assert_eq!(merged_quantity(CartMergePolicy::Sum, 4, 3), 7);
+        assert_eq!(merged_quantity(CartMergePolicy::Max, 4, 3), 4);
+        assert_eq!(merged_quantity(CartMergePolicy::Max, 2, 3), 3);
+        assert_eq!(merged_quantity(CartMergePolicy::Account, 2, 3), 2);
+        // Products only the guest had are always taken over
+        for policy in [CartMergePolicy::Sum, CartMergePolicy::Max, CartMergePolicy::Account] {
+            assert_eq!(merged_quantity(policy, 0, 3), 3);
+        }
+        assert_eq!(merged_quantity(CartMergePolicy::Sum, u32::MAX, 1), u32::MAX);
+    }
+
+    #[tokio::test]
+    async fn sum_adds_the_guest_quantities() {
+        let (app, user_id, merged) = merge(CartMergePolicy::Sum).await;
+        assert_eq!(summary(&merged), [(1, 3, 4, 7, false), (2, 2, 0, 2, false)]);
+        let cart = app.state.sessions.with_cart(&user_id, |cart| cart.iter().map(|i| (i.product.id, i.quantity)).collect::<Vec<_>>());
+        assert_eq!(cart.unwrap(), [(1, 7), (2, 2)]);
+    }
+
+    #[tokio::test]
+    async fn max_and_account_keep_larger_or_account_quantities() {
+        let (_, _, merged) = merge(CartMergePolicy::Max).await;
+        assert_eq!(summary(&merged), [(1, 3, 4, 4, false), (2, 2, 0, 2, false)]);
+        let (_, _, merged) = merge(CartMergePolicy::Account).await;
+        assert_eq!(summary(&merged), [(1, 3, 4, 4, false), (2, 2, 0, 2, false)]);
+    }
+
+    #[tokio::test]
+    async fn merged_quantities_are_cut_down_to_the_stock() {
+        let app = app(CartMergePolicy::Sum);
+        let account = app.account("ferris@example.com", Role::Customer);
+        add(&app, Owner::Account(&account.access_token), 1, 5).await;
+        let guest = app.guest().await;
+        add(&app, Owner::Guest(&guest), 1, 3).await;
+        let other = app.guest().await;
+        add(&app, Owner::Guest(&other), 1, 2).await;
+        // A stock count finds one less than there was
+        app.state.inventory.set(1, 9).unwrap();
+
+        // The guest's own reservation is released first, leaving 7 next to the other shopper's 2
+        let merged = merge_guest_cart(&app.state, &guest, &account.user.id).unwrap();
+        assert_eq!(summary(&merged), [(1, 3, 5, 7, true)]);
+        assert_eq!(app.state.inventory.level(1).map(|level| level.reserved), Ok(9));
+    }
+
+    #[tokio::test]
+    async fn account_ids_are_not_merged_as_guest_sessions() {
+        let (app, user_id, _) = merge(CartMergePolicy::Sum).await;
+        let other = app.account("other@example.com", Role::Customer);
+        assert!(merge_guest_cart(&app.state, &user_id, &other.user.id).unwrap().is_empty());
+        assert!(merge_guest_cart(&app.state, &user_id, &user_id).unwrap().is_empty());
+        assert_eq!(app.state.sessions.with_cart(&user_id, |cart| cart.len()), Ok(2));
+    }
+
+    #[tokio::test]
+    async fn signing_up_reports_what_was_merged() {
+        let app = app(CartMergePolicy::Sum);
+        let guest = app.guest().await;
+        add(&app, Owner::Guest(&guest), 2, 2).await;
+
+        let response = TestRequest::post(REGISTER_ENDPOINT)
+            .session(&guest)
+            .json(json!({ "email": "ferris@example.com", "password": "correct horse" }))
+            .send(&app)
+            .await;
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert_eq!(response.body["merged_cart"], json!([{
+            "product_id": 2,
+            "title": "Borrow Checker Mug",
+            "guest_quantity": 2,
+            "account_quantity": 0,
+            "quantity": 2,
+            "limited_by_stock": false,
+        }]));
+
+        let token = response.body["access_token"].as_str().unwrap();
+        let cart = TestRequest::get(CART_ENDPOINT).bearer(token).send(&app).await;
+        assert_eq!(cart.body["items"][0]["quantity"], 2);
+    }
+}
@@ -0,0 +1,207 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use shared::models::Product;
+use crate::config::{CatalogConfig, CatalogSource};
+
+pub const DEFAULT_FAKESTORE_URL: &str = "https://fakestoreapi.com";
+
+// Source of the products the shop sells
+#[async_trait]
+pub trait ProductCatalog: Send + Sync {
+    async fn products(&self) -> Result<Vec<Product>, String>;
+}
+
+// Products fetched from the Fake Store API (or anything serving the same `/products` shape)
+pub struct FakeStoreCatalog {
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl FakeStoreCatalog {
+    // `timeout` bounds a whole request, `connect_timeout` just establishing the connection
+    pub fn new(base_url: impl Into<String>, timeout: Duration, connect_timeout: Duration) -> Result<Self, String> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .connect_timeout(connect_timeout)
+            .build()
+            .map_err(|e| format!("Failed to create the catalog HTTP client: {}", e))?;
+        Ok(FakeStoreCatalog {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            client,
+        })
+    }
+}
+
+#[async_trait]
+impl ProductCatalog for FakeStoreCatalog {
+    async fn products(&self) -> Result<Vec<Product>, String> {
+        let url = format!("{}/products", self.base_url);
+        let res = self.client
+            .get(&url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| format!("Failed to fetch products: {}", e))?;
+
+        res.json().await.map_err(|e| format!("Failed to parse products: {}", e))
+    }
+}
+
+// Products read from a local JSON or YAML fixture file, re-read on every call
+pub struct FileCatalog {
+    path: PathBuf,
+}
+
+impl FileCatalog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileCatalog { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl ProductCatalog for FileCatalog {
+    async fn products(&self) -> Result<Vec<Product>, String> {
+        let contents = tokio::fs::read_to_string(&self.path)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", self.path.display(), e))?;
+
+        let is_yaml = matches!(
+            self.path.extension().and_then(|ext| ext.to_str()),
+            Some("yaml") | Some("yml")
+        );
+        if is_yaml {
+            serde_yaml::from_str(&contents).map_err(|e| format!("Failed to parse {}: {}", self.path.display(), e))
+        } else {
+            serde_json::from_str(&contents).map_err(|e| format!("Failed to parse {}: {}", self.path.display(), e))
+        }
+    }
+}
+
+// Fixed set of products held in memory
+pub struct InMemoryCatalog {
+    products: Vec<Product>,
+}
+
+impl InMemoryCatalog {
+    pub fn new(products: Vec<Product>) -> Self {
+        InMemoryCatalog { products }
+    }
+}
+
+#[async_trait]
+impl ProductCatalog for InMemoryCatalog {
+    async fn products(&self) -> Result<Vec<Product>, String> {
+        Ok(self.products.clone())
+    }
+}
+
+// Builds the catalog chosen in the configuration
+pub fn from_config(config: &CatalogConfig) -> Result<Arc<dyn ProductCatalog>, String> {
+    match config.source {
+        CatalogSource::Fakestore => Ok(Arc::new(FakeStoreCatalog::new(
+            config.url.as_str(),
+            config.timeout,
+            config.connect_timeout,
+        )?)),
+        CatalogSource::File => {
+            let path = config.file
+                .clone()
+                .ok_or_else(|| "catalog.file must be set when the catalog source is file".to_string())?;
+            Ok(Arc::new(FileCatalog::new(path)))
+        }
+        // Starts empty; mainly useful when products are supplied in code
+        CatalogSource::Memory => Ok(Arc::new(InMemoryCatalog::new(Vec::new()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::Router;
+    use super::*;
+
+    const PRODUCTS: &str = include_str!("../fixtures/products.json");
+
+    const YAML: &str = "
+- id: 7
+  title: Crab Socks
+  price: 79.0
+  description: Two pairs
+  category: clothing
+  image: https://example.com/socks.png
+";
+
+    // Writes `contents` to a new temporary file with the given extension
+    fn temp_file(extension: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rusty-cart-catalog-{}.{}", uuid::Uuid::new_v4(), extension));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    async fn load(extension: &str, contents: &str) -> Result<Vec<Product>, String> {
+        let path = temp_file(extension, contents);
+        let products = FileCatalog::new(&path).products().await;
+        std::fs::remove_file(&path).unwrap();
+        products
+    }
+
+    // Serves `router` on a free local port and returns its base URL
+    async fn serve(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        url
+    }
+
+    fn fakestore(url: &str) -> FakeStoreCatalog {
+        FakeStoreCatalog::new(url, Duration::from_secs(5), Duration::from_secs(1)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn files_are_read_as_json_or_yaml_by_extension() {
+        let products = load("json", PRODUCTS).await.unwrap();
+        assert_eq!(products, serde_json::from_str::<Vec<Product>>(PRODUCTS).unwrap());
+
+        for extension in ["yaml", "yml"] {
+            let products = load(extension, YAML).await.unwrap();
+            assert_eq!(products.iter().map(|p| (p.id, p.title.as_str())).collect::<Vec<_>>(), [(7, "Crab Socks")]);
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_and_missing_files_are_errors() {
+        let err = load("json", "[{ \"id\": 1, ").await.unwrap_err();
+        assert!(err.starts_with("Failed to parse"), "{}", err);
+        // YAML isn't accepted under a JSON name
+        assert!(load("json", YAML).await.is_err());
+        assert!(load("yaml", "- id: [").await.is_err());
+
+        let err = FileCatalog::new("/nonexistent/products.json").products().await.unwrap_err();
+        assert!(err.starts_with("Failed to read /nonexistent/products.json"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn fakestore_products_are_fetched_from_the_products_path() {
+        let url = serve(Router::new().route("/products", get(|| async { ([("content-type", "application/json")], PRODUCTS) }))).await;
+        let expected: Vec<Product> = serde_json::from_str(PRODUCTS).unwrap();
+        assert_eq!(fakestore(&url).products().await.unwrap(), expected);
+        // A trailing slash on the base URL doesn't matter
+        assert_eq!(fakestore(&format!("{}/", url)).products().await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn fakestore_failures_are_errors() {
+        let router = Router::new()
+            .route("/down/products", get(|| async { StatusCode::SERVICE_UNAVAILABLE }))
+            .route("/garbled/products", get(|| async { "<html>maintenance</html>" }));
+        let url = serve(router).await;
+
+        let err = fakestore(&format!("{}/down", url)).products().await.unwrap_err();
+        assert!(err.starts_with("Failed to fetch products") && err.contains("503"), "{}", err);
+        let err = fakestore(&format!("{}/garbled", url)).products().await.unwrap_err();
+        assert!(err.starts_with("Failed to parse products"), "{}", err);
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+use crate::catalog::ProductCatalog;
+use crate::storage::ProductStorage;
+use shared::models::Product;
+use validator::Validate;
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// How long requests don't try the upstream again after a failed fetch
+pub const FAILURE_BACKOFF: Duration = Duration::from_secs(30);
+
+// Products served from the cache; `stale` is set when the upstream failed and older data was returned
+pub struct CatalogSnapshot {
+    pub products: Vec<Product>,
+    pub stale: bool,
+}
+
+struct CacheEntry {
+    // As fetched from the source, and with the admin API's edits applied
+    upstream: Vec<Product>,
+    products: Vec<Product>,
+    fetched_at: Instant,
+}
+
+// Keeps the last successful catalog fetch in memory so requests don't hit the upstream every time.
+// Products created, changed or deleted through the admin API are kept in `edits` and laid over it.
+#[derive(Clone)]
+pub struct CachedCatalog {
+    source: Arc<dyn ProductCatalog>,
+    edits: Arc<dyn ProductStorage>,
+    ttl: Duration,
+    cache: Arc<RwLock<Option<CacheEntry>>>,
+    // Held while fetching, so concurrent requests share one fetch instead of starting their own
+    refresh_lock: Arc<tokio::sync::Mutex<()>>,
+    // When and why the last fetch failed, cleared by a successful one
+    last_failure: Arc<Mutex<Option<(Instant, String)>>>,
+    // Serializes edits, so two new products can't get the same id
+    edit_lock: Arc<Mutex<()>>,
+}
+
+impl CachedCatalog {
+    pub fn new(source: Arc<dyn ProductCatalog>, edits: Arc<dyn ProductStorage>, ttl: Duration) -> Self {
+        CachedCatalog {
+            source,
+            edits,
+            ttl,
+            cache: Arc::new(RwLock::new(None)),
+            refresh_lock: Arc::new(tokio::sync::Mutex::new(())),
+            last_failure: Arc::new(Mutex::new(None)),
+            edit_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    // Fetches the catalog from the source and replaces the cache, returning the number of products.
+    // Waits for a fetch already in progress, then fetches again regardless of any backoff.
+    pub async fn refresh(&self) -> Result<usize, String> {
+        let _guard = self.refresh_lock.lock().await;
+        self.fetch().await
+    }
+
+    // Callers must hold `refresh_lock`
+    async fn fetch(&self) -> Result<usize, String> {
+        let result = self.load().await;
+        let mut last_failure = self.last_failure.lock().unwrap_or_else(PoisonError::into_inner);
+        *last_failure = result.as_ref().err().map(|e| (Instant::now(), e.clone()));
+        result
+    }
+
+    async fn load(&self) -> Result<usize, String> {
+        let mut products = self.source.products().await?;
+        // A bad entry upstream shouldn't take the whole catalog down, so it is left out
+        products.retain(|product| match product.validate() {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Skipping invalid product {}: {}", product.id, e);
+                false
+            }
+        });
+        let (products, edited) = self.blocking(move |catalog| {
+            let edited = catalog.apply_edits(&products)?;
+            Ok((products, edited))
+        }).await?;
+        let count = edited.len();
+        let mut cache = self.cache.write().map_err(|e| e.to_string())?;
+        *cache = Some(CacheEntry {
+            upstream: products,
+            products: edited,
+            fetched_at: Instant::now(),
+        });
+        Ok(count)
+    }
+
+    // Runs `f` on Tokio's blocking thread pool, since the edits are kept in storage that may block
+    async fn blocking<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&CachedCatalog) -> Result<R, String> + Send + 'static,
+    ) -> Result<R, String> {
+        let catalog = self.clone();
+        tokio::task::spawn_blocking(move || f(&catalog))
+            .await
+            .map_err(|e| format!("Blocking task failed: {}", e))?
+    }
+
+    // The source's products with the stored edits applied; new products come last
+    fn apply_edits(&self, upstream: &[Product]) -> Result<Vec<Product>, String> {
+        let mut edits: HashMap<u32, Option<Product>> = self.edits.product_edits()?.into_iter().collect();
+        let mut products: Vec<Product> = upstream
+            .iter()
+            .filter_map(|product| match edits.remove(&product.id) {
+                Some(edit) => edit,
+                None => Some(product.clone()),
+            })
+            .collect();
+        let mut added: Vec<Product> = edits.into_values().flatten().collect();
+        added.sort_by_key(|product| product.id);
+        products.extend(added);
+        Ok(products)
+    }
+
+    // Re-applies the edits to the cached source products after one was stored
+    fn reapply_edits(&self) -> Result<(), String> {
+        let mut cache = self.cache.write().map_err(|e| e.to_string())?;
+        if let Some(entry) = cache.as_mut() {
+            entry.products = self.apply_edits(&entry.upstream)?;
+        }
+        Ok(())
+    }
+
+    // Adds a product with the next free id: one past the highest id the source or an edit has used,
+    // so a deleted product's id is not handed out again
+    pub async fn create(&self, build: impl FnOnce(u32) -> Product + Send + 'static) -> Result<Product, String> {
+        // Loads the cache if this is the first request
+        self.products().await?;
+        self.blocking(move |catalog| {
+            let _guard = catalog.edit_lock.lock().unwrap_or_else(PoisonError::into_inner);
+            let highest_upstream = {
+                let cache = catalog.cache.read().map_err(|e| e.to_string())?;
+                cache.as_ref().and_then(|entry| entry.upstream.iter().map(|p| p.id).max()).unwrap_or(0)
+            };
+            let highest_edit = catalog.edits.product_edits()?.iter().map(|(id, _)| *id).max().unwrap_or(0);
+            let id = highest_upstream.max(highest_edit).checked_add(1).ok_or("Product ids are exhausted")?;
+
+            let product = build(id);
+            catalog.edits.save_product(&product)?;
+            catalog.reapply_edits()?;
+            Ok(product)
+        }).await
+    }
+
+    // Replaces a product; returns false if the catalog doesn't have it
+    pub async fn update(&self, product: Product) -> Result<bool, String> {
+        if self.product(product.id).await?.is_none() {
+            return Ok(false);
+        }
+        self.blocking(move |catalog| {
+            let _guard = catalog.edit_lock.lock().unwrap_or_else(PoisonError::into_inner);
+            catalog.edits.save_product(&product)?;
+            catalog.reapply_edits()?;
+            Ok(true)
+        }).await
+    }
+
+    // Removes a product from the catalog, even if the source still has it; false if it isn't there
+    pub async fn delete(&self, id: u32) -> Result<bool, String> {
+        if self.product(id).await?.is_none() {
+            return Ok(false);
+        }
+        self.blocking(move |catalog| {
+            let _guard = catalog.edit_lock.lock().unwrap_or_else(PoisonError::into_inner);
+            catalog.edits.delete_product(id)?;
+            catalog.reapply_edits()?;
+            Ok(true)
+        }).await
+    }
+
+    // Returns cached products, refreshing them first if they are older than the TTL.
+    // If that refresh fails, another request is already refreshing or the upstream failed less than
+    // FAILURE_BACKOFF ago, the old products are returned marked as stale.
+    pub async fn products(&self) -> Result<CatalogSnapshot, String> {
+        if let Some(products) = self.cached(true)? {
+            return Ok(CatalogSnapshot { products, stale: false });
+        }
+
+        let result = match self.cached(false)? {
+            // There is something to serve meanwhile, so don't wait for another request's fetch
+            Some(_) => match self.refresh_lock.try_lock() {
+                Ok(_guard) => self.fetch_unless_backing_off().await,
+                Err(_) => Err("Catalog refresh in progress".to_string()),
+            },
+            None => {
+                let _guard = self.refresh_lock.lock().await;
+                self.fetch_unless_backing_off().await
+            }
+        };
+
+        match result {
+            Ok(()) => match self.cached(false)? {
+                Some(products) => Ok(CatalogSnapshot { products, stale: false }),
+                None => Err("Catalog cache is empty after refresh".to_string()),
+            },
+            Err(e) => match self.cached(false)? {
+                Some(products) => {
+                    eprintln!("Serving stale catalog: {}", e);
+                    Ok(CatalogSnapshot { products, stale: true })
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    // Callers must hold `refresh_lock`. A request that waited for it may find the cache already
+    // refreshed, or the fetch it waited for failed.
+    async fn fetch_unless_backing_off(&self) -> Result<(), String> {
+        if self.cached(true)?.is_some() {
+            return Ok(());
+        }
+        let last_failure = self.last_failure.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        match last_failure {
+            Some((at, e)) if at.elapsed() < FAILURE_BACKOFF => Err(e),
+            _ => self.fetch().await.map(|_| ()),
+        }
+    }
+
+    pub async fn product(&self, id: u32) -> Result<Option<Product>, String> {
+        Ok(self.products().await?.products.into_iter().find(|p| p.id == id))
+    }
+
+    fn cached(&self, fresh_only: bool) -> Result<Option<Vec<Product>>, String> {
+        let cache = self.cache.read().map_err(|e| e.to_string())?;
+        Ok(cache
+            .as_ref()
+            .filter(|entry| !fresh_only || entry.fetched_at.elapsed() < self.ttl)
+            .map(|entry| entry.products.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use async_trait::async_trait;
+    use shared::money::Money;
+    use crate::storage::MemoryStorage;
+    use super::*;
+
+    // Counts fetches, each taking `delay`, and fails them while `failing` is set
+    struct SlowSource {
+        fetches: AtomicUsize,
+        failing: AtomicBool,
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl ProductCatalog for SlowSource {
+        async fn products(&self) -> Result<Vec<Product>, String> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            if self.failing.load(Ordering::SeqCst) {
+                return Err("upstream down".to_string());
+            }
+            Ok(vec![Product {
+                id: 1,
+                title: "Ferris Plush Crab".to_string(),
+                price: Money::new(19900, "SEK"),
+                description: String::new(),
+                category: "toys".to_string(),
+                image: String::new(),
+                weight: None,
+            }])
+        }
+    }
+
+    fn catalog(delay: Duration, ttl: Duration) -> (Arc<SlowSource>, CachedCatalog) {
+        let source = Arc::new(SlowSource { fetches: AtomicUsize::new(0), failing: AtomicBool::new(false), delay });
+        let catalog = CachedCatalog::new(source.clone(), Arc::new(MemoryStorage::new()), ttl);
+        (source, catalog)
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_fetch() {
+        let (source, catalog) = catalog(Duration::from_millis(50), DEFAULT_TTL);
+        let requests: Vec<_> = (0..5).map(|_| tokio::spawn({
+            let catalog = catalog.clone();
+            async move { catalog.products().await }
+        })).collect();
+        for request in requests {
+            let snapshot = request.await.unwrap().unwrap();
+            assert_eq!(snapshot.products.len(), 1);
+            assert!(!snapshot.stale);
+        }
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn stale_products_are_served_while_another_request_refreshes() {
+        let (source, catalog) = catalog(Duration::from_millis(200), Duration::from_millis(10));
+        catalog.refresh().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let refreshing = tokio::spawn({
+            let catalog = catalog.clone();
+            async move { catalog.products().await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let snapshot = tokio::time::timeout(Duration::from_millis(100), catalog.products()).await.unwrap().unwrap();
+        assert!(snapshot.stale);
+        assert!(!refreshing.await.unwrap().unwrap().stale);
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_fetches_back_off() {
+        let (source, catalog) = catalog(Duration::ZERO, Duration::from_millis(10));
+        catalog.refresh().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        source.failing.store(true, Ordering::SeqCst);
+
+        for _ in 0..3 {
+            let snapshot = catalog.products().await.unwrap();
+            assert!(snapshot.stale);
+            assert_eq!(snapshot.products.len(), 1);
+        }
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 2);
+
+        // A forced refresh ignores the backoff
+        source.failing.store(false, Ordering::SeqCst);
+        catalog.refresh().await.unwrap();
+        assert!(!catalog.products().await.unwrap().stale);
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn waiting_requests_get_the_failure_without_fetching_again() {
+        let (source, catalog) = catalog(Duration::from_millis(50), DEFAULT_TTL);
+        source.failing.store(true, Ordering::SeqCst);
+        let requests: Vec<_> = (0..3).map(|_| tokio::spawn({
+            let catalog = catalog.clone();
+            async move { catalog.products().await }
+        })).collect();
+        for request in requests {
+            assert_eq!(request.await.unwrap().err().as_deref(), Some("upstream down"));
+        }
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,880 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use axum::http::HeaderValue;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use serde::Deserialize;
+use tower_http::cors::{AllowOrigin, Any};
+use crate::auth::normalize_email;
+use crate::catalog::DEFAULT_FAKESTORE_URL;
+use crate::catalog_cache::{DEFAULT_REFRESH_INTERVAL, DEFAULT_TTL};
+use crate::coupons::CouponBook;
+use crate::inventory::{DEFAULT_STOCK, RESERVATION_TTL};
+use crate::payments::FakeBehavior;
+use crate::shipping::ShippingTable;
+use crate::storage::DEFAULT_SQLITE_PATH;
+use crate::tax::TaxTable;
+
+// Read from the working directory when no file is named
+pub const DEFAULT_CONFIG_FILE: &str = "rusty-cart.toml";
+pub const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:3000";
+pub const DEFAULT_CATALOG_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_CATALOG_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_TOKEN_ISSUER: &str = "rusty-cart";
+pub const DEFAULT_ACCESS_TOKEN_TTL: Duration = Duration::from_secs(15 * 60);
+pub const DEFAULT_REFRESH_TOKEN_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+// Shortest HS256 secret accepted, in bytes
+pub const MIN_KEY_LENGTH: usize = 32;
+// Request budgets per session or account; each IP address gets DEFAULT_IP_MULTIPLIER times as much
+pub const DEFAULT_READ_BUDGET: RateBudget = RateBudget { per_minute: 300, burst: 60 };
+pub const DEFAULT_WRITE_BUDGET: RateBudget = RateBudget { per_minute: 60, burst: 20 };
+pub const DEFAULT_IP_MULTIPLIER: u32 = 4;
+// Where `trunk serve` runs the frontend
+pub const DEFAULT_CORS_ORIGINS: [&str; 2] = ["http://127.0.0.1:8080", "http://localhost:8080"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CatalogSource {
+    // Fake Store API, or anything serving the same `/products` shape
+    Fakestore,
+    // A local JSON or YAML product list
+    File,
+    // An empty in-memory catalog
+    Memory,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Sqlite,
+    Memory,
+}
+
+// What a product's quantity becomes when the guest cart is merged into the account's cart on login
+// and the product is in both. Products only in the guest cart are always added.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CartMergePolicy {
+    // Both quantities added up
+    Sum,
+    // The larger of the two
+    Max,
+    // The account's quantity, ignoring the guest's
+    Account,
+}
+
+// Everything the backend needs to start. Each setting comes from, in order of precedence,
+// a command line flag, an environment variable, the TOML config file or a built-in default.
+#[derive(Debug)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub catalog: CatalogConfig,
+    pub cors: CorsConfig,
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub cart: CartConfig,
+    pub rate_limit: RateLimitConfig,
+    pub checkout: CheckoutConfig,
+    pub inventory: InventoryConfig,
+    pub payments: PaymentsConfig,
+}
+
+#[derive(Debug)]
+pub struct CatalogConfig {
+    pub source: CatalogSource,
+    pub url: String,
+    pub file: Option<PathBuf>,
+    // Limits for a whole request to the upstream and for connecting to it
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    // How long cached products are served, and how often they are reloaded in the background
+    pub ttl: Duration,
+    pub refresh_interval: Duration,
+}
+
+#[derive(Debug)]
+pub struct CorsConfig {
+    // Origins allowed to call the API, e.g. http://localhost:8080; "*" allows any
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    // SQLite database file
+    pub path: PathBuf,
+}
+
+#[derive(Debug)]
+pub struct AuthConfig {
+    // `iss` claim of the access tokens, checked when they come back
+    pub issuer: String,
+    pub access_token_ttl: Duration,
+    pub refresh_token_ttl: Duration,
+    // Keys access tokens are verified with. Retired keys stay listed until the tokens they signed
+    // have expired. Empty means a random key is generated at startup.
+    pub keys: Vec<SigningKey>,
+    // Id of the key new tokens are signed with; the first key when not set
+    pub signing_key: Option<String>,
+    // Accounts with these emails are made admins at startup and when they register
+    pub admin_emails: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            issuer: DEFAULT_TOKEN_ISSUER.to_string(),
+            access_token_ttl: DEFAULT_ACCESS_TOKEN_TTL,
+            refresh_token_ttl: DEFAULT_REFRESH_TOKEN_TTL,
+            keys: Vec::new(),
+            signing_key: None,
+            admin_emails: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CartConfig {
+    pub merge_policy: CartMergePolicy,
+}
+
+#[derive(Debug)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    // Budgets of each session or account for reading (GET, HEAD) and for everything else
+    pub read: RateBudget,
+    pub write: RateBudget,
+    // How many times a client's budget an IP address gets, since several clients can share one
+    pub ip_multiplier: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            read: DEFAULT_READ_BUDGET,
+            write: DEFAULT_WRITE_BUDGET,
+            ip_multiplier: DEFAULT_IP_MULTIPLIER,
+        }
+    }
+}
+
+// JSON or YAML files with the shop's rules. Without them there are no coupons, the standard shipping
+// methods and Swedish VAT.
+#[derive(Debug)]
+pub struct CheckoutConfig {
+    pub coupons_file: Option<PathBuf>,
+    pub shipping_file: Option<PathBuf>,
+    pub tax_file: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct InventoryConfig {
+    // Stock a product starts with the first time it is used
+    pub default_stock: u32,
+    // How long items in a cart are held for it after the cart last changed
+    pub reservation_ttl: Duration,
+}
+
+// The fake payment provider
+pub struct PaymentsConfig {
+    // What charges do once `script` is used up
+    pub behavior: FakeBehavior,
+    pub script: Vec<FakeBehavior>,
+    // Where confirmations are posted; this server's webhook when not set
+    pub webhook_url: Option<String>,
+    // Empty when not configured, which the validation refuses
+    pub webhook_secret: String,
+}
+
+// Keeps the secret out of logs
+impl std::fmt::Debug for PaymentsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PaymentsConfig")
+            .field("behavior", &self.behavior)
+            .field("script", &self.script)
+            .field("webhook_url", &self.webhook_url)
+            .finish_non_exhaustive()
+    }
+}
+
+// A token bucket: `burst` requests at once, refilled at `per_minute`
+#[derive(Clone, Copy, Debug)]
+pub struct RateBudget {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+// An HS256 secret and the `kid` that selects it
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningKey {
+    pub id: String,
+    pub secret: String,
+}
+
+// Keeps the secret out of logs
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKey").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+impl std::str::FromStr for SigningKey {
+    type Err = String;
+
+    // `id:secret`, as given in RUSTY_CART_AUTH_KEYS
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value.split_once(':') {
+            Some((id, secret)) => Ok(SigningKey { id: id.trim().to_string(), secret: secret.to_string() }),
+            None => Err("expected id:secret".to_string()),
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig { allowed_origins: DEFAULT_CORS_ORIGINS.iter().map(|o| o.to_string()).collect() }
+    }
+}
+
+impl CorsConfig {
+    pub fn allow_origin(&self) -> AllowOrigin {
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            return Any.into();
+        }
+        // The origins were checked by `Config::validate`
+        AllowOrigin::list(self.allowed_origins.iter().filter_map(|o| HeaderValue::from_str(o).ok()))
+    }
+}
+
+#[derive(Parser)]
+#[command(version, about = "Rusty Cart HTTP API")]
+struct Args {
+    #[arg(long, env = "RUSTY_CART_CONFIG", value_name = "FILE",
+        help = "TOML config file [default: rusty-cart.toml if it exists]")]
+    config: Option<PathBuf>,
+
+    #[arg(long, env = "RUSTY_CART_LISTEN_ADDR", value_name = "ADDR", help = "Address to listen on [default: 127.0.0.1:3000]")]
+    listen: Option<SocketAddr>,
+
+    #[arg(long, env = "RUSTY_CART_CATALOG_SOURCE", value_name = "SOURCE", help = "Where products come from [default: fakestore]")]
+    catalog_source: Option<CatalogSource>,
+
+    #[arg(long, env = "RUSTY_CART_CATALOG_URL", value_name = "URL", help = "Base URL of the Fake Store API")]
+    catalog_url: Option<String>,
+
+    #[arg(long, env = "RUSTY_CART_CATALOG_FILE", value_name = "FILE", help = "JSON or YAML product list for the file source")]
+    catalog_file: Option<PathBuf>,
+
+    #[arg(long, env = "RUSTY_CART_CATALOG_TIMEOUT_SECS", value_name = "SECS", help = "Timeout for a catalog request [default: 10]")]
+    catalog_timeout_secs: Option<u64>,
+
+    #[arg(long, env = "RUSTY_CART_CATALOG_CONNECT_TIMEOUT_SECS", value_name = "SECS",
+        help = "Timeout for connecting to the catalog [default: 5]")]
+    catalog_connect_timeout_secs: Option<u64>,
+
+    #[arg(long, env = "RUSTY_CART_CATALOG_TTL_SECS", value_name = "SECS", help = "How long cached products are served [default: 600]")]
+    catalog_ttl_secs: Option<u64>,
+
+    #[arg(long, env = "RUSTY_CART_CATALOG_REFRESH_SECS", value_name = "SECS",
+        help = "How often the catalog is reloaded in the background [default: 300]")]
+    catalog_refresh_secs: Option<u64>,
+
+    #[arg(long = "cors-origin", env = "RUSTY_CART_CORS_ALLOWED_ORIGINS", value_name = "ORIGIN", value_delimiter = ',',
+        help = "Origin allowed to call the API, may be repeated; * allows any [default: the trunk dev server]")]
+    cors_origins: Vec<String>,
+
+    #[arg(long, env = "RUSTY_CART_STORAGE", value_name = "BACKEND", help = "Where carts and orders are kept [default: sqlite]")]
+    storage: Option<StorageBackend>,
+
+    #[arg(long, env = "RUSTY_CART_DB", value_name = "FILE", help = "SQLite database file [default: rusty-cart.db]")]
+    db: Option<PathBuf>,
+
+    #[arg(long, env = "RUSTY_CART_TOKEN_ISSUER", value_name = "NAME", help = "iss claim of access tokens [default: rusty-cart]")]
+    token_issuer: Option<String>,
+
+    #[arg(long, env = "RUSTY_CART_ACCESS_TOKEN_TTL_SECS", value_name = "SECS", help = "Lifetime of access tokens [default: 900]")]
+    access_token_ttl_secs: Option<u64>,
+
+    #[arg(long, env = "RUSTY_CART_REFRESH_TOKEN_TTL_SECS", value_name = "SECS",
+        help = "Lifetime of refresh tokens [default: 2592000]")]
+    refresh_token_ttl_secs: Option<u64>,
+
+    // Prefer the environment or the config file; flags show up in process listings
+    #[arg(long = "auth-key", env = "RUSTY_CART_AUTH_KEYS", value_name = "ID:SECRET", value_delimiter = ',',
+        help = "Token signing key, may be repeated [default: a random key per run]")]
+    auth_keys: Vec<SigningKey>,
+
+    #[arg(long, env = "RUSTY_CART_AUTH_SIGNING_KEY", value_name = "ID", help = "Key that signs new tokens [default: the first key]")]
+    auth_signing_key: Option<String>,
+
+    #[arg(long = "admin-email", env = "RUSTY_CART_ADMIN_EMAILS", value_name = "EMAIL", value_delimiter = ',',
+        help = "Email of an account to make admin, may be repeated")]
+    admin_emails: Vec<String>,
+
+    #[arg(long, env = "RUSTY_CART_MERGE_POLICY", value_name = "POLICY",
+        help = "How the guest cart is merged into the account's cart on login [default: sum]")]
+    cart_merge_policy: Option<CartMergePolicy>,
+
+    #[arg(long, env = "RUSTY_CART_RATE_LIMIT_ENABLED", value_name = "BOOL", help = "Limit request rates [default: true]")]
+    rate_limit_enabled: Option<bool>,
+
+    #[arg(long, env = "RUSTY_CART_RATE_LIMIT_READ_PER_MINUTE", value_name = "N",
+        help = "Reads per minute per session or account [default: 300]")]
+    rate_limit_read_per_minute: Option<u32>,
+
+    #[arg(long, env = "RUSTY_CART_RATE_LIMIT_READ_BURST", value_name = "N", help = "Reads allowed at once [default: 60]")]
+    rate_limit_read_burst: Option<u32>,
+
+    #[arg(long, env = "RUSTY_CART_RATE_LIMIT_WRITE_PER_MINUTE", value_name = "N",
+        help = "Writes per minute per session or account [default: 60]")]
+    rate_limit_write_per_minute: Option<u32>,
+
+    #[arg(long, env = "RUSTY_CART_RATE_LIMIT_WRITE_BURST", value_name = "N", help = "Writes allowed at once [default: 20]")]
+    rate_limit_write_burst: Option<u32>,
+
+    #[arg(long, env = "RUSTY_CART_RATE_LIMIT_IP_MULTIPLIER", value_name = "N",
+        help = "Budget of an IP address as a multiple of a client's [default: 4]")]
+    rate_limit_ip_multiplier: Option<u32>,
+
+    #[arg(long, env = "RUSTY_CART_COUPONS_FILE", value_name = "FILE", help = "JSON or YAML coupon list [default: no coupons]")]
+    coupons_file: Option<PathBuf>,
+
+    #[arg(long, env = "RUSTY_CART_SHIPPING_FILE", value_name = "FILE",
+        help = "JSON or YAML shipping methods [default: standard and express]")]
+    shipping_file: Option<PathBuf>,
+
+    #[arg(long, env = "RUSTY_CART_TAX_FILE", value_name = "FILE", help = "JSON or YAML tax table [default: Swedish VAT]")]
+    tax_file: Option<PathBuf>,
+
+    #[arg(long, env = "RUSTY_CART_DEFAULT_STOCK", value_name = "N", help = "Stock a product starts with [default: 100]")]
+    default_stock: Option<u32>,
+
+    #[arg(long, env = "RUSTY_CART_RESERVATION_TTL_SECS", value_name = "SECS",
+        help = "How long cart items are held after the cart last changed [default: 900]")]
+    reservation_ttl_secs: Option<u64>,
+
+    #[arg(long, env = "RUSTY_CART_FAKE_PAYMENT_BEHAVIOR", value_name = "BEHAVIOR",
+        help = "What the fake payment provider does with charges [default: approve]")]
+    fake_payment_behavior: Option<FakeBehavior>,
+
+    #[arg(long, env = "RUSTY_CART_FAKE_PAYMENT_SCRIPT", value_name = "BEHAVIOR", value_delimiter = ',',
+        help = "What the fake payment provider does with the first charges, in order")]
+    fake_payment_script: Vec<FakeBehavior>,
+
+    #[arg(long, env = "RUSTY_CART_PAYMENT_WEBHOOK_URL", value_name = "URL",
+        help = "Where payment confirmations are posted [default: this server's webhook]")]
+    payment_webhook_url: Option<String>,
+
+    #[arg(long, env = "RUSTY_CART_PAYMENT_WEBHOOK_SECRET", value_name = "SECRET",
+        help = "Signature of payment webhook calls; required")]
+    payment_webhook_secret: Option<String>,
+}
+
+impl Args {
+    // Parses `argv`, taking the settings it leaves out from the variables in `env`. clap would read
+    // them from the process environment itself, which is shared by everything running in it.
+    fn parse_from_env<I, T>(argv: I, env: &HashMap<OsString, OsString>) -> Result<Args, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let command = Args::command().mut_args(|arg| {
+            let Some(name) = arg.get_env().map(|name| name.to_string_lossy().into_owned()) else {
+                return arg;
+            };
+            let help = format!("{} [env: {}]", arg.get_help().map(|help| help.to_string()).unwrap_or_default(), name);
+            // Values are hidden because some of them are secrets
+            let arg = arg.env(None).help(help).hide_default_value(true);
+            match env.get(std::ffi::OsStr::new(&name)) {
+                Some(value) => arg.default_value(value),
+                None => arg,
+            }
+        });
+        Args::from_arg_matches(&command.try_get_matches_from(argv)?)
+    }
+}
+
+// The config file. Every key is optional; unknown keys are rejected so typos don't go unnoticed.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    server: ServerSection,
+    catalog: CatalogSection,
+    cors: CorsSection,
+    storage: StorageSection,
+    auth: AuthSection,
+    cart: CartSection,
+    rate_limit: RateLimitSection,
+    checkout: CheckoutSection,
+    inventory: InventorySection,
+    payments: PaymentsSection,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServerSection {
+    listen: Option<SocketAddr>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CatalogSection {
+    source: Option<CatalogSource>,
+    url: Option<String>,
+    file: Option<PathBuf>,
+    timeout_secs: Option<u64>,
+    connect_timeout_secs: Option<u64>,
+    ttl_secs: Option<u64>,
+    refresh_secs: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CorsSection {
+    allowed_origins: Option<Vec<String>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StorageSection {
+    backend: Option<StorageBackend>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AuthSection {
+    issuer: Option<String>,
+    access_token_ttl_secs: Option<u64>,
+    refresh_token_ttl_secs: Option<u64>,
+    keys: Option<Vec<SigningKey>>,
+    signing_key: Option<String>,
+    admin_emails: Option<Vec<String>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CartSection {
+    merge_policy: Option<CartMergePolicy>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RateLimitSection {
+    enabled: Option<bool>,
+    read_per_minute: Option<u32>,
+    read_burst: Option<u32>,
+    write_per_minute: Option<u32>,
+    write_burst: Option<u32>,
+    ip_multiplier: Option<u32>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CheckoutSection {
+    coupons_file: Option<PathBuf>,
+    shipping_file: Option<PathBuf>,
+    tax_file: Option<PathBuf>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct InventorySection {
+    default_stock: Option<u32>,
+    reservation_ttl_secs: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PaymentsSection {
+    behavior: Option<FakeBehavior>,
+    script: Option<Vec<FakeBehavior>>,
+    webhook_url: Option<String>,
+    webhook_secret: Option<String>,
+}
+
+impl FileConfig {
+    fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&contents).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+    }
+}
+
+impl Config {
+    // Reads the command line (exiting with usage help if it is wrong), the environment and the
+    // config file, then checks the result
+    pub fn load() -> Result<Config, String> {
+        let env = std::env::vars_os().collect();
+        Config::from_args(Args::parse_from_env(std::env::args_os(), &env).unwrap_or_else(|e| e.exit()))
+    }
+
+    fn from_args(args: Args) -> Result<Config, String> {
+        let file = match &args.config {
+            Some(path) => FileConfig::load(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => FileConfig::load(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => FileConfig::default(),
+        };
+
+        let secs = |arg: Option<u64>, file: Option<u64>, default: Duration| {
+            arg.or(file).map(Duration::from_secs).unwrap_or(default)
+        };
+        let admin_emails = if args.admin_emails.is_empty() {
+            file.auth.admin_emails.unwrap_or_default()
+        } else {
+            args.admin_emails
+        };
+        let config = Config {
+            listen: args.listen
+                .or(file.server.listen)
+                .unwrap_or_else(|| DEFAULT_LISTEN_ADDR.parse().unwrap()),
+            catalog: CatalogConfig {
+                source: args.catalog_source.or(file.catalog.source).unwrap_or(CatalogSource::Fakestore),
+                url: args.catalog_url
+                    .or(file.catalog.url)
+                    .unwrap_or_else(|| DEFAULT_FAKESTORE_URL.to_string()),
+                file: args.catalog_file.or(file.catalog.file),
+                timeout: secs(args.catalog_timeout_secs, file.catalog.timeout_secs, DEFAULT_CATALOG_TIMEOUT),
+                connect_timeout: secs(
+                    args.catalog_connect_timeout_secs,
+                    file.catalog.connect_timeout_secs,
+                    DEFAULT_CATALOG_CONNECT_TIMEOUT,
+                ),
+                ttl: secs(args.catalog_ttl_secs, file.catalog.ttl_secs, DEFAULT_TTL),
+                refresh_interval: secs(args.catalog_refresh_secs, file.catalog.refresh_secs, DEFAULT_REFRESH_INTERVAL),
+            },
+            cors: match (args.cors_origins, file.cors.allowed_origins) {
+                (origins, _) if !origins.is_empty() => CorsConfig { allowed_origins: origins },
+                (_, Some(origins)) => CorsConfig { allowed_origins: origins },
+                _ => CorsConfig::default(),
+            },
+            storage: StorageConfig {
+                backend: args.storage.or(file.storage.backend).unwrap_or(StorageBackend::Sqlite),
+                path: args.db.or(file.storage.path).unwrap_or_else(|| PathBuf::from(DEFAULT_SQLITE_PATH)),
+            },
+            auth: AuthConfig {
+                issuer: args.token_issuer.or(file.auth.issuer).unwrap_or_else(|| DEFAULT_TOKEN_ISSUER.to_string()),
+                access_token_ttl: secs(args.access_token_ttl_secs, file.auth.access_token_ttl_secs, DEFAULT_ACCESS_TOKEN_TTL),
+                refresh_token_ttl: secs(
+                    args.refresh_token_ttl_secs,
+                    file.auth.refresh_token_ttl_secs,
+                    DEFAULT_REFRESH_TOKEN_TTL,
+                ),
+                keys: if args.auth_keys.is_empty() { file.auth.keys.unwrap_or_default() } else { args.auth_keys },
+                signing_key: args.auth_signing_key.or(file.auth.signing_key),
+                admin_emails: admin_emails.iter().map(|email| normalize_email(email)).collect(),
+            },
+            cart: CartConfig {
+                merge_policy: args.cart_merge_policy.or(file.cart.merge_policy).unwrap_or(CartMergePolicy::Sum),
+            },
+            rate_limit: RateLimitConfig {
+                enabled: args.rate_limit_enabled.or(file.rate_limit.enabled).unwrap_or(true),
+                read: RateBudget {
+                    per_minute: args.rate_limit_read_per_minute
+                        .or(file.rate_limit.read_per_minute)
+                        .unwrap_or(DEFAULT_READ_BUDGET.per_minute),
+                    burst: args.rate_limit_read_burst.or(file.rate_limit.read_burst).unwrap_or(DEFAULT_READ_BUDGET.burst),
+                },
+                write: RateBudget {
+                    per_minute: args.rate_limit_write_per_minute
+                        .or(file.rate_limit.write_per_minute)
+                        .unwrap_or(DEFAULT_WRITE_BUDGET.per_minute),
+                    burst: args.rate_limit_write_burst.or(file.rate_limit.write_burst).unwrap_or(DEFAULT_WRITE_BUDGET.burst),
+                },
+                ip_multiplier: args.rate_limit_ip_multiplier
+                    .or(file.rate_limit.ip_multiplier)
+                    .unwrap_or(DEFAULT_IP_MULTIPLIER),
+            },
+            checkout: CheckoutConfig {
+                coupons_file: args.coupons_file.or(file.checkout.coupons_file),
+                shipping_file: args.shipping_file.or(file.checkout.shipping_file),
+                tax_file: args.tax_file.or(file.checkout.tax_file),
+            },
+            inventory: InventoryConfig {
+                default_stock: args.default_stock.or(file.inventory.default_stock).unwrap_or(DEFAULT_STOCK),
+                reservation_ttl: secs(args.reservation_ttl_secs, file.inventory.reservation_ttl_secs, RESERVATION_TTL),
+            },
+            payments: PaymentsConfig {
+                behavior: args.fake_payment_behavior.or(file.payments.behavior).unwrap_or(FakeBehavior::Approve),
+                script: if args.fake_payment_script.is_empty() {
+                    file.payments.script.unwrap_or_default()
+                } else {
+                    args.fake_payment_script
+                },
+                webhook_url: args.payment_webhook_url.or(file.payments.webhook_url),
+                webhook_secret: args.payment_webhook_secret
+                    .or(file.payments.webhook_secret)
+                    .unwrap_or_default(),
+            },
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Checks the settings that can't be told apart by type alone, reporting every problem at once
+    fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+
+        if self.catalog.source == CatalogSource::Fakestore {
+            match reqwest::Url::parse(&self.catalog.url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                _ => problems.push(format!("catalog.url '{}' is not an http(s) URL", self.catalog.url)),
+            }
+        }
+        if self.catalog.source == CatalogSource::File && self.catalog.file.is_none() {
+            problems.push("catalog.file must be set when the catalog source is file".to_string());
+        }
+        if self.catalog.timeout.is_zero() {
+            problems.push("catalog.timeout_secs must be at least 1".to_string());
+        }
+        if self.catalog.connect_timeout.is_zero() {
+            problems.push("catalog.connect_timeout_secs must be at least 1".to_string());
+        }
+        if self.catalog.refresh_interval.is_zero() {
+            problems.push("catalog.refresh_secs must be at least 1".to_string());
+        }
+
+        if self.cors.allowed_origins.is_empty() {
+            problems.push("cors.allowed_origins must not be empty, use \"*\" to allow any origin".to_string());
+        }
+        for origin in &self.cors.allowed_origins {
+            if origin != "*" && !is_origin(origin) {
+                problems.push(format!(
+                    "cors.allowed_origins entry '{}' is not an origin like http://localhost:8080",
+                    origin
+                ));
+            }
+        }
+
+        if self.storage.backend == StorageBackend::Sqlite && self.storage.path.as_os_str().is_empty() {
+            problems.push("storage.path must not be empty".to_string());
+        }
+
+        if self.auth.access_token_ttl.is_zero() {
+            problems.push("auth.access_token_ttl_secs must be at least 1".to_string());
+        }
+        if self.auth.refresh_token_ttl <= self.auth.access_token_ttl {
+            problems.push("auth.refresh_token_ttl_secs must be longer than auth.access_token_ttl_secs".to_string());
+        }
+        for (i, key) in self.auth.keys.iter().enumerate() {
+            if key.id.is_empty() {
+                problems.push("auth.keys entries need an id".to_string());
+            } else if self.auth.keys[..i].iter().any(|k| k.id == key.id) {
+                problems.push(format!("auth.keys has more than one key with id '{}'", key.id));
+            }
+            if key.secret.len() < MIN_KEY_LENGTH {
+                problems.push(format!("auth.keys secret of '{}' must be at least {} bytes", key.id, MIN_KEY_LENGTH));
+            }
+        }
+        if let Some(id) = &self.auth.signing_key {
+            if !self.auth.keys.iter().any(|k| &k.id == id) {
+                problems.push(format!("auth.signing_key '{}' is not one of auth.keys", id));
+            }
+        }
+        for (name, value) in [
+            ("read_per_minute", self.rate_limit.read.per_minute),
+            ("read_burst", self.rate_limit.read.burst),
+            ("write_per_minute", self.rate_limit.write.per_minute),
+            ("write_burst", self.rate_limit.write.burst),
+            ("ip_multiplier", self.rate_limit.ip_multiplier),
+        ] {
+            if value == 0 {
+                problems.push(format!("rate_limit.{} must be at least 1", name));
+            }
+        }
+
+        for email in &self.auth.admin_emails {
+            if !email.contains('@') {
+                problems.push(format!("auth.admin_emails entry '{}' is not an email address", email));
+            }
+        }
+
+        // The files are read again when the server starts; this way their mistakes are listed too
+        let data_files = [
+            ("coupons_file", self.checkout.coupons_file.as_deref().map(|path| CouponBook::load(path).err())),
+            ("shipping_file", self.checkout.shipping_file.as_deref().map(|path| ShippingTable::load(path).err())),
+            ("tax_file", self.checkout.tax_file.as_deref().map(|path| TaxTable::load(path).err())),
+        ];
+        for (name, error) in data_files {
+            if let Some(Some(e)) = error {
+                problems.push(format!("checkout.{}: {}", name, e));
+            }
+        }
+
+        if self.inventory.reservation_ttl.is_zero() {
+            problems.push("inventory.reservation_ttl_secs must be at least 1".to_string());
+        }
+
+        if let Some(url) = &self.payments.webhook_url {
+            match reqwest::Url::parse(url) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+                _ => problems.push(format!("payments.webhook_url '{}' is not an http(s) URL", url)),
+            }
+        }
+        // A well-known default would let anyone mark orders paid
+        if self.payments.webhook_secret.is_empty() {
+            problems.push("payments.webhook_secret must be set".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid configuration:\n  - {}", problems.join("\n  - ")))
+        }
+    }
+}
+
+// Scheme, host and optional port, without a path or trailing slash, as browsers send in the Origin header
+fn is_origin(value: &str) -> bool {
+    match reqwest::Url::parse(value) {
+        Ok(url) => {
+            matches!(url.scheme(), "http" | "https")
+                && url.has_host()
+                && url.origin().ascii_serialization() == value
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Loads the configuration from a config file with `file` in it, the variables in `env` and `flags`
+    fn load(file: &str, env: &[(&str, &str)], flags: &[&str]) -> Result<Config, String> {
+        let path = std::env::temp_dir().join(format!("rusty-cart-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, file).unwrap();
+
+        let env = env.iter().map(|(name, value)| (name.into(), value.into())).collect();
+        let path_arg = path.to_str().unwrap();
+        let args = ["backend", "--config", path_arg].into_iter().chain(flags.iter().copied());
+        let config = Args::parse_from_env(args, &env).map_err(|e| e.to_string()).and_then(Config::from_args);
+
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    const FILE: &str = r#"
+        [server]
+        listen = "127.0.0.1:4000"
+
+        [cors]
+        allowed_origins = ["https://shop.example"]
+
+        [inventory]
+        default_stock = 5
+
+        [payments]
+        script = ["decline", "confirm"]
+        webhook_secret = "file-secret"
+    "#;
+
+    #[test]
+    fn defaults_apply_without_settings() {
+        let config = load("", &[], &["--payment-webhook-secret", "s3cret"]).unwrap();
+        assert_eq!(config.listen, DEFAULT_LISTEN_ADDR.parse().unwrap());
+        assert_eq!(config.cors.allowed_origins, DEFAULT_CORS_ORIGINS);
+        assert_eq!(config.inventory.default_stock, DEFAULT_STOCK);
+        assert_eq!(config.inventory.reservation_ttl, RESERVATION_TTL);
+        assert_eq!(config.payments.behavior, FakeBehavior::Approve);
+        assert!(config.payments.script.is_empty());
+        assert_eq!(config.payments.webhook_secret, "s3cret");
+        assert!(config.checkout.coupons_file.is_none());
+    }
+
+    #[test]
+    fn the_webhook_secret_has_no_default() {
+        let err = load("", &[], &[]).unwrap_err();
+        assert!(err.contains("payments.webhook_secret must be set"), "{}", err);
+    }
+
+    #[test]
+    fn the_file_overrides_defaults() {
+        let config = load(FILE, &[], &[]).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(config.cors.allowed_origins, ["https://shop.example"]);
+        assert_eq!(config.inventory.default_stock, 5);
+        assert_eq!(config.payments.script, [FakeBehavior::Decline, FakeBehavior::RequireConfirmation]);
+    }
+
+    #[test]
+    fn the_environment_overrides_the_file() {
+        let env = [
+            ("RUSTY_CART_LISTEN_ADDR", "127.0.0.1:5000"),
+            ("RUSTY_CART_CORS_ALLOWED_ORIGINS", "https://a.example,https://b.example"),
+            ("RUSTY_CART_DEFAULT_STOCK", "7"),
+            ("RUSTY_CART_FAKE_PAYMENT_SCRIPT", "confirm"),
+        ];
+        let config = load(FILE, &env, &[]).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:5000".parse().unwrap());
+        assert_eq!(config.cors.allowed_origins, ["https://a.example", "https://b.example"]);
+        assert_eq!(config.inventory.default_stock, 7);
+        assert_eq!(config.payments.script, [FakeBehavior::RequireConfirmation]);
+    }
+
+    #[test]
+    fn flags_override_the_environment() {
+        let env = [("RUSTY_CART_LISTEN_ADDR", "127.0.0.1:5000"), ("RUSTY_CART_DEFAULT_STOCK", "7")];
+        let flags = ["--listen", "127.0.0.1:6000", "--default-stock", "9", "--cors-origin", "https://c.example"];
+        let config = load(FILE, &env, &flags).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:6000".parse().unwrap());
+        assert_eq!(config.inventory.default_stock, 9);
+        assert_eq!(config.cors.allowed_origins, ["https://c.example"]);
+    }
+
+    #[test]
+    fn malformed_values_are_rejected_instead_of_defaulted() {
+        assert!(load("", &[("RUSTY_CART_DEFAULT_STOCK", "lots")], &[]).is_err());
+        assert!(load("", &[("RUSTY_CART_RESERVATION_TTL_SECS", "-1")], &[]).is_err());
+        assert!(load("", &[("RUSTY_CART_FAKE_PAYMENT_BEHAVIOR", "refund")], &[]).is_err());
+    }
+
+    #[test]
+    fn only_prefixed_variables_are_read() {
+        let env = [("LISTEN_ADDR", "127.0.0.1:5000"), ("RUSTY_CART_PAYMENT_WEBHOOK_SECRET", "env-secret")];
+        let config = load("", &env, &[]).unwrap();
+        assert_eq!(config.listen, DEFAULT_LISTEN_ADDR.parse().unwrap());
+        assert_eq!(config.payments.webhook_secret, "env-secret");
+    }
+
+    #[test]
+    fn unknown_file_keys_are_rejected() {
+        let err = load("[inventory]\nstock = 3\n", &[], &[]).unwrap_err();
+        assert!(err.contains("unknown field `stock`"), "{}", err);
+    }
+
+    #[test]
+    fn validation_lists_every_problem() {
+        let flags = [
+            "--catalog-timeout-secs", "0",
+            "--cors-origin", "localhost:8080",
+            "--auth-signing-key", "missing",
+            "--rate-limit-write-burst", "0",
+            "--reservation-ttl-secs", "0",
+            "--payment-webhook-url", "ftp://example.com/webhook",
+            "--coupons-file", "/nonexistent/coupons.json",
+        ];
+        let err = load("", &[], &flags).unwrap_err();
+        for problem in [
+            "catalog.timeout_secs must be at least 1",
+            "cors.allowed_origins entry 'localhost:8080' is not an origin",
+            "auth.signing_key 'missing' is not one of auth.keys",
+            "rate_limit.write_burst must be at least 1",
+            "inventory.reservation_ttl_secs must be at least 1",
+            "payments.webhook_url 'ftp://example.com/webhook' is not an http(s) URL",
+            "checkout.coupons_file: Failed to read /nonexistent/coupons.json",
+        ] {
+            assert!(err.contains(problem), "missing '{}' in:\n{}", problem, err);
+        }
+    }
+
+    #[test]
+    fn short_signing_keys_are_rejected() {
+        let err = load("", &[], &["--auth-key", "k1:too-short"]).unwrap_err();
+        assert!(err.contains("auth.keys secret of 'k1' must be at least 32 bytes"), "{}", err);
+    }
+}
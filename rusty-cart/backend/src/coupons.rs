@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::path::Path;
+use serde::Deserialize;
+use shared::models::CartItem;
+use shared::Money;
+
+// How a coupon reduces the price of the lines it applies to
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DiscountRule {
+    // `percent` % off every eligible line
+    Percentage { percent: u32 },
+    // A fixed amount off the eligible lines, split between them by value
+    FixedAmount { amount: Money },
+    // For every `buy` units of a product, `get` more units of it are free
+    BuyXGetY { buy: u32, get: u32 },
+}
+
+// Timestamps are seconds since the Unix epoch
+#[derive(Deserialize, Clone, Debug)]
+pub struct Coupon {
+    pub code: String,
+    pub rule: DiscountRule,
+    // Only lines whose product is in this category are discounted
+    #[serde(default)]
+    pub category: Option<String>,
+    // The cart subtotal must be at least this much
+    #[serde(default)]
+    pub min_spend: Option<Money>,
+    #[serde(default)]
+    pub valid_from: Option<u64>,
+    #[serde(default)]
+    pub valid_until: Option<u64>,
+    // How many orders may use the coupon in total
+    #[serde(default)]
+    pub max_uses: Option<u32>,
+}
+
+impl Coupon {
+    fn is_eligible(&self, item: &CartItem) -> bool {
+        self.category
+            .as_ref()
+            .is_none_or(|category| item.product.category.eq_ignore_ascii_case(category))
+    }
+
+    // Explains why the coupon can't be used on this cart right now, if it can't
+    pub fn check(&self, cart: &[CartItem], subtotal: &Money, times_used: u32, now: u64) -> Result<(), String> {
+        if self.valid_from.is_some_and(|from| now < from) {
+            return Err(format!("Coupon {} is not valid yet", self.code));
+        }
+        if self.valid_until.is_some_and(|until| now > until) {
+            return Err(format!("Coupon {} has expired", self.code));
+        }
+        if self.max_uses.is_some_and(|max| times_used >= max) {
+            return Err(format!("Coupon {} has reached its usage limit", self.code));
+        }
+        if let Some(min_spend) = &self.min_spend {
+            if min_spend.currency() != subtotal.currency() || subtotal.minor() < min_spend.minor() {
+                return Err(format!("Coupon {} requires a minimum spend of {}", self.code, min_spend));
+            }
+        }
+        if !cart.iter().any(|item| self.is_eligible(item)) {
+            return Err(format!("Coupon {} does not apply to any item in the cart", self.code));
+        }
+        Ok(())
+    }
+
+    // Discount for each cart line, in the same order as `cart`
+    pub fn discounts(&self, cart: &[CartItem]) -> Vec<Money> {
+        let zero = |item: &CartItem| Money::zero(item.product.price.currency());
+
+        match &self.rule {
+            DiscountRule::Percentage { percent } => cart
+                .iter()
+                .map(|item| {
+                    if self.is_eligible(item) {
+                        item.line_total().mul_ratio((*percent).min(100) as i64, 100)
+                    } else {
+                        zero(item)
+                    }
+                })
+                .collect(),
+            DiscountRule::BuyXGetY { buy, get } => cart
+                .iter()
+                .map(|item| {
+                    // A group too large to count never fits in a cart line
+                    match buy.checked_add(*get) {
+                        Some(group) if self.is_eligible(item) && *get > 0 => {
+                            item.product.price.times(item.quantity / group * get)
+                        }
+                        _ => zero(item),
+                    }
+                })
+                .collect(),
+            DiscountRule::FixedAmount { amount } => {
+                let eligible: Vec<bool> = cart
+                    .iter()
+                    .map(|item| self.is_eligible(item) && item.product.price.currency() == amount.currency())
+                    .collect();
+                let eligible_total: i64 = cart
+                    .iter()
+                    .zip(&eligible)
+                    .filter(|(_, eligible)| **eligible)
+                    .map(|(item, _)| item.line_total().minor())
+                    .sum();
+                let to_spread = amount.minor().min(eligible_total);
+                if to_spread <= 0 {
+                    return cart.iter().map(zero).collect();
+                }
+
+                // Split by line value; the last eligible line takes the rounding remainder
+                let last = eligible.iter().rposition(|e| *e);
+                let mut remaining = to_spread;
+                cart.iter()
+                    .enumerate()
+                    .map(|(i, item)| {
+                        if !eligible[i] {
+                            return zero(item);
+                        }
+                        let share = if Some(i) == last {
+                            remaining
+                        } else {
+                            item.line_total().mul_ratio(to_spread, eligible_total).minor()
+                        };
+                        remaining -= share;
+                        Money::new(share, amount.currency())
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+// All coupons known to the shop, keyed by upper-cased code
+#[derive(Default)]
+pub struct CouponBook {
+    coupons: HashMap<String, Coupon>,
+}
+
+impl CouponBook {
+    pub fn new(coupons: Vec<Coupon>) -> Self {
+        CouponBook {
+            coupons: coupons
+                .into_iter()
+                .map(|coupon| (coupon.code.to_uppercase(), coupon))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, code: &str) -> Option<&Coupon> {
+        self.coupons.get(&code.to_uppercase())
+    }
+
+    // Loads coupons from a JSON or YAML file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let is_yaml = matches!(path.extension().and_then(|ext| ext.to_str()), Some("yaml") | Some("yml"));
+        let coupons: Vec<Coupon> = if is_yaml {
+            serde_yaml::from_str(&contents).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?
+        } else {
+            serde_json::from_str(&contents).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?
+        };
+        Ok(CouponBook::new(coupons))
+    }
+}
+
+// Reads coupons from the configured file; without one the shop has no coupons
+pub fn from_config(file: Option<&Path>) -> Result<CouponBook, String> {
+    match file {
+        Some(path) => CouponBook::load(path),
+        None => Ok(CouponBook::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::models::Product;
+    use super::*;
+
+    fn item(id: u32, price: i64, quantity: u32, category: &str) -> CartItem {
+        CartItem {
+            product: Product {
+                id,
+                title: format!("Product {}", id),
+                price: Money::new(price, "SEK"),
+                description: String::new(),
+                category: category.to_string(),
+                image: String::new(),
+                weight: None,
+            },
+            quantity,
+        }
+    }
+
+    fn coupon(rule: DiscountRule) -> Coupon {
+        Coupon {
+            code: "TEST".to_string(),
+            rule,
+            category: None,
+            min_spend: None,
+            valid_from: None,
+            valid_until: None,
+            max_uses: None,
+        }
+    }
+
+    fn minor(discounts: Vec<Money>) -> Vec<i64> {
+        discounts.iter().map(Money::minor).collect()
+    }
+
+    #[test]
+    fn percentages_round_each_line_to_the_nearest_minor_unit() {
+        let cart = [item(1, 333, 1, "toys"), item(2, 5, 3, "toys"), item(3, 1000, 1, "books")];
+        let mut fifteen_percent = coupon(DiscountRule::Percentage { percent: 15 });
+        // 49.95 rounds up, 2.25 rounds down
+        assert_eq!(minor(fifteen_percent.discounts(&cart)), [50, 2, 150]);
+
+        fifteen_percent.category = Some("TOYS".to_string());
+        assert_eq!(minor(fifteen_percent.discounts(&cart)), [50, 2, 0]);
+        assert_eq!(minor(coupon(DiscountRule::Percentage { percent: 250 }).discounts(&cart)), [333, 15, 1000]);
+    }
+
+    #[test]
+    fn fixed_amounts_are_split_by_line_value_with_the_remainder_on_the_last_line() {
+        let cart = [item(1, 100, 1, "toys"), item(2, 100, 1, "books"), item(3, 100, 1, "toys")];
+        let mut coupon = coupon(DiscountRule::FixedAmount { amount: Money::new(100, "SEK") });
+        assert_eq!(minor(coupon.discounts(&cart)), [33, 33, 34]);
+
+        coupon.category = Some("toys".to_string());
+        assert_eq!(minor(coupon.discounts(&cart)), [50, 0, 50]);
+
+        // Never more than the eligible lines are worth
+        coupon.rule = DiscountRule::FixedAmount { amount: Money::new(10_000, "SEK") };
+        assert_eq!(minor(coupon.discounts(&cart)), [100, 0, 100]);
+
+        coupon.rule = DiscountRule::FixedAmount { amount: Money::new(100, "EUR") };
+        assert_eq!(minor(coupon.discounts(&cart)), [0, 0, 0]);
+    }
+
+    #[test]
+    fn buy_x_get_y_counts_whole_groups_per_line() {
+        let cart = [item(1, 100, 2, "toys"), item(2, 100, 3, "toys"), item(3, 50, 7, "toys")];
+        assert_eq!(minor(coupon(DiscountRule::BuyXGetY { buy: 2, get: 1 }).discounts(&cart)), [0, 100, 100]);
+        assert_eq!(minor(coupon(DiscountRule::BuyXGetY { buy: 2, get: 0 }).discounts(&cart)), [0, 0, 0]);
+        assert_eq!(minor(coupon(DiscountRule::BuyXGetY { buy: 0, get: 1 }).discounts(&cart)), [200, 300, 350]);
+    }
+
+    #[test]
+    fn buy_x_get_y_with_huge_groups_discounts_nothing() {
+        let cart = [item(1, 100, u32::MAX, "toys")];
+        assert_eq!(minor(coupon(DiscountRule::BuyXGetY { buy: u32::MAX, get: 1 }).discounts(&cart)), [0]);
+        assert_eq!(minor(coupon(DiscountRule::BuyXGetY { buy: 1, get: u32::MAX }).discounts(&cart)), [0]);
+    }
+
+    #[test]
+    fn minimum_spend_compares_the_subtotal_in_the_same_currency() {
+        let cart = [item(1, 500, 1, "toys")];
+        let mut coupon = coupon(DiscountRule::Percentage { percent: 10 });
+        coupon.min_spend = Some(Money::new(500, "SEK"));
+        assert!(coupon.check(&cart, &Money::new(500, "SEK"), 0, 0).is_ok());
+        assert!(coupon.check(&cart, &Money::new(499, "SEK"), 0, 0).is_err());
+        assert!(coupon.check(&cart, &Money::new(1000, "EUR"), 0, 0).is_err());
+    }
+
+    #[test]
+    fn coupons_only_apply_inside_their_validity_window() {
+        let cart = [item(1, 500, 1, "toys")];
+        let subtotal = Money::new(500, "SEK");
+        let mut coupon = coupon(DiscountRule::Percentage { percent: 10 });
+        coupon.valid_from = Some(100);
+        coupon.valid_until = Some(200);
+        assert_eq!(coupon.check(&cart, &subtotal, 0, 99), Err("Coupon TEST is not valid yet".to_string()));
+        assert!(coupon.check(&cart, &subtotal, 0, 100).is_ok());
+        assert!(coupon.check(&cart, &subtotal, 0, 200).is_ok());
+        assert_eq!(coupon.check(&cart, &subtotal, 0, 201), Err("Coupon TEST has expired".to_string()));
+    }
+
+    #[test]
+    fn coupons_stop_working_after_max_uses() {
+        let cart = [item(1, 500, 1, "toys")];
+        let subtotal = Money::new(500, "SEK");
+        let mut coupon = coupon(DiscountRule::Percentage { percent: 10 });
+        coupon.max_uses = Some(2);
+        assert!(coupon.check(&cart, &subtotal, 1, 0).is_ok());
+        assert_eq!(coupon.check(&cart, &subtotal, 2, 0), Err("Coupon TEST has reached its usage limit".to_string()));
+    }
+
+    #[test]
+    fn coupons_for_another_category_are_refused() {
+        let mut coupon = coupon(DiscountRule::Percentage { percent: 10 });
+        coupon.category = Some("books".to_string());
+        let result = coupon.check(&[item(1, 500, 1, "toys")], &Money::new(500, "SEK"), 0, 0);
+        assert_eq!(result, Err("Coupon TEST does not apply to any item in the cart".to_string()));
+    }
+}
@@ -0,0 +1,404 @@
+use axum::{extract::rejection::{JsonRejection, PathRejection, QueryRejection}, http::{header, HeaderValue, StatusCode}, response::{IntoResponse, Response}, Json};
+use std::collections::BTreeMap;
+use serde::Serialize;
+use utoipa::ToSchema;
+use validator::{ValidationError, ValidationErrors};
+
+// Everything a handler can fail with. Each variant has a stable `code` clients can match on;
+// the `error` message next to it is meant for people and may change.
+#[derive(Debug)]
+pub enum ApiError {
+    // The request itself is malformed: bad JSON, query string or path parameter
+    InvalidRequest(String),
+    // The request parsed but some fields have values we don't accept
+    Validation(ValidationErrors),
+    // The body is JSON, but not of the expected shape, e.g. a missing field or a string for a number
+    InvalidBody(String),
+    // The body isn't declared as JSON
+    UnsupportedMediaType(String),
+    CartEmpty,
+    InvalidSignature,
+    // No valid access token was sent
+    Unauthorized,
+    // The access token was fine but has run out; the client should refresh it
+    TokenExpired,
+    InvalidRefreshToken,
+    // Unknown email or wrong password; deliberately doesn't say which
+    InvalidCredentials,
+    // Logged in, but the account's role doesn't allow this
+    Forbidden,
+    EmailTaken(String),
+    ProductNotFound(u32),
+    CategoryNotFound(String),
+    CartItemNotFound(u32),
+    CouponNotFound(String),
+    ShippingMethodNotFound(String),
+    OrderNotFound(String),
+    PaymentNotFound(String),
+    UserNotFound(String),
+    RouteNotFound,
+    MethodNotAllowed,
+    // Not enough of the product; `available` is how much the caller could still have, when known
+    OutOfStock { product_id: u32, title: String, available: Option<u32> },
+    InvalidOrderStatus(String),
+    CouponNotApplicable(String),
+    ShippingUnavailable(String),
+    InvalidAddress(String),
+    InvalidStockUpdate(String),
+    PricingFailed(String),
+    // The client used up its request budget; it may retry after this many seconds
+    RateLimited { retry_after: u64 },
+    // A service we depend on (catalog source, payment provider) failed
+    Upstream(String),
+    // Details are logged, never sent to the client
+    Internal(String),
+}
+
+impl ApiError {
+    // For `map_err` on internal errors: `.map_err(ApiError::internal("Failed to load cart"))`
+    pub fn internal(context: &'static str) -> impl Fn(String) -> ApiError {
+        move |e| ApiError::Internal(format!("{}: {}", context, e))
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidRequest(_) | ApiError::CartEmpty => StatusCode::BAD_REQUEST,
+            ApiError::InvalidSignature
+            | ApiError::Unauthorized
+            | ApiError::TokenExpired
+            | ApiError::InvalidRefreshToken
+            | ApiError::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::ProductNotFound(_)
+            | ApiError::CategoryNotFound(_)
+            | ApiError::CartItemNotFound(_)
+            | ApiError::CouponNotFound(_)
+            | ApiError::ShippingMethodNotFound(_)
+            | ApiError::OrderNotFound(_)
+            | ApiError::PaymentNotFound(_)
+            | ApiError::UserNotFound(_)
+            | ApiError::RouteNotFound => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::OutOfStock { .. } | ApiError::InvalidOrderStatus(_) | ApiError::EmailTaken(_) => {
+                StatusCode::CONFLICT
+            }
+            ApiError::CouponNotApplicable(_)
+            | ApiError::ShippingUnavailable(_)
+            | ApiError::InvalidAddress(_)
+            | ApiError::InvalidStockUpdate(_)
+            | ApiError::PricingFailed(_)
+            | ApiError::Validation(_)
+            | ApiError::InvalidBody(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::InvalidBody(_) => "invalid_body",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::CartEmpty => "cart_empty",
+            ApiError::InvalidSignature => "invalid_signature",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::TokenExpired => "token_expired",
+            ApiError::InvalidRefreshToken => "invalid_refresh_token",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::Forbidden => "forbidden",
+            ApiError::EmailTaken(_) => "email_taken",
+            ApiError::ProductNotFound(_) => "product_not_found",
+            ApiError::CategoryNotFound(_) => "category_not_found",
+            ApiError::CartItemNotFound(_) => "cart_item_not_found",
+            ApiError::CouponNotFound(_) => "coupon_not_found",
+            ApiError::ShippingMethodNotFound(_) => "shipping_method_not_found",
+            ApiError::OrderNotFound(_) => "order_not_found",
+            ApiError::PaymentNotFound(_) => "payment_not_found",
+            ApiError::UserNotFound(_) => "user_not_found",
+            ApiError::RouteNotFound => "route_not_found",
+            ApiError::MethodNotAllowed => "method_not_allowed",
+            ApiError::OutOfStock { .. } => "out_of_stock",
+            ApiError::InvalidOrderStatus(_) => "invalid_order_status",
+            ApiError::CouponNotApplicable(_) => "coupon_not_applicable",
+            ApiError::ShippingUnavailable(_) => "shipping_unavailable",
+            ApiError::InvalidAddress(_) => "invalid_address",
+            ApiError::InvalidStockUpdate(_) => "invalid_stock_update",
+            ApiError::PricingFailed(_) => "pricing_failed",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::Upstream(_) => "upstream_unavailable",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::Validation(_) => "Some fields are invalid".to_string(),
+            ApiError::CartEmpty => "Cart is empty".to_string(),
+            ApiError::InvalidSignature => "Invalid webhook signature".to_string(),
+            ApiError::Unauthorized => "Log in to continue".to_string(),
+            ApiError::TokenExpired => "Access token has expired".to_string(),
+            ApiError::InvalidRefreshToken => "Refresh token is invalid, expired or already used".to_string(),
+            ApiError::InvalidCredentials => "Wrong email or password".to_string(),
+            ApiError::Forbidden => "Your account is not allowed to do this".to_string(),
+            ApiError::EmailTaken(email) => format!("An account for {} already exists", email),
+            ApiError::ProductNotFound(id) => format!("Product {} not found", id),
+            ApiError::CategoryNotFound(name) => format!("Category {} not found", name),
+            ApiError::CartItemNotFound(id) => format!("Product {} is not in the cart", id),
+            ApiError::CouponNotFound(code) => format!("Coupon {} does not exist", code),
+            ApiError::ShippingMethodNotFound(id) => format!("Shipping method {} does not exist", id),
+            ApiError::OrderNotFound(id) => format!("Order {} not found", id),
+            ApiError::PaymentNotFound(id) => format!("Payment {} is not awaiting confirmation", id),
+            ApiError::UserNotFound(id) => format!("User {} not found", id),
+            ApiError::RouteNotFound => "No such endpoint".to_string(),
+            ApiError::MethodNotAllowed => "Method not allowed for this endpoint".to_string(),
+            ApiError::OutOfStock { title, available: Some(available), .. } => {
+                format!("Only {} of {} left in stock", available, title)
+            }
+            ApiError::OutOfStock { title, available: None, .. } => format!("Not enough {} in stock", title),
+            ApiError::RateLimited { retry_after } => {
+                format!("Too many requests, try again in {} seconds", retry_after)
+            }
+            ApiError::Internal(_) => "Internal server error".to_string(),
+            ApiError::InvalidRequest(message)
+            | ApiError::InvalidBody(message)
+            | ApiError::UnsupportedMediaType(message)
+            | ApiError::InvalidOrderStatus(message)
+            | ApiError::CouponNotApplicable(message)
+            | ApiError::ShippingUnavailable(message)
+            | ApiError::InvalidAddress(message)
+            | ApiError::InvalidStockUpdate(message)
+            | ApiError::PricingFailed(message)
+            | ApiError::Upstream(message) => message.clone(),
+        }
+    }
+}
+
+// What is wrong with a field, e.g. "must be between 1 and 999"
+fn describe(error: &ValidationError) -> String {
+    if let Some(message) = &error.message {
+        return message.to_string();
+    }
+    let param = |name: &str| error.params.get(name).map(|value| value.to_string());
+    match (error.code.as_ref(), param("min"), param("max")) {
+        ("range", Some(min), Some(max)) => format!("must be between {} and {}", min, max),
+        ("range", Some(min), None) => format!("must be at least {}", min),
+        ("range", None, Some(max)) => format!("must be at most {}", max),
+        ("length", Some(min), Some(max)) => format!("must be {} to {} characters long", min, max),
+        ("length", Some(min), None) => format!("must be at least {} characters long", min),
+        ("length", None, Some(max)) => format!("must be at most {} characters long", max),
+        (code, _, _) => format!("is invalid ({})", code),
+    }
+}
+
+// Field name -> problems; checks spanning several fields are listed under "body"
+fn field_errors(errors: &ValidationErrors) -> BTreeMap<String, Vec<String>> {
+    errors
+        .field_errors()
+        .into_iter()
+        .map(|(field, errors)| {
+            let field = if field == "__all__" { "body".to_string() } else { field.to_string() };
+            (field, errors.iter().map(describe).collect())
+        })
+        .collect()
+}
+
+// Body of every error response
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    // Stable machine-readable code, e.g. "out_of_stock"
+    pub code: &'static str,
+    pub error: String,
+    // validation_failed: field name -> problems
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<BTreeMap<String, Vec<String>>>,
+    // out_of_stock: the product that ran out and how much of it is left for the caller
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available: Option<u32>,
+    // rate_limited: seconds until the request may be retried, as in the Retry-After header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let ApiError::Internal(details) = &self {
+            eprintln!("Internal error: {}", details);
+        }
+
+        let mut body = ErrorBody {
+            code: self.code(),
+            error: self.message(),
+            fields: None,
+            product_id: None,
+            available: None,
+            retry_after: None,
+        };
+        match &self {
+            ApiError::OutOfStock { product_id, available, .. } => {
+                body.product_id = Some(*product_id);
+                body.available = *available;
+            }
+            ApiError::Validation(errors) => body.fields = Some(field_errors(errors)),
+            ApiError::RateLimited { retry_after } => body.retry_after = Some(*retry_after),
+            _ => {}
+        }
+        let mut res = (self.status(), Json(body)).into_response();
+        match self {
+            ApiError::Unauthorized => {
+                res.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            ApiError::TokenExpired => {
+                res.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Bearer error=\"invalid_token\", error_description=\"expired\""),
+                );
+            }
+            ApiError::RateLimited { retry_after } => {
+                res.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+            }
+            _ => {}
+        }
+        res
+    }
+}
+
+// Keeps axum's distinction between unreadable, wrongly typed and wrongly shaped bodies
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection {
+            JsonRejection::MissingJsonContentType(_) => ApiError::UnsupportedMediaType(rejection.body_text()),
+            JsonRejection::JsonDataError(_) => ApiError::InvalidBody(rejection.body_text()),
+            _ => ApiError::InvalidRequest(rejection.body_text()),
+        }
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::InvalidRequest(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        ApiError::InvalidRequest(rejection.body_text())
+    }
+}
+
+// Answers requests no route matches
+pub async fn route_not_found() -> ApiError {
+    ApiError::RouteNotFound
+}
+
+pub async fn method_not_allowed() -> ApiError {
+    ApiError::MethodNotAllowed
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use axum::extract::FromRequest;
+    use axum::http::Request;
+    use serde_json::{json, Value};
+    use shared::models::AddToCartPayload;
+    use crate::extract::ApiJson;
+    use super::*;
+
+    async fn body(res: Response) -> Value {
+        serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap()
+    }
+
+    // How `ApiJson` rejects a body sent with `content_type`
+    async fn rejection(content_type: Option<&str>, body: &str) -> ApiError {
+        let mut req = Request::builder().method("POST").uri("/cart/items");
+        if let Some(content_type) = content_type {
+            req = req.header(header::CONTENT_TYPE, content_type);
+        }
+        let req = req.body(Body::from(body.to_string())).unwrap();
+        match ApiJson::<AddToCartPayload>::from_request(req, &()).await {
+            Ok(_) => panic!("{} was accepted", body),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn every_error_has_a_status_and_a_code() {
+        let cases = [
+            (ApiError::InvalidRequest("bad".to_string()), StatusCode::BAD_REQUEST, "invalid_request"),
+            (ApiError::CartEmpty, StatusCode::BAD_REQUEST, "cart_empty"),
+            (ApiError::InvalidSignature, StatusCode::UNAUTHORIZED, "invalid_signature"),
+            (ApiError::TokenExpired, StatusCode::UNAUTHORIZED, "token_expired"),
+            (ApiError::Forbidden, StatusCode::FORBIDDEN, "forbidden"),
+            (ApiError::ProductNotFound(1), StatusCode::NOT_FOUND, "product_not_found"),
+            (ApiError::RouteNotFound, StatusCode::NOT_FOUND, "route_not_found"),
+            (ApiError::MethodNotAllowed, StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed"),
+            (ApiError::UnsupportedMediaType("json".to_string()), StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type"),
+            (ApiError::EmailTaken("a@b.se".to_string()), StatusCode::CONFLICT, "email_taken"),
+            (ApiError::InvalidOrderStatus("no".to_string()), StatusCode::CONFLICT, "invalid_order_status"),
+            (ApiError::Validation(ValidationErrors::new()), StatusCode::UNPROCESSABLE_ENTITY, "validation_failed"),
+            (ApiError::InvalidBody("shape".to_string()), StatusCode::UNPROCESSABLE_ENTITY, "invalid_body"),
+            (ApiError::PricingFailed("tax".to_string()), StatusCode::UNPROCESSABLE_ENTITY, "pricing_failed"),
+            (ApiError::RateLimited { retry_after: 3 }, StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+            (ApiError::Upstream("down".to_string()), StatusCode::BAD_GATEWAY, "upstream_unavailable"),
+            (ApiError::Internal("disk".to_string()), StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+        ];
+        for (error, status, code) in cases {
+            assert_eq!((error.status(), error.code()), (status, code), "{:?}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_carry_the_details_of_their_error() {
+        let res = ApiError::OutOfStock { product_id: 1, title: "Mug".to_string(), available: Some(2) }.into_response();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            body(res).await,
+            json!({ "code": "out_of_stock", "error": "Only 2 of Mug left in stock", "product_id": 1, "available": 2 })
+        );
+
+        let res = ApiError::RateLimited { retry_after: 7 }.into_response();
+        assert_eq!(res.headers()[header::RETRY_AFTER], "7");
+        assert_eq!(body(res).await["retry_after"], 7);
+
+        let res = ApiError::Unauthorized.into_response();
+        assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+    }
+
+    #[tokio::test]
+    async fn internal_details_stay_out_of_the_response() {
+        let res = ApiError::Internal("database is locked".to_string()).into_response();
+        assert_eq!(body(res).await, json!({ "code": "internal_error", "error": "Internal server error" }));
+    }
+
+    #[tokio::test]
+    async fn validation_errors_are_listed_per_field() {
+        let mut errors = ValidationErrors::new();
+        let mut range = ValidationError::new("range");
+        range.add_param("min".into(), &1);
+        range.add_param("max".into(), &999);
+        errors.add("quantity", range);
+        errors.add("__all__", ValidationError::new("passwords_match").with_message("passwords differ".into()));
+        let body = body(ApiError::Validation(errors).into_response()).await;
+        assert_eq!(body["fields"], json!({ "quantity": ["must be between 1 and 999"], "body": ["passwords differ"] }));
+    }
+
+    #[tokio::test]
+    async fn json_bodies_are_rejected_by_what_is_wrong_with_them() {
+        let missing_type = rejection(None, r#"{"product_id": 1}"#).await;
+        assert_eq!(missing_type.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let wrong_type = rejection(Some("text/plain"), r#"{"product_id": 1}"#).await;
+        assert_eq!(wrong_type.code(), "unsupported_media_type");
+
+        let syntax = rejection(Some("application/json"), r#"{"product_id": "#).await;
+        assert_eq!((syntax.status(), syntax.code()), (StatusCode::BAD_REQUEST, "invalid_request"));
+        let shape = rejection(Some("application/json"), r#"{"product_id": "mug"}"#).await;
+        assert_eq!((shape.status(), shape.code()), (StatusCode::UNPROCESSABLE_ENTITY, "invalid_body"));
+        let invalid = rejection(Some("application/json"), r#"{"product_id": 1, "quantity": 0}"#).await;
+        assert_eq!(invalid.code(), "validation_failed");
+    }
+}
@@ -0,0 +1,182 @@
+use std::sync::Arc;
+use std::time::Duration;
+use shared::models::{CartItem, OrderLine, StockLevel};
+use crate::config::InventoryConfig;
+use crate::storage::{now_secs, InventoryStorage};
+
+// Stock a product starts with the first time it is used
+pub const DEFAULT_STOCK: u32 = 100;
+// How long items in a cart are held for it after the cart last changed
+pub const RESERVATION_TTL: Duration = Duration::from_secs(15 * 60);
+
+// Stock levels and cart reservations on top of the inventory storage
+#[derive(Clone)]
+pub struct Inventory {
+    storage: Arc<dyn InventoryStorage>,
+    default_stock: u32,
+    reservation_ttl: Duration,
+}
+
+impl Inventory {
+    pub fn new(storage: Arc<dyn InventoryStorage>, default_stock: u32, reservation_ttl: Duration) -> Self {
+        Inventory {
+            storage,
+            default_stock,
+            reservation_ttl,
+        }
+    }
+
+    pub fn level(&self, product_id: u32) -> Result<StockLevel, String> {
+        self.storage.init_stock(product_id, self.default_stock)?;
+        self.storage
+            .stock_level(product_id, now_secs())?
+            .ok_or_else(|| format!("Product {} has no stock level", product_id))
+    }
+
+    // How much of the product the session's cart could hold
+    pub fn available(&self, session_id: &str, product_id: u32) -> Result<u32, String> {
+        self.storage.init_stock(product_id, self.default_stock)?;
+        self.storage.available(session_id, product_id, now_secs())
+    }
+
+    // Holds `quantity` of the product for the session's cart, renewing the reservation;
+    // returns false if that much isn't available
+    pub fn reserve(&self, session_id: &str, product_id: u32, quantity: u32) -> Result<bool, String> {
+        self.storage.init_stock(product_id, self.default_stock)?;
+        let now = now_secs();
+        self.storage.reserve(session_id, product_id, quantity, now + self.reservation_ttl.as_secs(), now)
+    }
+
+    pub fn release(&self, session_id: &str, product_id: u32) -> Result<(), String> {
+        self.storage.reserve(session_id, product_id, 0, 0, now_secs()).map(|_| ())
+    }
+
+    // Takes the cart out of stock at checkout; returns the id of a product that is short, if any.
+    // Items whose reservation expired still go through as long as the stock is there.
+    pub fn commit(&self, session_id: &str, cart: &[CartItem]) -> Result<Option<u32>, String> {
+        let items: Vec<(u32, u32)> = cart.iter().map(|item| (item.product.id, item.quantity)).collect();
+        for (product_id, _) in &items {
+            self.storage.init_stock(*product_id, self.default_stock)?;
+        }
+        self.storage.commit_stock(session_id, &items, now_secs())
+    }
+
+    // Puts the lines of an order back in stock, e.g. when it is cancelled
+    pub fn restock(&self, lines: &[OrderLine]) -> Result<(), String> {
+        for line in lines {
+            self.storage.adjust_stock(line.product_id, line.quantity as i64)?;
+        }
+        Ok(())
+    }
+
+    pub fn set(&self, product_id: u32, on_hand: u32) -> Result<StockLevel, String> {
+        self.storage.set_stock(product_id, on_hand)?;
+        self.level(product_id)
+    }
+
+    // Changes the stock by `delta`; None if that would make it negative
+    pub fn adjust(&self, product_id: u32, delta: i64) -> Result<Option<StockLevel>, String> {
+        self.storage.init_stock(product_id, self.default_stock)?;
+        match self.storage.adjust_stock(product_id, delta)? {
+            Some(_) => self.level(product_id).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+pub fn from_config(storage: Arc<dyn InventoryStorage>, config: &InventoryConfig) -> Inventory {
+    Inventory::new(storage, config.default_stock, config.reservation_ttl)
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::models::Product;
+    use shared::Money;
+    use crate::storage::{CartStorage, MemoryStorage};
+    use super::*;
+
+    fn inventory() -> Inventory {
+        let storage = Arc::new(MemoryStorage::new());
+        for session in ["a", "b"] {
+            storage.create_session(session).unwrap();
+        }
+        Inventory::new(storage, 5, RESERVATION_TTL)
+    }
+
+    fn item(product_id: u32, quantity: u32) -> CartItem {
+        CartItem {
+            product: Product {
+                id: product_id,
+                title: format!("Product {}", product_id),
+                price: Money::new(100, "SEK"),
+                description: String::new(),
+                category: "misc".to_string(),
+                image: String::new(),
+                weight: None,
+            },
+            quantity,
+        }
+    }
+
+    #[test]
+    fn products_start_with_the_default_stock() {
+        let inventory = inventory();
+        assert_eq!(inventory.level(1), Ok(StockLevel { product_id: 1, on_hand: 5, reserved: 0, available: 5 }));
+        assert_eq!(inventory.available("a", 2), Ok(5));
+    }
+
+    #[test]
+    fn reservations_hold_stock_for_one_session_only() {
+        let inventory = inventory();
+        assert_eq!(inventory.reserve("a", 1, 3), Ok(true));
+        assert_eq!(inventory.available("a", 1), Ok(5));
+        assert_eq!(inventory.available("b", 1), Ok(2));
+        assert_eq!(inventory.reserve("b", 1, 3), Ok(false));
+        assert_eq!(inventory.reserve("b", 1, 2), Ok(true));
+
+        // A new reservation replaces the session's earlier one
+        assert_eq!(inventory.reserve("a", 1, 1), Ok(true));
+        assert_eq!(inventory.level(1).map(|level| level.reserved), Ok(3));
+        inventory.release("a", 1).unwrap();
+        assert_eq!(inventory.level(1).map(|level| level.available), Ok(3));
+    }
+
+    #[test]
+    fn checkout_takes_the_cart_out_of_stock() {
+        let inventory = inventory();
+        inventory.reserve("a", 1, 2).unwrap();
+        assert_eq!(inventory.commit("a", &[item(1, 2), item(2, 5)]), Ok(None));
+        assert_eq!(inventory.level(1), Ok(StockLevel { product_id: 1, on_hand: 3, reserved: 0, available: 3 }));
+        assert_eq!(inventory.level(2).map(|level| level.on_hand), Ok(0));
+
+        // Nothing changes when one product is short
+        assert_eq!(inventory.commit("b", &[item(1, 1), item(2, 1)]), Ok(Some(2)));
+        assert_eq!(inventory.level(1).map(|level| level.on_hand), Ok(3));
+    }
+
+    #[test]
+    fn cancelled_orders_are_restocked() {
+        let inventory = inventory();
+        inventory.commit("a", &[item(1, 4)]).unwrap();
+        let line = OrderLine {
+            product_id: 1,
+            title: "Product 1".to_string(),
+            unit_price: Money::new(100, "SEK"),
+            quantity: 4,
+            line_total: Money::new(400, "SEK"),
+            discount: None,
+        };
+        inventory.restock(&[line]).unwrap();
+        assert_eq!(inventory.level(1).map(|level| level.on_hand), Ok(5));
+    }
+
+    #[test]
+    fn stock_can_be_set_or_adjusted_but_not_below_zero() {
+        let inventory = inventory();
+        assert_eq!(inventory.set(1, 8).map(|level| level.on_hand), Ok(8));
+        assert_eq!(inventory.adjust(1, -3).map(|level| level.map(|l| l.on_hand)), Ok(Some(5)));
+        assert_eq!(inventory.adjust(1, -6), Ok(None));
+        // Products without a level get the default before the change
+        assert_eq!(inventory.adjust(2, 2).map(|level| level.map(|l| l.on_hand)), Ok(Some(7)));
+    }
+}
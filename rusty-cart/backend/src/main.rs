@@ -1,38 +1,226 @@
-use axum::{routing::{get, post}, http::Method, Router};
-use tower_http::cors::{CorsLayer, Any};
+use std::net::SocketAddr;
+use std::time::Duration;
+use axum::{http::{header, HeaderName, Method}, middleware, Router};
+use tower_http::cors::CorsLayer;
+use config::{Config, CorsConfig};
+use utoipa::OpenApi;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use utoipa_swagger_ui::SwaggerUi;
 use state::AppState;
-use routers::{get_products, get_cart, add_to_cart, edit_cart_item, delete_cart_item};
+use catalog_cache::CachedCatalog;
+use session::{session_layer, SESSION_TTL};
+use shared::api::{ADMIN_PREFIX, PAYMENT_SIGNATURE_HEADER, SESSION_HEADER, TOTAL_COUNT_HEADER};
+use shared::models::Role;
 use tokio::signal;
 
-mod models;
+mod auth;
+mod cart_merge;
+mod catalog;
+mod catalog_cache;
+mod config;
+mod coupons;
+mod error;
+mod extract;
+mod inventory;
+mod openapi;
+mod orders;
+mod payments;
+mod pricing;
+mod rate_limit;
 mod routers;
+mod search;
+mod session;
+mod shipping;
 mod state;
+mod storage;
+mod tax;
+#[cfg(test)]
+mod test_support;
+
+// The cart item routes replaced by `/cart/items`, kept for one release
+#[allow(deprecated)]
+fn deprecated_cart_routes() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(routes!(routers::add_to_cart))
+        .routes(routes!(routers::edit_cart_item))
+        .routes(routes!(routers::delete_cart_item))
+}
+
+// Catalog, stock, order and account management, nested under /admin. Everything needs a staff
+// account; managing accounts needs an admin.
+fn admin_routes(state: &AppState) -> OpenApiRouter<AppState> {
+    let admin_only = OpenApiRouter::new()
+        .routes(routes!(routers::list_users))
+        .routes(routes!(routers::set_user_role))
+        .route_layer(middleware::from_fn_with_state((state.clone(), Role::Admin), auth::require_role));
+
+    OpenApiRouter::new()
+        .routes(routes!(routers::refresh_catalog))
+        .routes(routes!(routers::create_product))
+        .routes(routes!(routers::update_product, routers::delete_product))
+        .routes(routes!(routers::get_stock, routers::update_stock))
+        .routes(routes!(routers::list_all_orders))
+        .routes(routes!(routers::get_any_order))
+        .routes(routes!(routers::update_order_status))
+        .route_layer(middleware::from_fn_with_state((state.clone(), Role::Staff), auth::require_role))
+        .merge(admin_only)
+}
+
+// The whole HTTP API. Routes are registered together with their OpenAPI documentation,
+// which is served at /openapi.json and browsable in the Swagger UI.
+fn app(state: AppState, cors: &CorsConfig) -> Router {
+    let cors = CorsLayer::new()
+    .allow_origin(cors.allow_origin())
+    .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE, Method::OPTIONS])
+    .allow_headers([
+        header::CONTENT_TYPE,
+        header::AUTHORIZATION,
+        HeaderName::from_static(SESSION_HEADER),
+        HeaderName::from_static(PAYMENT_SIGNATURE_HEADER),
+    ])
+    .expose_headers([
+        HeaderName::from_static(SESSION_HEADER),
+        HeaderName::from_static(TOTAL_COUNT_HEADER),
+        header::WARNING,
+        header::LINK,
+        HeaderName::from_static("deprecation"),
+    ]);
+
+    let cart_routes = OpenApiRouter::new()
+        .routes(routes!(routers::get_cart, routers::clear_cart))
+        .routes(routes!(routers::add_cart_item))
+        .routes(routes!(routers::update_cart_item, routers::remove_cart_item))
+        .merge(deprecated_cart_routes())
+        .routes(routes!(routers::apply_coupon, routers::remove_coupon))
+        .routes(routes!(routers::set_address))
+        .routes(routes!(routers::select_shipping))
+        .routes(routes!(routers::checkout))
+        .routes(routes!(routers::get_orders))
+        .routes(routes!(routers::get_order))
+        .routes(routes!(routers::cancel_order))
+        .routes(routes!(routers::pay_order))
+        .route_layer(middleware::from_fn_with_state(state.clone(), session_layer));
+
+    let (router, api) = OpenApiRouter::with_openapi(openapi::ApiDoc::openapi())
+        .routes(routes!(routers::get_products))
+        .routes(routes!(routers::get_product))
+        .routes(routes!(routers::get_categories))
+        .routes(routes!(routers::get_category_products))
+        .routes(routes!(routers::register))
+        .routes(routes!(routers::login))
+        .routes(routes!(routers::logout))
+        .routes(routes!(routers::refresh))
+        .routes(routes!(routers::confirm_payment))
+        .routes(routes!(routers::payment_webhook))
+        .merge(cart_routes)
+        .nest(ADMIN_PREFIX, admin_routes(&state))
+        .split_for_parts();
+
+    router
+        .merge(SwaggerUi::new(openapi::DOCS_PATH).url(openapi::SPEC_PATH, api))
+        .fallback(error::route_not_found)
+        .method_not_allowed_fallback(error::method_not_allowed)
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit::rate_limit_layer))
+        .layer(middleware::from_fn_with_state(state.clone(), auth::auth_layer))
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit::ip_rate_limit_layer))
+        .with_state(state)
+        .layer(cors)
+}
 
 #[tokio::main]
 async fn main() {
-    let app_state = AppState::new();
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let source = catalog::from_config(&config.catalog).expect("Invalid catalog configuration");
+    let refresh_interval = config.catalog.refresh_interval;
+    let storage = storage::from_config(&config.storage).expect("Invalid cart storage configuration");
+    let payments = payments::from_config(&config.payments, config.listen);
+    let coupons = coupons::from_config(config.checkout.coupons_file.as_deref()).expect("Invalid coupon configuration");
+    let shipping = shipping::from_config(config.checkout.shipping_file.as_deref()).expect("Invalid shipping configuration");
+    let tax = tax::from_config(config.checkout.tax_file.as_deref()).expect("Invalid tax configuration");
+    let inventory = inventory::from_config(storage.clone(), &config.inventory);
+    let tokens = auth::TokenIssuer::new(&config.auth);
+    let app_state = AppState::new(
+        CachedCatalog::new(source, storage.clone(), config.catalog.ttl),
+        storage,
+        payments,
+        coupons,
+        shipping,
+        tax,
+        inventory,
+        tokens,
+        config.cart.merge_policy,
+        config.auth.admin_emails.clone(),
+        rate_limit::RateLimiter::new(&config.rate_limit),
+    );
 
-    let cors = CorsLayer::new()
-    .allow_origin(Any)
-    .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-    .allow_headers(Any);
-
-    let app = Router::new()
-        .route("/products", get(get_products))
-        .route("/cart", get(get_cart))
-        .route("/cart/add", post(add_to_cart))
-        .route("/cart/edit", post(edit_cart_item))
-        .route("/cart/delete", post(delete_cart_item))
-        .with_state(app_state)
-        .layer(cors);
-
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+    // Accounts registered before their email was configured as an admin's
+    for email in &config.auth.admin_emails {
+        match app_state.storage.user_by_email(email) {
+            Ok(Some(user)) if user.role != Role::Admin => match app_state.storage.set_role(&user.id, Role::Admin) {
+                Ok(_) => println!("Made {} an admin", email),
+                Err(e) => eprintln!("Failed to make {} an admin: {}", email, e),
+            },
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to look up admin {}: {}", email, e),
+        }
+    }
+
+    // Load the catalog at startup and keep it fresh in the background
+    {
+        let catalog = app_state.catalog.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(refresh_interval);
+            loop {
+                interval.tick().await;
+                match catalog.refresh().await {
+                    Ok(count) => println!("Catalog refreshed with {} products", count),
+                    Err(e) => eprintln!("Catalog refresh failed: {}", e),
+                }
+            }
+        });
+    }
+
+    // Periodically drop idle sessions and their carts
+    {
+        let sessions = app_state.sessions.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let sessions = sessions.clone();
+                let expired = tokio::task::spawn_blocking(move || sessions.expire_idle(SESSION_TTL)).await.unwrap_or(0);
+                if expired > 0 {
+                    println!("Expired {} idle sessions", expired);
+                }
+            }
+        });
+    }
+
+    // Periodically forget the request budgets of clients that have gone quiet
+    {
+        let rate_limiter = app_state.rate_limiter.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                rate_limiter.retain_recent();
+            }
+        });
+    }
+
+    let app = app(app_state, &config.cors);
+
+    let listener = tokio::net::TcpListener::bind(config.listen)
         .await
-        .expect("Failed to bind to port");
+        .unwrap_or_else(|e| panic!("Failed to bind to {}: {}", config.listen, e));
 
     println!("Server running at http://{}", listener.local_addr().unwrap());
 
-    axum::serve(listener, app)
+    // Rate limiting needs the address of each client
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(async {
             signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
             println!("Shutting down gracefully...");
@@ -0,0 +1,156 @@
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use shared::api::{PAYMENT_SIGNATURE_HEADER, SESSION_HEADER};
+
+// Where the generated OpenAPI document and the Swagger UI are served
+pub const SPEC_PATH: &str = "/openapi.json";
+pub const DOCS_PATH: &str = "/swagger-ui";
+
+// Top level of the OpenAPI document; the paths are added by the routes registered in `main::app`
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Rusty Cart",
+        description = "Product catalog, carts, accounts, orders and payments. \
+            Errors are JSON with a stable `code` and a human-readable `error` message.",
+    ),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "catalog", description = "Products and categories"),
+        (name = "auth", description = "Accounts and login tokens"),
+        (name = "cart", description = "The cart of the logged-in account, or of the guest session"),
+        (name = "orders", description = "Checkout and the caller's orders"),
+        (name = "payments", description = "Paying orders and the payment provider's callbacks"),
+        (name = "admin", description = "Catalog, stock, order and account management; staff or admin role required"),
+    )
+)]
+pub struct ApiDoc;
+
+// The session id and the webhook signature are sent in headers; as API keys the Swagger UI can fill them in.
+// Login tokens are plain bearer tokens.
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                SESSION_HEADER,
+                "Session id from an earlier response; a new session is started without it",
+            ))),
+        );
+        components.add_security_scheme(
+            "payment_signature",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                PAYMENT_SIGNATURE_HEADER,
+                "Shared secret of the payment provider",
+            ))),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .description(Some("Access token from `POST /auth/login`, `/auth/register` or `/auth/refresh`"))
+                    .build(),
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use axum::http::{header, Method, Request, StatusCode};
+    use axum::Router;
+    use serde_json::Value;
+    use tower::ServiceExt;
+    use crate::test_support::TestApp;
+    use shared::api::ADMIN_PREFIX;
+    use super::SPEC_PATH;
+
+    const METHODS: [Method; 5] = [Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE];
+
+    fn test_app() -> Router {
+        TestApp::new().router
+    }
+
+    // Status and error code (if any) of the app's answer to an empty JSON request
+    async fn answer(app: &Router, method: Method, uri: &str) -> (StatusCode, Option<String>) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{}"))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let code = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|body| body["code"].as_str().map(str::to_string));
+        (status, code)
+    }
+
+    async fn served_spec(app: &Router) -> Value {
+        let request = Request::get(SPEC_PATH).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+    }
+
+    // Fills every `{param}` of a path template with 1
+    fn example_uri(path: &str) -> String {
+        path.split('/')
+            .map(|segment| if segment.starts_with('{') { "1" } else { segment })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[tokio::test]
+    async fn spec_matches_the_router() {
+        let app = test_app();
+        let spec = served_spec(&app).await;
+        let paths = spec["paths"].as_object().unwrap();
+        assert!(!paths.is_empty());
+
+        for (path, item) in paths {
+            let uri = example_uri(path);
+            for method in METHODS {
+                let documented = item.get(method.as_str().to_lowercase()).is_some();
+                let (status, code) = answer(&app, method.clone(), &uri).await;
+                let routed = status != StatusCode::METHOD_NOT_ALLOWED && code.as_deref() != Some("route_not_found");
+                assert_eq!(
+                    documented, routed,
+                    "{} {} is {}",
+                    method,
+                    path,
+                    if documented { "documented but not routed" } else { "routed but not documented" }
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn every_endpoint_is_documented() {
+        let spec = served_spec(&test_app()).await;
+        let constants = |suffix: &str| -> Vec<String> {
+            include_str!("../../shared/src/api.rs")
+                .lines()
+                .filter(|line| line.starts_with("pub const ") && line.contains(&format!("{}: &str", suffix)))
+                .filter_map(|line| line.split('"').nth(1))
+                .map(str::to_string)
+                .collect()
+        };
+        let mut endpoints = constants("_ENDPOINT");
+        let admin_paths = constants("_PATH");
+        assert!(!endpoints.is_empty() && !admin_paths.is_empty());
+        endpoints.extend(admin_paths.iter().map(|path| format!("{}{}", ADMIN_PREFIX, path)));
+
+        for endpoint in endpoints {
+            assert!(spec["paths"].get(&endpoint).is_some(), "{} is missing from the OpenAPI document", endpoint);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use shared::models::{CartSummary, Order, OrderLine, OrderShipping, OrderStatus};
+use uuid::Uuid;
+use crate::storage::now_secs;
+
+// Snapshots the priced cart into a new pending order
+pub fn order_from_summary(summary: &CartSummary) -> Order {
+    let lines = summary
+        .items
+        .iter()
+        .map(|line| OrderLine {
+            product_id: line.item.product.id,
+            title: line.item.product.title.clone(),
+            unit_price: line.item.product.price.clone(),
+            quantity: line.item.quantity,
+            line_total: line.subtotal.clone(),
+            discount: Some(line.discount.clone()).filter(|d| !d.is_zero()),
+        })
+        .collect();
+    let coupon = summary
+        .coupon
+        .as_ref()
+        .filter(|coupon| coupon.applied)
+        .map(|coupon| coupon.code.clone());
+    let shipping = summary
+        .shipping
+        .as_ref()
+        .filter(|shipping| shipping.available)
+        .map(|shipping| OrderShipping {
+            method: shipping.method.clone(),
+            name: shipping.name.clone(),
+            cost: shipping.gross.clone(),
+        });
+
+    let now = now_secs();
+    Order {
+        id: Uuid::new_v4().to_string(),
+        lines,
+        coupon,
+        discount: Some(summary.discount.clone()).filter(|d| !d.is_zero()),
+        tax: Some(summary.tax.clone()),
+        address: summary.address.clone(),
+        shipping,
+        total: summary.total.clone(),
+        status: OrderStatus::Pending,
+        payment_id: None,
+        created_at: now,
+        updated_at: now,
+    }
+}
+
+// Moves the order to `next`, rejecting transitions the status rules don't allow
+pub fn transition(order: &mut Order, next: OrderStatus) -> Result<(), String> {
+    if !order.status.can_transition_to(next) {
+        return Err(format!(
+            "Cannot change order status from {} to {}",
+            order.status.as_str(),
+            next.as_str()
+        ));
+    }
+    order.status = next;
+    order.updated_at = now_secs();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::Money;
+    use super::*;
+
+    const ALL: [OrderStatus; 4] = [OrderStatus::Pending, OrderStatus::Paid, OrderStatus::Shipped, OrderStatus::Cancelled];
+
+    fn order(status: OrderStatus) -> Order {
+        Order {
+            id: "o1".to_string(),
+            lines: Vec::new(),
+            coupon: None,
+            discount: None,
+            tax: None,
+            address: None,
+            shipping: None,
+            total: Money::zero("SEK"),
+            status,
+            payment_id: None,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    // Every status the order may move to from `from`
+    fn reachable(from: OrderStatus) -> Vec<OrderStatus> {
+        ALL.into_iter().filter(|next| transition(&mut order(from), *next).is_ok()).collect()
+    }
+
+    #[test]
+    fn orders_move_forward_until_shipped_or_cancelled() {
+        assert_eq!(reachable(OrderStatus::Pending), [OrderStatus::Paid, OrderStatus::Cancelled]);
+        assert_eq!(reachable(OrderStatus::Paid), [OrderStatus::Shipped, OrderStatus::Cancelled]);
+        assert!(reachable(OrderStatus::Shipped).is_empty());
+        assert!(reachable(OrderStatus::Cancelled).is_empty());
+    }
+
+    #[test]
+    fn transitions_stamp_the_order_and_refusals_leave_it_alone() {
+        let mut paid = order(OrderStatus::Pending);
+        transition(&mut paid, OrderStatus::Paid).unwrap();
+        assert_eq!(paid.status, OrderStatus::Paid);
+        assert!(paid.updated_at > 0);
+
+        let mut shipped = order(OrderStatus::Shipped);
+        let err = transition(&mut shipped, OrderStatus::Cancelled).unwrap_err();
+        assert_eq!(err, "Cannot change order status from shipped to cancelled");
+        assert_eq!((shipped.status, shipped.updated_at), (OrderStatus::Shipped, 0));
+    }
+
+    #[test]
+    fn only_pending_orders_can_be_cancelled_by_customers() {
+        let cancellable: Vec<_> = ALL.into_iter().filter(|status| status.customer_can_cancel()).collect();
+        assert_eq!(cancellable, [OrderStatus::Pending]);
+    }
+}
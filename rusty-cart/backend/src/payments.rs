@@ -0,0 +1,314 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, PoisonError};
+use async_trait::async_trait;
+use clap::ValueEnum;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use shared::api::{CONFIRM_PAYMENT_ENDPOINT, PAYMENT_SIGNATURE_HEADER, PAYMENT_WEBHOOK_ENDPOINT};
+use shared::models::{Order, PaymentEvent, PaymentEventKind, PaymentOutcome};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+use crate::config::PaymentsConfig;
+
+// Something that can take money for an order
+#[async_trait]
+pub trait PaymentProvider: Send + Sync {
+    async fn charge(&self, order: &Order) -> Result<PaymentOutcome, String>;
+
+    // Completes a payment that returned `RequiresConfirmation`. The result is not returned
+    // here but delivered to the payment webhook, like a real provider would.
+    async fn confirm(&self, payment_id: &str, approve: bool) -> Result<(), String>;
+
+    // Checks that a webhook call really comes from this provider
+    fn verify_webhook(&self, signature: Option<&str>) -> bool;
+}
+
+// What the fake provider does with the next charge
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FakeBehavior {
+    Approve,
+    Decline,
+    #[serde(rename = "confirm")]
+    #[value(name = "confirm")]
+    RequireConfirmation,
+}
+
+// Local stand-in for a payment gateway. Charges follow the script first and then
+// the default behavior; confirmations are reported by calling the webhook over HTTP.
+pub struct FakePaymentProvider {
+    default: FakeBehavior,
+    script: Mutex<VecDeque<FakeBehavior>>,
+    // payment id -> order id for payments waiting for confirmation
+    awaiting_confirmation: Mutex<HashMap<String, String>>,
+    webhook_url: String,
+    webhook_secret: String,
+    client: reqwest::Client,
+}
+
+impl FakePaymentProvider {
+    pub fn new(default: FakeBehavior, webhook_url: impl Into<String>, webhook_secret: impl Into<String>) -> Self {
+        FakePaymentProvider {
+            default,
+            script: Mutex::new(VecDeque::new()),
+            awaiting_confirmation: Mutex::new(HashMap::new()),
+            webhook_url: webhook_url.into(),
+            webhook_secret: webhook_secret.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    // Queues behaviors used, in order, for the next charges
+    pub fn with_script(mut self, script: impl IntoIterator<Item = FakeBehavior>) -> Self {
+        self.script.get_mut().unwrap_or_else(PoisonError::into_inner).extend(script);
+        self
+    }
+
+    fn next_behavior(&self) -> FakeBehavior {
+        self.script
+            .lock()
+            .ok()
+            .and_then(|mut script| script.pop_front())
+            .unwrap_or(self.default)
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for FakePaymentProvider {
+    async fn charge(&self, order: &Order) -> Result<PaymentOutcome, String> {
+        let payment_id = format!("fake_{}", Uuid::new_v4().simple());
+        let outcome = match self.next_behavior() {
+            FakeBehavior::Approve => PaymentOutcome::Approved { payment_id },
+            FakeBehavior::Decline => PaymentOutcome::Declined {
+                payment_id,
+                reason: "Card declined by fake provider".to_string(),
+            },
+            FakeBehavior::RequireConfirmation => {
+                self.awaiting_confirmation
+                    .lock()
+                    .map_err(|e| e.to_string())?
+                    .insert(payment_id.clone(), order.id.clone());
+                PaymentOutcome::RequiresConfirmation {
+                    confirmation_url: CONFIRM_PAYMENT_ENDPOINT.replace("{payment_id}", &payment_id),
+                    payment_id,
+                }
+            }
+        };
+        Ok(outcome)
+    }
+
+    async fn confirm(&self, payment_id: &str, approve: bool) -> Result<(), String> {
+        let order_id = self.awaiting_confirmation
+            .lock()
+            .map_err(|e| e.to_string())?
+            .remove(payment_id)
+            .ok_or_else(|| format!("Payment {} is not awaiting confirmation", payment_id))?;
+
+        let event = PaymentEvent {
+            payment_id: payment_id.to_string(),
+            order_id,
+            kind: if approve { PaymentEventKind::Succeeded } else { PaymentEventKind::Failed },
+        };
+
+        // Deliver in the background so the caller isn't blocked on the webhook
+        let request = self.client
+            .post(&self.webhook_url)
+            .header(PAYMENT_SIGNATURE_HEADER, &self.webhook_secret)
+            .json(&event);
+        tokio::spawn(async move {
+            match request.send().await.and_then(|res| res.error_for_status()) {
+                Ok(_) => println!("Delivered payment webhook for {}", event.payment_id),
+                Err(e) => eprintln!("Failed to deliver payment webhook for {}: {}", event.payment_id, e),
+            }
+        });
+        Ok(())
+    }
+
+    fn verify_webhook(&self, signature: Option<&str>) -> bool {
+        // Compares digests in constant time, so neither the time taken nor the length gives the secret away
+        signature.is_some_and(|signature| {
+            Sha256::digest(signature.as_bytes()).ct_eq(&Sha256::digest(self.webhook_secret.as_bytes())).into()
+        })
+    }
+}
+
+// Builds the fake provider. Without a webhook URL confirmations go to this server's own webhook.
+pub fn from_config(config: &PaymentsConfig, listen: SocketAddr) -> Arc<dyn PaymentProvider> {
+    let webhook_url = config.webhook_url.clone().unwrap_or_else(|| {
+        // A server listening on all interfaces is reached through loopback
+        let mut addr = listen;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        format!("http://{}{}", addr, PAYMENT_WEBHOOK_ENDPOINT)
+    });
+    let provider = FakePaymentProvider::new(config.behavior, webhook_url, config.webhook_secret.as_str());
+    Arc::new(provider.with_script(config.script.iter().copied()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+    use shared::api::{CART_ITEMS_ENDPOINT, CHECKOUT_ENDPOINT, ORDER_ENDPOINT, PAY_ORDER_ENDPOINT};
+    use crate::test_support::{TestApp, TestOptions, TestRequest, TestResponse};
+    use super::*;
+
+    const SECRET: &str = "test-webhook-secret";
+
+    fn app_with(provider: FakePaymentProvider) -> TestApp {
+        TestApp::with(TestOptions { payments: Arc::new(provider), ..TestOptions::default() })
+    }
+
+    fn app(script: impl IntoIterator<Item = FakeBehavior>) -> TestApp {
+        app_with(FakePaymentProvider::new(FakeBehavior::Approve, "http://127.0.0.1:9/webhook", SECRET).with_script(script))
+    }
+
+    // A guest session with a pending order, and the order's id
+    async fn pending_order(app: &TestApp) -> (String, String) {
+        let guest = app.guest().await;
+        TestRequest::post(CART_ITEMS_ENDPOINT)
+            .session(&guest)
+            .json(json!({ "product_id": 1, "quantity": 1 }))
+            .send(app)
+            .await;
+        let order = TestRequest::post(CHECKOUT_ENDPOINT).session(&guest).send(app).await;
+        assert_eq!(order.status, StatusCode::CREATED);
+        let id = order.body["id"].as_str().unwrap().to_string();
+        (guest, id)
+    }
+
+    async fn pay(app: &TestApp, guest: &str, order_id: &str) -> TestResponse {
+        TestRequest::post(&PAY_ORDER_ENDPOINT.replace("{id}", order_id)).session(guest).send(app).await
+    }
+
+    async fn order(app: &TestApp, guest: &str, order_id: &str) -> Value {
+        TestRequest::get(&ORDER_ENDPOINT.replace("{id}", order_id)).session(guest).send(app).await.body
+    }
+
+    async fn webhook(app: &TestApp, signature: Option<&str>, event: Value) -> TestResponse {
+        let mut request = TestRequest::post(PAYMENT_WEBHOOK_ENDPOINT).json(event);
+        if let Some(signature) = signature {
+            request = request.header(PAYMENT_SIGNATURE_HEADER, signature);
+        }
+        request.send(app).await
+    }
+
+    #[test]
+    fn only_the_exact_secret_verifies() {
+        let provider = FakePaymentProvider::new(FakeBehavior::Approve, "http://127.0.0.1:9/webhook", SECRET);
+        assert!(provider.verify_webhook(Some(SECRET)));
+        assert!(!provider.verify_webhook(None));
+        assert!(!provider.verify_webhook(Some("")));
+        assert!(!provider.verify_webhook(Some("test-webhook")));
+        assert!(!provider.verify_webhook(Some("test-webhook-secret2")));
+    }
+
+    #[tokio::test]
+    async fn approved_charges_mark_the_order_paid() {
+        let app = app([]);
+        let (guest, id) = pending_order(&app).await;
+
+        let paid = pay(&app, &guest, &id).await;
+        assert_eq!(paid.status, StatusCode::OK);
+        assert_eq!(paid.body["payment"]["status"], "approved");
+        assert_eq!(paid.body["order"]["status"], "paid");
+        assert_eq!(order(&app, &guest, &id).await["payment_id"], paid.body["payment"]["payment_id"]);
+
+        let again = pay(&app, &guest, &id).await;
+        assert_eq!((again.status, again.code()), (StatusCode::CONFLICT, Some("invalid_order_status")));
+    }
+
+    #[tokio::test]
+    async fn declined_charges_leave_the_order_pending() {
+        let app = app([FakeBehavior::Decline]);
+        let (guest, id) = pending_order(&app).await;
+
+        let declined = pay(&app, &guest, &id).await;
+        assert_eq!(declined.status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(declined.body["payment"]["status"], "declined");
+        let stored = order(&app, &guest, &id).await;
+        assert_eq!((stored["status"].as_str(), stored["payment_id"].as_str()), (Some("pending"), None));
+
+        // The customer can try again
+        assert_eq!(pay(&app, &guest, &id).await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn confirmed_payments_are_reported_to_the_webhook() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}{}", listener.local_addr().unwrap(), PAYMENT_WEBHOOK_ENDPOINT);
+        let app = app_with(FakePaymentProvider::new(FakeBehavior::RequireConfirmation, url, SECRET));
+        let router = app.router.clone();
+        let server = tokio::spawn(async move { axum::serve(listener, router).await });
+        let (guest, id) = pending_order(&app).await;
+
+        let pending = pay(&app, &guest, &id).await;
+        assert_eq!(pending.status, StatusCode::ACCEPTED);
+        assert_eq!(pending.body["order"]["status"], "pending");
+        let confirmation_url = pending.body["payment"]["confirmation_url"].as_str().unwrap();
+        let confirmed = TestRequest::post(confirmation_url).json(json!({ "approve": true })).send(&app).await;
+        assert_eq!(confirmed.status, StatusCode::ACCEPTED);
+        // Only once
+        let again = TestRequest::post(confirmation_url).json(json!({ "approve": true })).send(&app).await;
+        assert_eq!(again.status, StatusCode::NOT_FOUND);
+
+        // The webhook is called in the background
+        let mut status = Value::Null;
+        for _ in 0..50 {
+            status = order(&app, &guest, &id).await["status"].clone();
+            if status == "paid" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(status, "paid");
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn replayed_and_late_webhook_events_change_nothing() {
+        let app = app([FakeBehavior::RequireConfirmation]);
+        let (guest, id) = pending_order(&app).await;
+        let payment_id = pay(&app, &guest, &id).await.body["payment"]["payment_id"].clone();
+        let event = |kind: &str| json!({ "payment_id": payment_id, "order_id": id, "kind": kind });
+
+        let succeeded = webhook(&app, Some(SECRET), event("succeeded")).await;
+        assert_eq!((succeeded.status, succeeded.body["status"].as_str()), (StatusCode::OK, Some("paid")));
+        let replayed = webhook(&app, Some(SECRET), event("succeeded")).await;
+        assert_eq!((replayed.status, replayed.body["status"].as_str()), (StatusCode::OK, Some("paid")));
+        let late_failure = webhook(&app, Some(SECRET), event("failed")).await;
+        assert_eq!((late_failure.status, late_failure.body["status"].as_str()), (StatusCode::OK, Some("paid")));
+        assert_eq!(order(&app, &guest, &id).await["payment_id"], payment_id);
+    }
+
+    #[tokio::test]
+    async fn failed_payments_let_the_customer_try_again() {
+        let app = app([FakeBehavior::RequireConfirmation]);
+        let (guest, id) = pending_order(&app).await;
+        let payment_id = pay(&app, &guest, &id).await.body["payment"]["payment_id"].clone();
+
+        let failed = webhook(&app, Some(SECRET), json!({ "payment_id": payment_id, "order_id": id, "kind": "failed" })).await;
+        assert_eq!((failed.body["status"].as_str(), failed.body["payment_id"].as_str()), (Some("pending"), None));
+        // The failed payment no longer belongs to the order
+        let late = webhook(&app, Some(SECRET), json!({ "payment_id": payment_id, "order_id": id, "kind": "succeeded" })).await;
+        assert_eq!((late.status, late.code()), (StatusCode::BAD_REQUEST, Some("invalid_request")));
+        assert_eq!(pay(&app, &guest, &id).await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn webhooks_without_the_right_signature_are_refused() {
+        let app = app([FakeBehavior::RequireConfirmation]);
+        let (guest, id) = pending_order(&app).await;
+        let payment_id = pay(&app, &guest, &id).await.body["payment"]["payment_id"].clone();
+        let event = json!({ "payment_id": payment_id, "order_id": id, "kind": "succeeded" });
+
+        for signature in [None, Some("wrong"), Some("")] {
+            let response = webhook(&app, signature, event.clone()).await;
+            assert_eq!((response.status, response.code()), (StatusCode::UNAUTHORIZED, Some("invalid_signature")));
+        }
+        assert_eq!(order(&app, &guest, &id).await["status"], "pending");
+    }
+}
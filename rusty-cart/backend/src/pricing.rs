@@ -0,0 +1,284 @@
+use shared::models::{Address, AppliedCoupon, CartItem, CartLine, CartSummary, SelectedShipping};
+use shared::money::{Money, DEFAULT_CURRENCY};
+use crate::coupons::Coupon;
+use crate::shipping::ShippingTable;
+use crate::tax::TaxTable;
+
+// The coupon code stored on a cart, resolved against the coupon book by the caller
+pub enum CartCoupon<'a> {
+    Known { coupon: &'a Coupon, times_used: u32 },
+    Unknown(String),
+}
+
+// Where the cart is going and the shipping method chosen for it, as stored on the session
+pub struct Delivery<'a> {
+    pub address: Option<&'a Address>,
+    pub method: Option<&'a str>,
+}
+
+// Prices the chosen shipping method; problems with the choice are reported on the result
+// rather than as errors, like coupons that don't qualify
+fn price_shipping(
+    id: &str,
+    cart: &[CartItem],
+    goods_total: &Money,
+    country: Option<&str>,
+    shipping: &ShippingTable,
+    tax: &TaxTable,
+    region: &str,
+) -> Result<SelectedShipping, String> {
+    // Shipping is taxed at the region's standard rate
+    let tax_rate = tax.standard_rate(region)?;
+    let unavailable = |method: &str, name: &str, reason: String| {
+        let zero = Money::zero(goods_total.currency());
+        SelectedShipping {
+            method: method.to_string(),
+            name: name.to_string(),
+            available: false,
+            reason: Some(reason),
+            cost: zero.clone(),
+            tax_rate,
+            net: zero.clone(),
+            tax: zero.clone(),
+            gross: zero,
+        }
+    };
+
+    let Some(method) = shipping.get(id) else {
+        return Ok(unavailable(id, id, format!("Shipping method {} does not exist", id)));
+    };
+    let quote = match country {
+        None => Err(format!("Set a delivery address to use {}", method.name)),
+        Some(_) if cart.is_empty() => Err("The cart is empty".to_string()),
+        Some(country) => shipping.quote(method, cart, goods_total, country),
+    };
+    Ok(match quote {
+        Ok(cost) => {
+            let split = tax.split(&cost, tax_rate);
+            SelectedShipping {
+                method: method.id.clone(),
+                name: method.name.clone(),
+                available: true,
+                reason: None,
+                cost,
+                tax_rate,
+                net: split.net,
+                tax: split.tax,
+                gross: split.gross,
+            }
+        }
+        Err(reason) => unavailable(&method.id, &method.name, reason),
+    })
+}
+
+// Prices every line of the cart, applies the coupon if it currently qualifies, adds shipping
+// and splits everything into net, tax and gross for the destination's tax region
+pub fn price_cart(
+    cart: &[CartItem],
+    coupon: Option<CartCoupon>,
+    delivery: Delivery,
+    shipping: &ShippingTable,
+    tax: &TaxTable,
+    now: u64,
+) -> Result<CartSummary, String> {
+    let currency = cart
+        .first()
+        .map(|item| item.product.price.currency().to_string())
+        .unwrap_or_else(|| DEFAULT_CURRENCY.to_string());
+    let mixed_currencies = || "Cart contains products in different currencies".to_string();
+
+    let subtotal = Money::sum(cart.iter().map(CartItem::line_total), &currency).ok_or_else(mixed_currencies)?;
+    let no_discounts = || cart.iter().map(|_| Money::zero(&currency)).collect::<Vec<_>>();
+
+    let (discounts, applied) = match coupon {
+        None => (no_discounts(), None),
+        Some(CartCoupon::Unknown(code)) => (no_discounts(), Some(AppliedCoupon {
+            reason: Some(format!("Coupon {} does not exist", code)),
+            code,
+            applied: false,
+        })),
+        Some(CartCoupon::Known { coupon, times_used }) => match coupon.check(cart, &subtotal, times_used, now) {
+            Ok(()) => (coupon.discounts(cart), Some(AppliedCoupon {
+                code: coupon.code.clone(),
+                applied: true,
+                reason: None,
+            })),
+            Err(reason) => (no_discounts(), Some(AppliedCoupon {
+                code: coupon.code.clone(),
+                applied: false,
+                reason: Some(reason),
+            })),
+        },
+    };
+
+    let region = delivery
+        .address
+        .map(|address| address.country.to_uppercase())
+        .unwrap_or_else(|| tax.default_region.to_uppercase());
+
+    let mut items = Vec::with_capacity(cart.len());
+    for (item, discount) in cart.iter().zip(discounts) {
+        let line_subtotal = item.line_total();
+        let total = line_subtotal.checked_sub(&discount).ok_or_else(mixed_currencies)?;
+        let tax_rate = tax.rate_for(&region, &item.product.category)?;
+        let split = tax.split(&total, tax_rate);
+        items.push(CartLine {
+            item: item.clone(),
+            subtotal: line_subtotal,
+            discount,
+            total,
+            tax_rate,
+            net: split.net,
+            tax: split.tax,
+            gross: split.gross,
+        });
+    }
+
+    // Totals are sums of the already rounded lines, so they always match the lines
+    let sum = |part: fn(&CartLine) -> &Money| {
+        Money::sum(items.iter().map(|line| part(line).clone()), &currency).ok_or_else(mixed_currencies)
+    };
+    let discount = sum(|line| &line.discount)?;
+    let goods_total = subtotal.checked_sub(&discount).ok_or_else(mixed_currencies)?;
+
+    let country = delivery.address.map(|address| address.country.as_str());
+    let shipping_options = match country {
+        Some(country) if !cart.is_empty() => shipping.options(cart, &goods_total, country),
+        _ => Vec::new(),
+    };
+    let selected = delivery
+        .method
+        .map(|id| price_shipping(id, cart, &goods_total, country, shipping, tax, &region))
+        .transpose()?;
+
+    // Unavailable shipping is all zeros, so it can be added either way
+    let with_shipping = |amount: Money, part: fn(&SelectedShipping) -> &Money| match &selected {
+        Some(shipping) => amount.checked_add(part(shipping)).ok_or_else(mixed_currencies),
+        None => Ok(amount),
+    };
+    let net = with_shipping(sum(|line| &line.net)?, |s| &s.net)?;
+    let tax_total = with_shipping(sum(|line| &line.tax)?, |s| &s.tax)?;
+    let gross = with_shipping(sum(|line| &line.gross)?, |s| &s.gross)?;
+
+    Ok(CartSummary {
+        coupon: applied,
+        address: delivery.address.cloned(),
+        shipping_options,
+        shipping: selected,
+        region,
+        tax_mode: tax.mode,
+        subtotal,
+        discount,
+        net,
+        tax: tax_total,
+        total: gross.clone(),
+        gross,
+        items,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::models::{Product, TaxMode};
+    use crate::coupons::DiscountRule;
+    use super::*;
+
+    fn item(price: i64, quantity: u32, category: &str) -> CartItem {
+        CartItem {
+            product: Product {
+                id: price as u32,
+                title: category.to_string(),
+                price: Money::new(price, "SEK"),
+                description: String::new(),
+                category: category.to_string(),
+                image: String::new(),
+                weight: None,
+            },
+            quantity,
+        }
+    }
+
+    fn address(country: &str) -> Address {
+        Address {
+            name: "Ferris".to_string(),
+            street: "Crab Lane 1".to_string(),
+            postal_code: "111 22".to_string(),
+            city: "Oslo".to_string(),
+            country: country.to_string(),
+        }
+    }
+
+    fn percent_off(percent: u32) -> Coupon {
+        Coupon {
+            code: "SALE".to_string(),
+            rule: DiscountRule::Percentage { percent },
+            category: None,
+            min_spend: None,
+            valid_from: None,
+            valid_until: None,
+            max_uses: None,
+        }
+    }
+
+    fn price(cart: &[CartItem], coupon: Option<&Coupon>, delivery: Delivery, tax: &TaxTable) -> CartSummary {
+        let coupon = coupon.map(|coupon| CartCoupon::Known { coupon, times_used: 0 });
+        price_cart(cart, coupon, delivery, &ShippingTable::standard(), tax, 0).unwrap()
+    }
+
+    fn minor(lines: &[CartLine], part: fn(&CartLine) -> &Money) -> Vec<i64> {
+        lines.iter().map(|line| part(line).minor()).collect()
+    }
+
+    #[test]
+    fn tax_is_worked_out_per_line_after_discounts() {
+        let cart = [item(999, 1, "toys"), item(333, 3, "books")];
+        let summary = price(&cart, Some(&percent_off(10)), Delivery { address: None, method: None }, &TaxTable::swedish());
+
+        assert_eq!(minor(&summary.items, |line| &line.discount), [100, 100]);
+        assert_eq!(minor(&summary.items, |line| &line.total), [899, 899]);
+        assert_eq!(summary.items.iter().map(|line| line.tax_rate).collect::<Vec<_>>(), [25, 6]);
+        // 179.8 and 50.89 are rounded on each line before they are added up
+        assert_eq!(minor(&summary.items, |line| &line.tax), [180, 51]);
+        assert_eq!((summary.subtotal.minor(), summary.discount.minor()), (1998, 200));
+        assert_eq!((summary.net.minor(), summary.tax.minor(), summary.gross.minor()), (1567, 231, 1798));
+        assert_eq!(summary.total, summary.gross);
+    }
+
+    #[test]
+    fn exclusive_prices_add_tax_on_top_of_the_discounted_lines() {
+        let tax = TaxTable { mode: TaxMode::Exclusive, ..TaxTable::swedish() };
+        let cart = [item(999, 1, "toys"), item(333, 3, "books")];
+        let summary = price(&cart, Some(&percent_off(10)), Delivery { address: None, method: None }, &tax);
+
+        assert_eq!(summary.tax_mode, TaxMode::Exclusive);
+        assert_eq!(minor(&summary.items, |line| &line.tax), [225, 54]);
+        assert_eq!((summary.net.minor(), summary.tax.minor(), summary.gross.minor()), (1798, 279, 2077));
+    }
+
+    #[test]
+    fn carts_without_an_address_are_taxed_in_the_default_region() {
+        let mut tax = TaxTable::swedish();
+        tax.regions.insert("NO".to_string(), crate::tax::RegionRates { default_rate: 15, categories: Default::default() });
+        let cart = [item(1150, 1, "books")];
+
+        let summary = price(&cart, None, Delivery { address: None, method: None }, &tax);
+        assert_eq!((summary.region.as_str(), summary.tax.minor()), ("SE", 65));
+
+        let address = address("no");
+        let summary = price(&cart, None, Delivery { address: Some(&address), method: None }, &tax);
+        assert_eq!((summary.region.as_str(), summary.tax.minor()), ("NO", 150));
+    }
+
+    #[test]
+    fn shipping_is_taxed_at_the_standard_rate_and_added_to_the_totals() {
+        let address = address("SE");
+        let cart = [item(1060, 1, "books")];
+        let delivery = Delivery { address: Some(&address), method: Some("standard") };
+        let summary = price(&cart, None, delivery, &TaxTable::swedish());
+
+        let shipping = summary.shipping.unwrap();
+        assert!(shipping.available);
+        assert_eq!((shipping.tax_rate, shipping.net.minor(), shipping.tax.minor()), (25, 3920, 980));
+        assert_eq!((summary.net.minor(), summary.tax.minor(), summary.gross.minor()), (1000 + 3920, 60 + 980, 1060 + 4900));
+    }
+}
@@ -0,0 +1,283 @@
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, Method};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use governor::clock::{Clock, DefaultClock};
+use governor::{DefaultKeyedRateLimiter, Quota};
+use shared::api::{PAYMENT_WEBHOOK_ENDPOINT, SESSION_HEADER};
+use crate::auth::AuthUser;
+use crate::config::{RateBudget, RateLimitConfig};
+use crate::error::ApiError;
+use crate::state::AppState;
+
+// Token buckets for one kind of request: one per IP address and one per session or account
+struct Buckets {
+    by_ip: DefaultKeyedRateLimiter<IpAddr>,
+    by_client: DefaultKeyedRateLimiter<String>,
+}
+
+impl Buckets {
+    fn new(budget: RateBudget, ip_multiplier: u32) -> Self {
+        let ip_budget = RateBudget {
+            per_minute: budget.per_minute.saturating_mul(ip_multiplier),
+            burst: budget.burst.saturating_mul(ip_multiplier),
+        };
+        Buckets {
+            by_ip: DefaultKeyedRateLimiter::keyed(quota(ip_budget)),
+            by_client: DefaultKeyedRateLimiter::keyed(quota(budget)),
+        }
+    }
+
+    // Takes a token from the IP address's bucket, or says how long to wait
+    fn check_ip(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = DefaultClock::default().now();
+        self.by_ip.check_key(&ip).map_err(|denied| denied.wait_time_from(now))
+    }
+
+    // Takes a token from the session's or account's bucket, or says how long to wait
+    fn check_client(&self, client: &String) -> Result<(), Duration> {
+        let now = DefaultClock::default().now();
+        self.by_client.check_key(client).map_err(|denied| denied.wait_time_from(now))
+    }
+
+    fn retain_recent(&self) {
+        self.by_ip.retain_recent();
+        self.by_client.retain_recent();
+    }
+}
+
+fn quota(budget: RateBudget) -> Quota {
+    // The configuration is validated to be at least 1 everywhere
+    let per_minute = NonZeroU32::new(budget.per_minute).unwrap_or(NonZeroU32::MIN);
+    let burst = NonZeroU32::new(budget.burst).unwrap_or(NonZeroU32::MIN);
+    Quota::per_minute(per_minute).allow_burst(burst)
+}
+
+// Request budgets kept in memory, which is enough for a single node. Reads and writes are
+// budgeted separately so browsing can't use up a client's budget for changing its cart.
+#[derive(Clone)]
+pub struct RateLimiter {
+    // None when rate limiting is disabled
+    buckets: Option<Arc<(Buckets, Buckets)>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let buckets = config.enabled.then(|| {
+            Arc::new((
+                Buckets::new(config.read, config.ip_multiplier),
+                Buckets::new(config.write, config.ip_multiplier),
+            ))
+        });
+        RateLimiter { buckets }
+    }
+
+    // The buckets for reads or for writes; None when rate limiting is disabled
+    fn buckets(&self, write: bool) -> Option<&Buckets> {
+        self.buckets.as_deref().map(|buckets| if write { &buckets.1 } else { &buckets.0 })
+    }
+
+    // Forgets the buckets that have filled up again, so idle clients don't take up memory
+    pub fn retain_recent(&self) {
+        if let Some(buckets) = &self.buckets {
+            buckets.0.retain_recent();
+            buckets.1.retain_recent();
+        }
+    }
+}
+
+// The session id the request sends, if it belongs to a live session. Made-up ids would each get
+// a fresh budget, so requests sending one only count against their IP address.
+async fn known_session(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    let id = headers.get(SESSION_HEADER)?.to_str().ok()?.to_string();
+    let known = state.blocking(move |state| {
+        state.sessions
+            .exists(&id)
+            .map(|exists| exists.then_some(id))
+            .map_err(ApiError::internal("Failed to look up session"))
+    }).await;
+    known.unwrap_or_else(|e| {
+        eprintln!("Rate limiting by IP only: {:?}", e);
+        None
+    })
+}
+
+// The read or write buckets the request counts against, unless it isn't limited at all
+fn buckets_for<'a>(state: &'a AppState, req: &Request) -> Option<&'a Buckets> {
+    // Payment provider callbacks must not be dropped
+    if req.uri().path() == PAYMENT_WEBHOOK_ENDPOINT {
+        return None;
+    }
+    let write = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    state.rate_limiter.buckets(write)
+}
+
+fn rate_limited(wait: Duration) -> Response {
+    // Whole seconds, rounded up so retrying right then succeeds
+    let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    ApiError::RateLimited { retry_after: retry_after.max(1) }.into_response()
+}
+
+// Counts the request against the budget of the caller's IP address, answering 429 with
+// Retry-After once it is used up. Runs before `auth_layer`, so requests with bad tokens count too.
+pub async fn ip_rate_limit_layer(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let ip = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip());
+    if let (Some(buckets), Some(ip)) = (buckets_for(&state, &req), ip) {
+        if let Err(wait) = buckets.check_ip(ip) {
+            return rate_limited(wait);
+        }
+    }
+    next.run(req).await
+}
+
+// Counts the request against the budget of the caller's account or session, answering 429 with
+// Retry-After once it is used up. Runs after `auth_layer` so signed-in callers are budgeted per
+// account rather than per session.
+pub async fn rate_limit_layer(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(buckets) = buckets_for(&state, &req) else {
+        return next.run(req).await;
+    };
+    let client = match req.extensions().get::<AuthUser>() {
+        Some(user) => Some(format!("user:{}", user.id)),
+        None => known_session(&state, req.headers()).await.map(|id| format!("session:{}", id)),
+    };
+    if let Some(Err(wait)) = client.map(|client| buckets.check_client(&client)) {
+        return rate_limited(wait);
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use shared::api::{CART_ENDPOINT, PRODUCTS_ENDPOINT};
+    use shared::models::Role;
+    use crate::test_support::{TestApp, TestOptions, TestRequest};
+    use super::*;
+
+    // Two writes and four reads at once per client, three times that per IP, refilled slowly
+    fn app() -> TestApp {
+        TestApp::with(TestOptions {
+            rate_limit: RateLimitConfig {
+                enabled: true,
+                read: RateBudget { per_minute: 1, burst: 4 },
+                write: RateBudget { per_minute: 1, burst: 2 },
+                ip_multiplier: 3,
+            },
+            ..TestOptions::default()
+        })
+    }
+
+    fn clear_cart() -> TestRequest {
+        TestRequest::new(Method::DELETE, CART_ENDPOINT).client_ip("192.0.2.1")
+    }
+
+    // How many of `count` requests got through before the first 429
+    async fn allowed(app: &TestApp, count: usize, request: impl Fn() -> TestRequest) -> usize {
+        for i in 0..count {
+            if request().send(app).await.status == StatusCode::TOO_MANY_REQUESTS {
+                return i;
+            }
+        }
+        count
+    }
+
+    #[tokio::test]
+    async fn exhausted_budgets_answer_429_with_retry_after() {
+        let app = app();
+        let session = app.guest().await;
+        assert_eq!(allowed(&app, 2, || clear_cart().session(&session)).await, 2);
+
+        let response = clear_cart().session(&session).send(&app).await;
+        assert_eq!((response.status, response.code()), (StatusCode::TOO_MANY_REQUESTS, Some("rate_limited")));
+        let retry_after: u64 = response.header("retry-after").unwrap().parse().unwrap();
+        assert!((1..=60).contains(&retry_after), "{}", retry_after);
+        assert_eq!(response.body["retry_after"], retry_after);
+    }
+
+    #[tokio::test]
+    async fn reads_and_writes_have_separate_budgets() {
+        let app = app();
+        let session = app.guest().await;
+        assert_eq!(allowed(&app, 3, || clear_cart().session(&session)).await, 2);
+        let read = || TestRequest::get(PRODUCTS_ENDPOINT).client_ip("192.0.2.1").session(&session);
+        assert_eq!(allowed(&app, 5, read).await, 4);
+    }
+
+    #[tokio::test]
+    async fn sessions_have_their_own_budget_within_their_ips() {
+        let app = app();
+        let (first, second) = (app.guest().await, app.guest().await);
+        assert_eq!(allowed(&app, 3, || clear_cart().session(&first)).await, 2);
+        assert_eq!(allowed(&app, 2, || clear_cart().session(&second)).await, 2);
+
+        // The IP has 6 writes and refused requests count too, so only one is left
+        let third = app.guest().await;
+        assert_eq!(allowed(&app, 2, || clear_cart().session(&third)).await, 1);
+        // Elsewhere only the session's own budget applies
+        assert_eq!(allowed(&app, 2, || clear_cart().client_ip("192.0.2.2").session(&third)).await, 1);
+        let fourth = app.guest().await;
+        assert_eq!(allowed(&app, 1, || clear_cart().client_ip("192.0.2.2").session(&fourth)).await, 1);
+    }
+
+    #[tokio::test]
+    async fn made_up_session_ids_only_count_against_the_ip() {
+        let app = app();
+        // A session budget would stop this after 2; the IP's lasts for 6
+        assert_eq!(allowed(&app, 7, || clear_cart().session("made-up")).await, 6);
+
+        // Neither does a new id every time buy a fresh budget
+        let ids = std::sync::Mutex::new(0);
+        let fresh_id = || {
+            let mut ids = ids.lock().unwrap();
+            *ids += 1;
+            clear_cart().client_ip("192.0.2.3").session(&format!("made-up-{}", ids))
+        };
+        assert_eq!(allowed(&app, 7, fresh_id).await, 6);
+    }
+
+    #[tokio::test]
+    async fn requests_with_bad_tokens_count_against_the_ip() {
+        let app = app();
+        let guess = || clear_cart().bearer("guessed-token");
+        assert_eq!(guess().send(&app).await.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(allowed(&app, 10, guess).await, 5);
+        // The IP's budget is gone for everything else too
+        let session = app.guest().await;
+        assert_eq!(clear_cart().session(&session).send(&app).await.status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn accounts_share_one_budget_across_logins() {
+        let app = app();
+        let first = app.account("ferris@example.com", Role::Customer);
+        let user = app.state.storage.user_by_id(&first.user.id).unwrap().unwrap();
+        let second = app.state.tokens.login(app.state.storage.as_ref(), &user).unwrap();
+
+        let request = |token: &str| TestRequest::new(Method::DELETE, CART_ENDPOINT).bearer(token);
+        assert_eq!(request(&first.access_token).send(&app).await.status, StatusCode::OK);
+        assert_eq!(request(&second.access_token).send(&app).await.status, StatusCode::OK);
+        assert_eq!(request(&first.access_token).send(&app).await.status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn payment_webhooks_and_disabled_limits_are_not_limited() {
+        let app = app();
+        let webhook = || {
+            TestRequest::post(PAYMENT_WEBHOOK_ENDPOINT)
+                .client_ip("192.0.2.1")
+                .json(json!({ "payment_id": "p", "order_id": "o", "kind": "succeeded" }))
+        };
+        assert_eq!(allowed(&app, 10, webhook).await, 10);
+
+        let mut options = TestOptions::default();
+        options.rate_limit.enabled = false;
+        let unlimited = TestApp::with(options);
+        assert_eq!(allowed(&unlimited, 100, clear_cart).await, 100);
+    }
+}
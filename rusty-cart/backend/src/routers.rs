@@ -1,76 +1,1707 @@
-use axum::{extract::State, Json, http::StatusCode};
-use crate::models::{Product, CartItem, EditCartItemPayload, DeleteCartItemPayload};
+use axum::{extract::State, Json, http::{header, HeaderMap, HeaderValue, StatusCode}, response::{IntoResponse, Response}};
+use shared::models::{
+    CartItem, CartSummary, AddToCartPayload, UpdateCartItemPayload, EditCartItemPayload, DeleteCartItemPayload, ApplyCouponPayload,
+    Address, SelectShippingPayload, Product, Category, ProductQuery, StockLevel, UpdateStockPayload,
+    Order, OrderStatus, UpdateOrderStatusPayload, PaymentOutcome, PayOrderResponse,
+    PaymentEvent, PaymentEventKind, ConfirmPaymentPayload, RegisterPayload, LoginPayload, AuthSession, RefreshPayload, MergedCartItem,
+    Role, UserProfile, UpdateRolePayload, ProductPayload, AdminOrder, AdminOrderQuery, MAX_ITEM_QUANTITY,
+};
+use shared::api::{
+    PRODUCTS_ENDPOINT, PRODUCT_ENDPOINT, CATEGORIES_ENDPOINT, CATEGORY_PRODUCTS_ENDPOINT, CART_ENDPOINT, CART_ITEMS_ENDPOINT,
+    CART_ITEM_ENDPOINT, cart_item_path, ADD_TO_CART_ENDPOINT, EDIT_CART_ENDPOINT, DELETE_FROM_CART_ENDPOINT, CART_COUPON_ENDPOINT,
+    CART_ADDRESS_ENDPOINT, CART_SHIPPING_ENDPOINT, CHECKOUT_ENDPOINT, ORDERS_ENDPOINT, ORDER_ENDPOINT, CANCEL_ORDER_ENDPOINT,
+    PAY_ORDER_ENDPOINT, CONFIRM_PAYMENT_ENDPOINT, PAYMENT_WEBHOOK_ENDPOINT, ADMIN_CATALOG_REFRESH_PATH,
+    ADMIN_PRODUCTS_PATH, ADMIN_PRODUCT_PATH, ADMIN_STOCK_PATH, ADMIN_ORDERS_PATH, ADMIN_ORDER_PATH, ADMIN_ORDER_STATUS_PATH,
+    ADMIN_USERS_PATH, ADMIN_USER_ROLE_PATH, REGISTER_ENDPOINT, LOGIN_ENDPOINT, LOGOUT_ENDPOINT, REFRESH_ENDPOINT,
+    PAYMENT_SIGNATURE_HEADER, SESSION_HEADER, TOTAL_COUNT_HEADER,
+};
+use crate::auth::{self, normalize_email, AuthUser, User};
+use crate::cart_merge::merge_guest_cart;
+use crate::error::{ApiError, ErrorBody};
+use crate::extract::{ApiJson, ApiPath, ApiQuery};
+use crate::orders::{order_from_summary, transition};
+use crate::pricing::{price_cart, CartCoupon, Delivery};
+use crate::search::{categories, search};
+use crate::session::CartOwner;
+use crate::storage::now_secs;
 use crate::state::AppState;
-use serde_json::{json, Value};
+use serde::Serialize;
+use utoipa::ToSchema;
+use validator::{ValidationError, ValidationErrors};
 
-// Fetch products from Fake Store API
-pub async fn get_products() -> Result<Json<Vec<Product>>, (StatusCode, Json<Value>)> {
-    let url = "https://fakestoreapi.com/products";
-    let res = reqwest::get(url).await.map_err(|e| (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        Json(json!({ "error": format!("Failed to fetch products: {}", e) }))
-    ))?;
+// Sent with product lists served from the cache after the upstream catalog failed
+const STALE_WARNING: &str = "110 - \"Response is Stale\"";
+// Set on responses from routes that will be removed (RFC 9745)
+const DEPRECATION_HEADER: &str = "deprecation";
 
-    let products: Vec<Product> = res.json().await.map_err(|e| (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        Json(json!({ "error": format!("Failed to parse products: {}", e) }))
-    ))?;
+// Acknowledges a request that has nothing else to return
+#[derive(Serialize, ToSchema)]
+pub struct StatusMessage {
+    pub status: &'static str,
+}
 
-    //println!("Fetched products: {:#?}", products);
-    Ok(Json(products))
+#[derive(Serialize, ToSchema)]
+pub struct CatalogRefreshed {
+    pub status: &'static str,
+    // Number of products now in the catalog
+    pub products: usize,
 }
 
-pub async fn get_cart(State(state): State<AppState>) -> Result<Json<Vec<CartItem>>, (StatusCode, Json<serde_json::Value>)> {
-    match state.cart.lock() {
-        Ok(cart) => Ok(Json(cart.clone())),
-        Err(e) => Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "error": format!("Failed to access cart: {}", e) }))
-        )),
+// Marks responses built from a stale catalog
+fn with_stale_warning(mut res: Response, stale: bool) -> Response {
+    if stale {
+        res.headers_mut().insert(header::WARNING, HeaderValue::from_static(STALE_WARNING));
     }
+    res
 }
 
-pub async fn add_to_cart(
+// One page of the products matching the query, with the number of matches across all pages
+// in the x-total-count header
+fn product_page(products: Vec<Product>, query: &ProductQuery, stale: bool) -> Response {
+    let page = search(products, query);
+    let mut res = Json(page.products).into_response();
+    res.headers_mut().insert(TOTAL_COUNT_HEADER, HeaderValue::from(page.total));
+    with_stale_warning(res, stale)
+}
+
+// Fetch products from the cached catalog, filtered, sorted and paged by the query
+#[utoipa::path(
+    get,
+    path = PRODUCTS_ENDPOINT,
+    tag = "catalog",
+    params(ProductQuery),
+    responses(
+        (status = 200, description = "One page of matching products", body = Vec<Product>, headers(("x-total-count" = usize, description = "Matching products across all pages"))),
+        (status = 422, description = "Invalid query parameters", body = ErrorBody),
+        (status = 502, description = "The catalog is unavailable", body = ErrorBody),
+    )
+)]
+pub async fn get_products(
+    State(state): State<AppState>,
+    ApiQuery(query): ApiQuery<ProductQuery>,
+) -> Result<Response, ApiError> {
+    let snapshot = state.catalog.products().await.map_err(ApiError::Upstream)?;
+
+    //println!("Fetched products: {:#?}", snapshot.products);
+    Ok(product_page(snapshot.products, &query, snapshot.stale))
+}
+
+#[utoipa::path(
+    get,
+    path = PRODUCT_ENDPOINT,
+    tag = "catalog",
+    params(("id" = u32, Path, description = "Product id")),
+    responses(
+        (status = 200, body = Product),
+        (status = 404, description = "No such product", body = ErrorBody),
+        (status = 502, description = "The catalog is unavailable", body = ErrorBody),
+    )
+)]
+pub async fn get_product(
     State(state): State<AppState>,
-    Json(product): Json<Product>,
-) -> Json<serde_json::Value> {
-    let mut cart = state.cart.lock().unwrap();
+    ApiPath(id): ApiPath<u32>,
+) -> Result<Json<Product>, ApiError> {
+    find_product(&state, id).await.map(Json)
+}
+
+// Distinct product categories with how many products each has
+#[utoipa::path(
+    get,
+    path = CATEGORIES_ENDPOINT,
+    tag = "catalog",
+    responses(
+        (status = 200, description = "Categories by name with their product counts", body = Vec<Category>),
+        (status = 502, description = "The catalog is unavailable", body = ErrorBody),
+    )
+)]
+pub async fn get_categories(State(state): State<AppState>) -> Result<Response, ApiError> {
+    let snapshot = state.catalog.products().await.map_err(ApiError::Upstream)?;
+
+    Ok(with_stale_warning(Json(categories(&snapshot.products)).into_response(), snapshot.stale))
+}
 
-    if let Some(item) = cart.iter_mut().find(|i| i.product.id == product.id) {
-        item.quantity += 1;
-    } else {
-        cart.push(CartItem { product, quantity: 1 });
+// Products in one category; takes the same query parameters as `GET /products`
+#[utoipa::path(
+    get,
+    path = CATEGORY_PRODUCTS_ENDPOINT,
+    tag = "catalog",
+    params(("name" = String, Path, description = "Category name, case-insensitive"), ProductQuery),
+    responses(
+        (status = 200, description = "One page of matching products in the category", body = Vec<Product>, headers(("x-total-count" = usize, description = "Matching products across all pages"))),
+        (status = 404, description = "No product has this category", body = ErrorBody),
+        (status = 422, description = "Invalid query parameters", body = ErrorBody),
+        (status = 502, description = "The catalog is unavailable", body = ErrorBody),
+    )
+)]
+pub async fn get_category_products(
+    State(state): State<AppState>,
+    ApiPath(name): ApiPath<String>,
+    ApiQuery(mut query): ApiQuery<ProductQuery>,
+) -> Result<Response, ApiError> {
+    let snapshot = state.catalog.products().await.map_err(ApiError::Upstream)?;
+
+    if !snapshot.products.iter().any(|p| p.category.eq_ignore_ascii_case(&name)) {
+        return Err(ApiError::CategoryNotFound(name));
     }
+    query.category = Some(name);
+    Ok(product_page(snapshot.products, &query, snapshot.stale))
+}
 
-    Json(json!({ "status": "added to cart" }))
+// Force a reload of the catalog from its source
+#[utoipa::path(
+    post,
+    path = ADMIN_CATALOG_REFRESH_PATH,
+    tag = "admin",
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = CatalogRefreshed),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Staff only", body = ErrorBody),
+        (status = 502, description = "The catalog source failed", body = ErrorBody),
+    )
+)]
+pub async fn refresh_catalog(State(state): State<AppState>) -> Result<Json<CatalogRefreshed>, ApiError> {
+    let count = state.catalog.refresh().await.map_err(ApiError::Upstream)?;
+    Ok(Json(CatalogRefreshed { status: "catalog refreshed", products: count }))
 }
 
-// Edit quantity of a product in the cart
-pub async fn edit_cart_item(
+// The session's coupon, resolved against the coupon book
+fn session_coupon<'a>(state: &'a AppState, session_id: &str) -> Result<Option<CartCoupon<'a>>, String> {
+    let Some(code) = state.sessions.coupon(session_id)? else {
+        return Ok(None);
+    };
+    Ok(Some(match state.coupons.get(&code) {
+        Some(coupon) => CartCoupon::Known {
+            coupon,
+            times_used: state.storage.coupon_uses(&coupon.code)?,
+        },
+        None => CartCoupon::Unknown(code),
+    }))
+}
+
+// Price the cart with the given coupon, the session's delivery address and either the given
+// shipping method or the one stored on the session
+fn price_with(
+    state: &AppState,
+    session_id: &str,
+    cart: &[CartItem],
+    coupon: Option<CartCoupon>,
+    method: Option<&str>,
+) -> Result<CartSummary, ApiError> {
+    let internal = ApiError::internal("Failed to price cart");
+
+    let address = state.sessions.address(session_id).map_err(&internal)?;
+    let stored_method = match method {
+        Some(_) => None,
+        None => state.sessions.shipping_method(session_id).map_err(&internal)?,
+    };
+    let delivery = Delivery {
+        address: address.as_ref(),
+        method: method.or(stored_method.as_deref()),
+    };
+
+    price_cart(cart, coupon, delivery, &state.shipping, &state.tax, now_secs()).map_err(ApiError::PricingFailed)
+}
+
+// Price the cart with everything stored on the session
+fn price_session_cart(state: &AppState, session_id: &str, cart: &[CartItem]) -> Result<CartSummary, ApiError> {
+    let coupon = session_coupon(state, session_id).map_err(ApiError::internal("Failed to price cart"))?;
+    price_with(state, session_id, cart, coupon, None)
+}
+
+fn load_cart(state: &AppState, session_id: &str) -> Result<Vec<CartItem>, ApiError> {
+    state.sessions
+        .with_cart(session_id, |cart| cart.clone())
+        .map_err(ApiError::internal("Failed to access cart"))
+}
+
+// The caller's cart with subtotal, coupon discounts, shipping, tax and total
+#[utoipa::path(
+    get,
+    path = CART_ENDPOINT,
+    tag = "cart",
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, body = CartSummary),
+    )
+)]
+pub async fn get_cart(
+    State(state): State<AppState>,
+    owner: CartOwner,
+) -> Result<Json<CartSummary>, ApiError> {
+    state.blocking(move |state| {
+        let cart = load_cart(state, &owner.0)?;
+        price_session_cart(state, &owner.0, &cart).map(Json)
+    }).await
+}
+
+// Attach a coupon to the cart if it currently qualifies
+#[utoipa::path(
+    post,
+    path = CART_COUPON_ENDPOINT,
+    tag = "cart",
+    request_body = ApplyCouponPayload,
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The cart with the coupon applied", body = CartSummary),
+        (status = 404, description = "No such coupon", body = ErrorBody),
+        (status = 422, description = "The coupon does not apply to this cart", body = ErrorBody),
+    )
+)]
+pub async fn apply_coupon(
+    State(state): State<AppState>,
+    owner: CartOwner,
+    ApiJson(payload): ApiJson<ApplyCouponPayload>,
+) -> Result<Json<CartSummary>, ApiError> {
+    state.blocking(move |state| {
+        let code = payload.code.trim();
+        let coupon = state.coupons.get(code).ok_or_else(|| ApiError::CouponNotFound(code.to_string()))?;
+        let times_used = state.storage
+            .coupon_uses(&coupon.code)
+            .map_err(ApiError::internal("Failed to load coupon usage"))?;
+
+        let cart = load_cart(state, &owner.0)?;
+        let summary = price_with(state, &owner.0, &cart, Some(CartCoupon::Known { coupon, times_used }), None)?;
+        if let Some(reason) = summary.coupon.as_ref().and_then(|c| c.reason.clone()) {
+            return Err(ApiError::CouponNotApplicable(reason));
+        }
+
+        state.sessions
+            .set_coupon(&owner.0, Some(&coupon.code))
+            .map_err(ApiError::internal("Failed to save coupon"))?;
+        Ok(Json(summary))
+    }).await
+}
+
+// Detach the coupon from the cart
+#[utoipa::path(
+    delete,
+    path = CART_COUPON_ENDPOINT,
+    tag = "cart",
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, body = CartSummary),
+    )
+)]
+pub async fn remove_coupon(
+    State(state): State<AppState>,
+    owner: CartOwner,
+) -> Result<Json<CartSummary>, ApiError> {
+    state.blocking(move |state| {
+        state.sessions
+            .set_coupon(&owner.0, None)
+            .map_err(ApiError::internal("Failed to remove coupon"))?;
+        let cart = load_cart(state, &owner.0)?;
+        price_session_cart(state, &owner.0, &cart).map(Json)
+    }).await
+}
+
+// Set where the cart should be delivered; the country also decides which tax rates apply
+#[utoipa::path(
+    post,
+    path = CART_ADDRESS_ENDPOINT,
+    tag = "cart",
+    request_body = Address,
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The cart priced for the new address", body = CartSummary),
+        (status = 422, description = "Invalid address or a country we don't deliver to", body = ErrorBody),
+    )
+)]
+pub async fn set_address(
+    State(state): State<AppState>,
+    owner: CartOwner,
+    ApiJson(mut address): ApiJson<Address>,
+) -> Result<Json<CartSummary>, ApiError> {
+    address.country = address.country.trim().to_uppercase();
+    if !state.tax.has_region(&address.country) {
+        return Err(ApiError::InvalidAddress(format!("We don't deliver to {}", address.country)));
+    }
+
+    state.blocking(move |state| {
+        state.sessions
+            .set_address(&owner.0, Some(&address))
+            .map_err(ApiError::internal("Failed to save address"))?;
+        let cart = load_cart(state, &owner.0)?;
+        price_session_cart(state, &owner.0, &cart).map(Json)
+    }).await
+}
+
+// Choose how the cart is shipped; the method must be able to deliver the cart to its address
+#[utoipa::path(
+    post,
+    path = CART_SHIPPING_ENDPOINT,
+    tag = "cart",
+    request_body = SelectShippingPayload,
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The cart with the shipping method", body = CartSummary),
+        (status = 404, description = "No such shipping method", body = ErrorBody),
+        (status = 422, description = "The method can't deliver this cart", body = ErrorBody),
+    )
+)]
+pub async fn select_shipping(
     State(state): State<AppState>,
-    Json(payload): Json<EditCartItemPayload>,
-) -> Json<serde_json::Value> {
-    let mut cart = state.cart.lock().unwrap();
-    if let Some(item) = cart.iter_mut().find(|i| i.product.id == payload.product_id) {
-        item.quantity = payload.quantity;
-        Json(json!({ "status": "quantity updated" }))
-    } else {
-        Json(json!({ "error": "Product not found in cart" }))
+    owner: CartOwner,
+    ApiJson(payload): ApiJson<SelectShippingPayload>,
+) -> Result<Json<CartSummary>, ApiError> {
+    state.blocking(move |state| {
+        let requested = payload.method.trim();
+        let method = state.shipping
+            .get(requested)
+            .ok_or_else(|| ApiError::ShippingMethodNotFound(requested.to_string()))?;
+
+        let cart = load_cart(state, &owner.0)?;
+        let coupon = session_coupon(state, &owner.0).map_err(ApiError::internal("Failed to price cart"))?;
+        let summary = price_with(state, &owner.0, &cart, coupon, Some(&method.id))?;
+        if let Some(reason) = summary.shipping.as_ref().and_then(|s| s.reason.clone()) {
+            return Err(ApiError::ShippingUnavailable(reason));
+        }
+
+        state.sessions
+            .set_shipping_method(&owner.0, Some(&method.id))
+            .map_err(ApiError::internal("Failed to save shipping method"))?;
+        Ok(Json(summary))
+    }).await
+}
+
+async fn find_product(state: &AppState, id: u32) -> Result<Product, ApiError> {
+    state.catalog
+        .product(id)
+        .await
+        .map_err(ApiError::Upstream)?
+        .ok_or(ApiError::ProductNotFound(id))
+}
+
+// Hold `quantity` of the product for the session's cart, or explain how much is left
+fn reserve_stock(state: &AppState, session_id: &str, product: &Product, quantity: u32) -> Result<(), ApiError> {
+    let internal = ApiError::internal("Failed to reserve stock");
+
+    if state.inventory.reserve(session_id, product.id, quantity).map_err(&internal)? {
+        return Ok(());
+    }
+    let available = state.inventory.available(session_id, product.id).map_err(&internal)?;
+    Err(ApiError::OutOfStock {
+        product_id: product.id,
+        title: product.title.clone(),
+        available: Some(available),
+    })
+}
+
+// For adds that would take a line with `in_cart` past MAX_ITEM_QUANTITY, the limit payloads are validated against
+fn too_many_in_cart(in_cart: u32) -> ApiError {
+    let message = format!("must be at most {} with the {} already in the cart", MAX_ITEM_QUANTITY.saturating_sub(in_cart), in_cart);
+    let mut errors = ValidationErrors::new();
+    errors.add("quantity", ValidationError::new("range").with_message(message.into()));
+    ApiError::Validation(errors)
+}
+
+// Add a product to the cart, using the catalog's product data rather than anything sent by the client
+async fn add_item(state: &AppState, session_id: String, product_id: u32, quantity: u32) -> Result<(), ApiError> {
+    let product = find_product(state, product_id).await?;
+
+    state.blocking(move |state| {
+        state.sessions.with_cart(&session_id, |cart| -> Result<(), ApiError> {
+            let in_cart = cart.iter().find(|i| i.product.id == product.id).map_or(0, |i| i.quantity);
+            let total = in_cart
+                .checked_add(quantity)
+                .filter(|total| *total <= MAX_ITEM_QUANTITY)
+                .ok_or_else(|| too_many_in_cart(in_cart))?;
+            reserve_stock(state, &session_id, &product, total)?;
+            if let Some(item) = cart.iter_mut().find(|i| i.product.id == product.id) {
+                item.quantity = total;
+            } else {
+                cart.push(CartItem { product, quantity });
+            }
+            Ok(())
+        }).map_err(ApiError::internal("Failed to access cart"))?
+    }).await
+}
+
+fn set_item_quantity(state: &AppState, session_id: &str, product_id: u32, quantity: u32) -> Result<(), ApiError> {
+    state.sessions.with_cart(session_id, |cart| -> Result<(), ApiError> {
+        let item = cart
+            .iter_mut()
+            .find(|i| i.product.id == product_id)
+            .ok_or(ApiError::CartItemNotFound(product_id))?;
+        reserve_stock(state, session_id, &item.product, quantity)?;
+        item.quantity = quantity;
+        Ok(())
+    }).map_err(ApiError::internal("Failed to access cart"))?
+}
+
+fn remove_item(state: &AppState, session_id: &str, product_id: u32) -> Result<(), ApiError> {
+    let removed = state.sessions.with_cart(session_id, |cart| {
+        let len_before = cart.len();
+        cart.retain(|item| item.product.id != product_id);
+        cart.len() < len_before
+    }).map_err(ApiError::internal("Failed to access cart"))?;
+
+    if !removed {
+        return Err(ApiError::CartItemNotFound(product_id));
     }
+    if let Err(e) = state.inventory.release(session_id, product_id) {
+        eprintln!("Failed to release stock of product {}: {}", product_id, e);
+    }
+    Ok(())
+}
+
+// Add a product to the cart and return the updated cart
+#[utoipa::path(
+    post,
+    path = CART_ITEMS_ENDPOINT,
+    tag = "cart",
+    request_body = AddToCartPayload,
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The updated cart", body = CartSummary),
+        (status = 404, description = "No such product", body = ErrorBody),
+        (status = 409, description = "Not enough in stock", body = ErrorBody),
+        (status = 422, description = "Invalid payload, or more of the product than a cart can hold", body = ErrorBody),
+    )
+)]
+pub async fn add_cart_item(
+    State(state): State<AppState>,
+    owner: CartOwner,
+    ApiJson(payload): ApiJson<AddToCartPayload>,
+) -> Result<Json<CartSummary>, ApiError> {
+    add_item(&state, owner.0.clone(), payload.product_id, payload.quantity.unwrap_or(1)).await?;
+    state.blocking(move |state| {
+        let cart = load_cart(state, &owner.0)?;
+        price_session_cart(state, &owner.0, &cart).map(Json)
+    }).await
 }
 
-// Delete a product from the cart
+// Change the quantity of a product in the cart and return the updated cart
+#[utoipa::path(
+    patch,
+    path = CART_ITEM_ENDPOINT,
+    tag = "cart",
+    params(("product_id" = u32, Path, description = "Product id")),
+    request_body = UpdateCartItemPayload,
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The updated cart", body = CartSummary),
+        (status = 404, description = "The product is not in the cart", body = ErrorBody),
+        (status = 409, description = "Not enough in stock", body = ErrorBody),
+        (status = 422, description = "Invalid payload", body = ErrorBody),
+    )
+)]
+pub async fn update_cart_item(
+    State(state): State<AppState>,
+    owner: CartOwner,
+    ApiPath(product_id): ApiPath<u32>,
+    ApiJson(payload): ApiJson<UpdateCartItemPayload>,
+) -> Result<Json<CartSummary>, ApiError> {
+    state.blocking(move |state| {
+        set_item_quantity(state, &owner.0, product_id, payload.quantity)?;
+        let cart = load_cart(state, &owner.0)?;
+        price_session_cart(state, &owner.0, &cart).map(Json)
+    }).await
+}
+
+// Remove a product from the cart and return the updated cart
+#[utoipa::path(
+    delete,
+    path = CART_ITEM_ENDPOINT,
+    tag = "cart",
+    params(("product_id" = u32, Path, description = "Product id")),
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The updated cart", body = CartSummary),
+        (status = 404, description = "The product is not in the cart", body = ErrorBody),
+    )
+)]
+pub async fn remove_cart_item(
+    State(state): State<AppState>,
+    owner: CartOwner,
+    ApiPath(product_id): ApiPath<u32>,
+) -> Result<Json<CartSummary>, ApiError> {
+    state.blocking(move |state| {
+        remove_item(state, &owner.0, product_id)?;
+        let cart = load_cart(state, &owner.0)?;
+        price_session_cart(state, &owner.0, &cart).map(Json)
+    }).await
+}
+
+// Empty the cart and give its reserved stock back; the coupon, address and shipping method stay
+#[utoipa::path(
+    delete,
+    path = CART_ENDPOINT,
+    tag = "cart",
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The emptied cart", body = CartSummary),
+    )
+)]
+pub async fn clear_cart(
+    State(state): State<AppState>,
+    owner: CartOwner,
+) -> Result<Json<CartSummary>, ApiError> {
+    state.blocking(move |state| {
+        let removed = state.sessions
+            .with_cart(&owner.0, std::mem::take)
+            .map_err(ApiError::internal("Failed to access cart"))?;
+        for item in &removed {
+            if let Err(e) = state.inventory.release(&owner.0, item.product.id) {
+                eprintln!("Failed to release stock of product {}: {}", item.product.id, e);
+            }
+        }
+        price_session_cart(state, &owner.0, &[]).map(Json)
+    }).await
+}
+
+// Marks a response from a deprecated route and points to the route replacing it
+fn deprecated(res: impl IntoResponse, successor: &str) -> Response {
+    let mut res = res.into_response();
+    res.headers_mut().insert(DEPRECATION_HEADER, HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor)) {
+        res.headers_mut().insert(header::LINK, link);
+    }
+    res
+}
+
+#[deprecated(note = "use POST /cart/items")]
+#[utoipa::path(
+    post,
+    path = ADD_TO_CART_ENDPOINT,
+    tag = "cart",
+    request_body = AddToCartPayload,
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, body = StatusMessage),
+        (status = 404, description = "No such product", body = ErrorBody),
+        (status = 409, description = "Not enough in stock", body = ErrorBody),
+        (status = 422, description = "Invalid payload, or more of the product than a cart can hold", body = ErrorBody),
+    )
+)]
+pub async fn add_to_cart(
+    State(state): State<AppState>,
+    owner: CartOwner,
+    ApiJson(payload): ApiJson<AddToCartPayload>,
+) -> Result<Response, ApiError> {
+    add_item(&state, owner.0, payload.product_id, payload.quantity.unwrap_or(1)).await?;
+    Ok(deprecated(Json(StatusMessage { status: "added to cart" }), CART_ITEMS_ENDPOINT))
+}
+
+#[deprecated(note = "use PATCH /cart/items/{product_id}")]
+#[utoipa::path(
+    post,
+    path = EDIT_CART_ENDPOINT,
+    tag = "cart",
+    request_body = EditCartItemPayload,
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, body = StatusMessage),
+        (status = 404, description = "The product is not in the cart", body = ErrorBody),
+        (status = 409, description = "Not enough in stock", body = ErrorBody),
+        (status = 422, description = "Invalid payload", body = ErrorBody),
+    )
+)]
+pub async fn edit_cart_item(
+    State(state): State<AppState>,
+    owner: CartOwner,
+    ApiJson(payload): ApiJson<EditCartItemPayload>,
+) -> Result<Response, ApiError> {
+    state.blocking(move |state| set_item_quantity(state, &owner.0, payload.product_id, payload.quantity)).await?;
+    Ok(deprecated(Json(StatusMessage { status: "quantity updated" }), &cart_item_path(payload.product_id)))
+}
+
+#[deprecated(note = "use DELETE /cart/items/{product_id}")]
+#[utoipa::path(
+    post,
+    path = DELETE_FROM_CART_ENDPOINT,
+    tag = "cart",
+    request_body = DeleteCartItemPayload,
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, body = StatusMessage),
+        (status = 404, description = "The product is not in the cart", body = ErrorBody),
+    )
+)]
 pub async fn delete_cart_item(
     State(state): State<AppState>,
-    Json(payload): Json<DeleteCartItemPayload>,
-) -> Json<serde_json::Value> {
-    let mut cart = state.cart.lock().unwrap();
-    let len_before = cart.len();
-    cart.retain(|item| item.product.id != payload.product_id);
-    if cart.len() < len_before {
-        Json(json!({ "status": "deleted from cart" }))
-    } else {
-        Json(json!({ "error": "Product not found in cart" }))
+    owner: CartOwner,
+    ApiJson(payload): ApiJson<DeleteCartItemPayload>,
+) -> Result<Response, ApiError> {
+    state.blocking(move |state| remove_item(state, &owner.0, payload.product_id)).await?;
+    Ok(deprecated(Json(StatusMessage { status: "deleted from cart" }), &cart_item_path(payload.product_id)))
+}
+
+
+// Turn the caller's cart into a pending order and empty the cart
+#[utoipa::path(
+    post,
+    path = CHECKOUT_ENDPOINT,
+    tag = "orders",
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 201, description = "The new pending order", body = Order),
+        (status = 400, description = "The cart is empty", body = ErrorBody),
+        (status = 409, description = "Not enough in stock", body = ErrorBody),
+        (status = 422, description = "The coupon or shipping method no longer applies", body = ErrorBody),
+    )
+)]
+pub async fn checkout(
+    State(state): State<AppState>,
+    owner: CartOwner,
+) -> Result<(StatusCode, Json<Order>), ApiError> {
+    let order = state.blocking(move |state| {
+        state.sessions.with_cart(&owner.0, |cart| {
+            if cart.is_empty() {
+                return Err(ApiError::CartEmpty);
+            }
+
+            let summary = price_session_cart(state, &owner.0, cart)?;
+            if let Some(reason) = summary.shipping.as_ref().and_then(|s| s.reason.clone()) {
+                return Err(ApiError::ShippingUnavailable(reason));
+            }
+            if let Some(reason) = summary.coupon.as_ref().and_then(|c| c.reason.clone()) {
+                return Err(ApiError::CouponNotApplicable(reason));
+            }
+
+            let order = order_from_summary(&summary);
+            let short = state.inventory
+                .commit(&owner.0, cart)
+                .map_err(ApiError::internal("Failed to update stock"))?;
+            if let Some(product_id) = short {
+                let title = cart.iter().find(|i| i.product.id == product_id).map_or("", |i| i.product.title.as_str());
+                return Err(ApiError::OutOfStock {
+                    product_id,
+                    title: title.to_string(),
+                    available: None,
+                });
+            }
+            // Undoes the stock change if the order can't be placed after all
+            let put_back = || {
+                if let Err(e) = state.inventory.restock(&order.lines) {
+                    eprintln!("Failed to restock order {}: {}", order.id, e);
+                }
+            };
+
+            if let Some(coupon) = &summary.coupon {
+                let max_uses = state.coupons.get(&coupon.code).and_then(|c| c.max_uses);
+                let counted = state.storage.record_coupon_use(&coupon.code, max_uses).map_err(|e| {
+                    put_back();
+                    ApiError::Internal(format!("Failed to record coupon use: {}", e))
+                })?;
+                if !counted {
+                    put_back();
+                    return Err(ApiError::CouponNotApplicable(format!("Coupon {} has reached its usage limit", coupon.code)));
+                }
+            }
+
+            state.storage.insert_order(&owner.0, &order).map_err(|e| {
+                put_back();
+                // The use was counted for this order, which doesn't exist
+                if let Some(coupon) = &summary.coupon {
+                    if let Err(e) = state.storage.release_coupon_use(&coupon.code) {
+                        eprintln!("Failed to release a use of coupon {}: {}", coupon.code, e);
+                    }
+                }
+                ApiError::Internal(format!("Failed to save order: {}", e))
+            })?;
+
+            cart.clear();
+            if summary.coupon.is_some() {
+                // The order is already saved, so a failure here only leaves the coupon on the empty cart
+                let _ = state.sessions.set_coupon(&owner.0, None);
+            }
+            Ok(order)
+        }).map_err(ApiError::internal("Failed to access cart"))?
+    }).await?;
+
+    Ok((StatusCode::CREATED, Json(order)))
+}
+
+// Orders placed by the caller's account, or by their guest session, newest first
+#[utoipa::path(
+    get,
+    path = ORDERS_ENDPOINT,
+    tag = "orders",
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Orders of the caller, newest first", body = Vec<Order>),
+    )
+)]
+pub async fn get_orders(
+    State(state): State<AppState>,
+    owner: CartOwner,
+) -> Result<Json<Vec<Order>>, ApiError> {
+    state.blocking(move |state| {
+        state.storage
+            .orders_for(&owner.0)
+            .map(Json)
+            .map_err(ApiError::internal("Failed to load orders"))
+    }).await
+}
+
+// Look up an order, optionally requiring that it belongs to `owner`
+fn find_order(state: &AppState, id: &str, owner: Option<&str>) -> Result<Order, ApiError> {
+    let found = state.storage.get_order(id).map_err(ApiError::internal("Failed to load order"))?;
+
+    match found {
+        Some((order_owner, order)) if owner.is_none_or(|o| o == order_owner) => Ok(order),
+        _ => Err(ApiError::OrderNotFound(id.to_string())),
+    }
+}
+
+// Stores the changed order unless another request changed its status since it was loaded
+fn save_order(state: &AppState, order: &Order, loaded_as: OrderStatus) -> Result<(), ApiError> {
+    let saved = state.storage.update_order(order, loaded_as).map_err(ApiError::internal("Failed to save order"))?;
+    if !saved {
+        return Err(ApiError::InvalidOrderStatus(format!("Order {} changed status in the meantime", order.id)));
+    }
+    Ok(())
+}
+
+// Apply a status change and store the updated order; cancelled orders go back in stock
+fn change_order_status(state: &AppState, mut order: Order, next: OrderStatus) -> Result<Json<Order>, ApiError> {
+    let previous = order.status;
+    transition(&mut order, next).map_err(ApiError::InvalidOrderStatus)?;
+    save_order(state, &order, previous)?;
+    if next == OrderStatus::Cancelled {
+        if let Err(e) = state.inventory.restock(&order.lines) {
+            eprintln!("Failed to restock order {}: {}", order.id, e);
+        }
     }
+    Ok(Json(order))
+}
+
+#[utoipa::path(
+    get,
+    path = ORDER_ENDPOINT,
+    tag = "orders",
+    params(("id" = String, Path, description = "Order id")),
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, body = Order),
+        (status = 404, description = "No such order for the caller", body = ErrorBody),
+    )
+)]
+pub async fn get_order(
+    State(state): State<AppState>,
+    owner: CartOwner,
+    ApiPath(id): ApiPath<String>,
+) -> Result<Json<Order>, ApiError> {
+    state.blocking(move |state| find_order(state, &id, Some(&owner.0)).map(Json)).await
+}
+
+// Let the customer cancel their own order while it hasn't been paid
+#[utoipa::path(
+    post,
+    path = CANCEL_ORDER_ENDPOINT,
+    tag = "orders",
+    params(("id" = String, Path, description = "Order id")),
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The cancelled order", body = Order),
+        (status = 404, description = "No such order for the caller", body = ErrorBody),
+        (status = 409, description = "The order is no longer pending", body = ErrorBody),
+    )
+)]
+pub async fn cancel_order(
+    State(state): State<AppState>,
+    owner: CartOwner,
+    ApiPath(id): ApiPath<String>,
+) -> Result<Json<Order>, ApiError> {
+    state.blocking(move |state| {
+        let order = find_order(state, &id, Some(&owner.0))?;
+        if !order.status.customer_can_cancel() {
+            return Err(ApiError::InvalidOrderStatus(format!("Order is {} and can no longer be cancelled", order.status.as_str())));
+        }
+        change_order_status(state, order, OrderStatus::Cancelled)
+    }).await
 }
 
+// Move any order to a new status, e.g. mark it paid or shipped
+#[utoipa::path(
+    post,
+    path = ADMIN_ORDER_STATUS_PATH,
+    tag = "admin",
+    params(("id" = String, Path, description = "Order id")),
+    request_body = UpdateOrderStatusPayload,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = Order),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Staff only", body = ErrorBody),
+        (status = 404, description = "No such order", body = ErrorBody),
+        (status = 409, description = "The order can't move to that status", body = ErrorBody),
+        (status = 422, description = "Invalid payload", body = ErrorBody),
+    )
+)]
+pub async fn update_order_status(
+    State(state): State<AppState>,
+    ApiPath(id): ApiPath<String>,
+    ApiJson(payload): ApiJson<UpdateOrderStatusPayload>,
+) -> Result<Json<Order>, ApiError> {
+    state.blocking(move |state| {
+        let order = find_order(state, &id, None)?;
+        change_order_status(state, order, payload.status)
+    }).await
+}
+
+// Stock level of a catalog product
+#[utoipa::path(
+    get,
+    path = ADMIN_STOCK_PATH,
+    tag = "admin",
+    params(("id" = u32, Path, description = "Product id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = StockLevel),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Staff only", body = ErrorBody),
+        (status = 404, description = "No such product", body = ErrorBody),
+    )
+)]
+pub async fn get_stock(
+    State(state): State<AppState>,
+    ApiPath(id): ApiPath<u32>,
+) -> Result<Json<StockLevel>, ApiError> {
+    find_product(&state, id).await?;
+    state.blocking(move |state| state.inventory.level(id).map(Json).map_err(ApiError::internal("Failed to load stock"))).await
+}
+
+// Set a product's stock or change it by a delta, e.g. after a delivery or a stock count
+#[utoipa::path(
+    post,
+    path = ADMIN_STOCK_PATH,
+    tag = "admin",
+    params(("id" = u32, Path, description = "Product id")),
+    request_body = UpdateStockPayload,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = StockLevel),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Staff only", body = ErrorBody),
+        (status = 404, description = "No such product", body = ErrorBody),
+        (status = 422, description = "Invalid payload or the stock would go below zero", body = ErrorBody),
+    )
+)]
+pub async fn update_stock(
+    State(state): State<AppState>,
+    ApiPath(id): ApiPath<u32>,
+    ApiJson(payload): ApiJson<UpdateStockPayload>,
+) -> Result<Json<StockLevel>, ApiError> {
+    find_product(&state, id).await?;
+    state.blocking(move |state| {
+        let internal = ApiError::internal("Failed to update stock");
+        match (payload.set, payload.adjust) {
+            (Some(on_hand), None) => state.inventory.set(id, on_hand).map(Json).map_err(internal),
+            (None, Some(delta)) => state.inventory
+                .adjust(id, delta)
+                .map_err(internal)?
+                .map(Json)
+                .ok_or_else(|| ApiError::InvalidStockUpdate(format!("Stock of product {} can't go below zero", id))),
+            // Ruled out by the payload's validation
+            _ => Err(ApiError::InvalidStockUpdate("Send either set or adjust".to_string())),
+        }
+    }).await
+}
+
+// Add a product to the catalog. It is served from `GET /products` like the source's products and
+// gets the next free id.
+#[utoipa::path(
+    post,
+    path = ADMIN_PRODUCTS_PATH,
+    tag = "admin",
+    request_body = ProductPayload,
+    security(("bearer" = [])),
+    responses(
+        (status = 201, description = "The new product", body = Product),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Staff only", body = ErrorBody),
+        (status = 422, description = "Invalid payload", body = ErrorBody),
+        (status = 502, description = "The catalog source failed", body = ErrorBody),
+    )
+)]
+pub async fn create_product(
+    State(state): State<AppState>,
+    ApiJson(payload): ApiJson<ProductPayload>,
+) -> Result<(StatusCode, Json<Product>), ApiError> {
+    let product = state.catalog
+        .create(|id| payload.into_product(id))
+        .await
+        .map_err(ApiError::Upstream)?;
+    Ok((StatusCode::CREATED, Json(product)))
+}
+
+// Replace a product, whether it was created here or comes from the catalog source. Edits to the
+// source's products win over later changes upstream.
+#[utoipa::path(
+    put,
+    path = ADMIN_PRODUCT_PATH,
+    tag = "admin",
+    params(("id" = u32, Path, description = "Product id")),
+    request_body = ProductPayload,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The updated product", body = Product),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Staff only", body = ErrorBody),
+        (status = 404, description = "No such product", body = ErrorBody),
+        (status = 422, description = "Invalid payload", body = ErrorBody),
+    )
+)]
+pub async fn update_product(
+    State(state): State<AppState>,
+    ApiPath(id): ApiPath<u32>,
+    ApiJson(payload): ApiJson<ProductPayload>,
+) -> Result<Json<Product>, ApiError> {
+    let product = payload.into_product(id);
+    if !state.catalog.update(product.clone()).await.map_err(ApiError::Upstream)? {
+        return Err(ApiError::ProductNotFound(id));
+    }
+    Ok(Json(product))
+}
+
+// Take a product out of the catalog. Carts and orders keep the copy they already have.
+#[utoipa::path(
+    delete,
+    path = ADMIN_PRODUCT_PATH,
+    tag = "admin",
+    params(("id" = u32, Path, description = "Product id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 204, description = "The product is gone from the catalog"),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Staff only", body = ErrorBody),
+        (status = 404, description = "No such product", body = ErrorBody),
+    )
+)]
+pub async fn delete_product(
+    State(state): State<AppState>,
+    ApiPath(id): ApiPath<u32>,
+) -> Result<StatusCode, ApiError> {
+    if !state.catalog.delete(id).await.map_err(ApiError::Upstream)? {
+        return Err(ApiError::ProductNotFound(id));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Every customer's orders, newest first, optionally only those with one status
+#[utoipa::path(
+    get,
+    path = ADMIN_ORDERS_PATH,
+    tag = "admin",
+    params(AdminOrderQuery),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = Vec<AdminOrder>),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Staff only", body = ErrorBody),
+    )
+)]
+pub async fn list_all_orders(
+    State(state): State<AppState>,
+    ApiQuery(query): ApiQuery<AdminOrderQuery>,
+) -> Result<Json<Vec<AdminOrder>>, ApiError> {
+    let orders = state.blocking(|state| state.storage.all_orders().map_err(ApiError::internal("Failed to load orders"))).await?;
+    Ok(Json(orders
+        .into_iter()
+        .filter(|(_, order)| query.status.is_none_or(|status| order.status == status))
+        .map(|(owner, order)| AdminOrder { owner, order })
+        .collect()))
+}
+
+// Any order together with who placed it
+#[utoipa::path(
+    get,
+    path = ADMIN_ORDER_PATH,
+    tag = "admin",
+    params(("id" = String, Path, description = "Order id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = AdminOrder),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Staff only", body = ErrorBody),
+        (status = 404, description = "No such order", body = ErrorBody),
+    )
+)]
+pub async fn get_any_order(
+    State(state): State<AppState>,
+    ApiPath(id): ApiPath<String>,
+) -> Result<Json<AdminOrder>, ApiError> {
+    state.blocking(move |state| {
+        state.storage
+            .get_order(&id)
+            .map_err(ApiError::internal("Failed to load order"))?
+            .map(|(owner, order)| Json(AdminOrder { owner, order }))
+            .ok_or(ApiError::OrderNotFound(id))
+    }).await
+}
+
+// Every account, oldest first
+#[utoipa::path(
+    get,
+    path = ADMIN_USERS_PATH,
+    tag = "admin",
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = Vec<UserProfile>),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Admins only", body = ErrorBody),
+    )
+)]
+pub async fn list_users(State(state): State<AppState>) -> Result<Json<Vec<UserProfile>>, ApiError> {
+    let users = state.blocking(|state| state.storage.users().map_err(ApiError::internal("Failed to load users"))).await?;
+    Ok(Json(users.iter().map(User::profile).collect()))
+}
+
+// Make an account a customer, staff member or admin. Takes effect on the account's next request.
+#[utoipa::path(
+    post,
+    path = ADMIN_USER_ROLE_PATH,
+    tag = "admin",
+    params(("id" = String, Path, description = "User id")),
+    request_body = UpdateRolePayload,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The updated account", body = UserProfile),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Admins only", body = ErrorBody),
+        (status = 404, description = "No such account", body = ErrorBody),
+        (status = 422, description = "Invalid payload", body = ErrorBody),
+    )
+)]
+pub async fn set_user_role(
+    State(state): State<AppState>,
+    ApiPath(id): ApiPath<String>,
+    ApiJson(payload): ApiJson<UpdateRolePayload>,
+) -> Result<Json<UserProfile>, ApiError> {
+    state.blocking(move |state| {
+        let updated = state.storage.set_role(&id, payload.role).map_err(ApiError::internal("Failed to update role"))?;
+        if !updated {
+            return Err(ApiError::UserNotFound(id));
+        }
+        state.storage
+            .user_by_id(&id)
+            .map_err(ApiError::internal("Failed to load user"))?
+            .map(|user| Json(user.profile()))
+            .ok_or(ApiError::UserNotFound(id))
+    }).await
+}
+
+// Charge the payment provider for one of the caller's pending orders
+#[utoipa::path(
+    post,
+    path = PAY_ORDER_ENDPOINT,
+    tag = "payments",
+    params(("id" = String, Path, description = "Order id")),
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The payment was approved and the order is paid", body = PayOrderResponse),
+        (status = 202, description = "The customer has to confirm the payment", body = PayOrderResponse),
+        (status = 402, description = "The payment was declined", body = PayOrderResponse),
+        (status = 404, description = "No such order for the caller", body = ErrorBody),
+        (status = 409, description = "The order is not pending", body = ErrorBody),
+        (status = 502, description = "The payment provider failed", body = ErrorBody),
+    )
+)]
+pub async fn pay_order(
+    State(state): State<AppState>,
+    owner: CartOwner,
+    ApiPath(id): ApiPath<String>,
+) -> Result<(StatusCode, Json<PayOrderResponse>), ApiError> {
+    let order = state.blocking({
+        let id = id.clone();
+        let owner = owner.clone();
+        move |state| find_order(state, &id, Some(&owner.0))
+    }).await?;
+    if order.status != OrderStatus::Pending {
+        return Err(ApiError::InvalidOrderStatus(format!("Order is {} and cannot be paid", order.status.as_str())));
+    }
+
+    let payment = state.payments
+        .charge(&order)
+        .await
+        .map_err(|e| ApiError::Upstream(format!("Payment provider failed: {}", e)))?;
+
+    // The order may have been cancelled and restocked while the charge was in flight, so it is
+    // reloaded and only saved if it is still pending
+    let (status, order) = state.blocking({
+        let payment = payment.clone();
+        move |state| {
+            let mut order = find_order(state, &id, Some(&owner.0))?;
+            let status = match payment {
+                PaymentOutcome::Approved { payment_id } => {
+                    order.payment_id = Some(payment_id);
+                    transition(&mut order, OrderStatus::Paid).map_err(ApiError::InvalidOrderStatus)?;
+                    StatusCode::OK
+                }
+                PaymentOutcome::RequiresConfirmation { payment_id, .. } => {
+                    order.payment_id = Some(payment_id);
+                    StatusCode::ACCEPTED
+                }
+                PaymentOutcome::Declined { .. } => return Ok((StatusCode::PAYMENT_REQUIRED, order)),
+            };
+            save_order(state, &order, OrderStatus::Pending)?;
+            Ok((status, order))
+        }
+    }).await?;
+    Ok((status, Json(PayOrderResponse { order, payment })))
+}
+
+// Stand-in for the provider's confirmation page: approve or reject a payment waiting for confirmation
+#[utoipa::path(
+    post,
+    path = CONFIRM_PAYMENT_ENDPOINT,
+    tag = "payments",
+    params(("payment_id" = String, Path, description = "Payment id")),
+    request_body = ConfirmPaymentPayload,
+    responses(
+        (status = 202, description = "The result will be sent to the webhook", body = StatusMessage),
+        (status = 404, description = "No payment awaiting confirmation", body = ErrorBody),
+    )
+)]
+pub async fn confirm_payment(
+    State(state): State<AppState>,
+    ApiPath(payment_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<ConfirmPaymentPayload>,
+) -> Result<(StatusCode, Json<StatusMessage>), ApiError> {
+    match state.payments.confirm(&payment_id, payload.approve).await {
+        Ok(()) => Ok((StatusCode::ACCEPTED, Json(StatusMessage { status: "confirmation sent" }))),
+        Err(e) => {
+            eprintln!("Failed to confirm payment {}: {}", payment_id, e);
+            Err(ApiError::PaymentNotFound(payment_id))
+        }
+    }
+}
+
+// Called by the payment provider when a payment completes
+#[utoipa::path(
+    post,
+    path = PAYMENT_WEBHOOK_ENDPOINT,
+    tag = "payments",
+    request_body = PaymentEvent,
+    security(("payment_signature" = [])),
+    responses(
+        (status = 200, description = "The updated order", body = Order),
+        (status = 400, description = "The payment does not belong to the order", body = ErrorBody),
+        (status = 401, description = "Missing or wrong signature", body = ErrorBody),
+        (status = 404, description = "No such order", body = ErrorBody),
+        (status = 409, description = "The order can't be marked paid", body = ErrorBody),
+    )
+)]
+pub async fn payment_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(event): ApiJson<PaymentEvent>,
+) -> Result<Json<Order>, ApiError> {
+    let signature = headers.get(PAYMENT_SIGNATURE_HEADER).and_then(|v| v.to_str().ok());
+    if !state.payments.verify_webhook(signature) {
+        return Err(ApiError::InvalidSignature);
+    }
+
+    state.blocking(move |state| {
+        let mut order = find_order(state, &event.order_id, None)?;
+        if order.payment_id.as_deref() != Some(event.payment_id.as_str()) {
+            return Err(ApiError::InvalidRequest(format!("Payment {} does not belong to order {}", event.payment_id, order.id)));
+        }
+
+        match event.kind {
+            // Providers may deliver the same event more than once
+            PaymentEventKind::Succeeded if order.status == OrderStatus::Paid => Ok(Json(order)),
+            PaymentEventKind::Succeeded => change_order_status(state, order, OrderStatus::Paid),
+            // A late or replayed failure must not touch an order that has moved on
+            PaymentEventKind::Failed if order.status != OrderStatus::Pending => Ok(Json(order)),
+            // The order stays pending so the customer can try again
+            PaymentEventKind::Failed => {
+                order.payment_id = None;
+                save_order(state, &order, OrderStatus::Pending)?;
+                Ok(Json(order))
+            }
+        }
+    }).await
+}
+
+// Moves the cart of the guest session named in the `x-session-id` header, if any, into the account's
+// cart. A failed merge is logged rather than failing the login.
+fn merge_session_cart(state: &AppState, headers: &HeaderMap, user: &User) -> Vec<MergedCartItem> {
+    let Some(guest_id) = headers.get(SESSION_HEADER).and_then(|v| v.to_str().ok()) else {
+        return Vec::new();
+    };
+    merge_guest_cart(state, guest_id, &user.id).unwrap_or_else(|e| {
+        eprintln!("Failed to merge guest cart into the cart of {}: {}", user.id, e);
+        Vec::new()
+    })
+}
+
+// Creates an account and logs it in, taking over the guest cart
+#[utoipa::path(
+    post,
+    path = REGISTER_ENDPOINT,
+    tag = "auth",
+    request_body = RegisterPayload,
+    security((), ("session" = [])),
+    responses(
+        (status = 201, description = "The new account with access and refresh tokens", body = AuthSession),
+        (status = 409, description = "The email is already registered", body = ErrorBody),
+        (status = 422, description = "Invalid email or password", body = ErrorBody),
+    )
+)]
+pub async fn register(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(payload): ApiJson<RegisterPayload>,
+) -> Result<(StatusCode, Json<AuthSession>), ApiError> {
+    let email = normalize_email(&payload.email);
+    let password_hash = auth::hash_password(payload.password)
+        .await
+        .map_err(ApiError::internal("Failed to hash password"))?;
+    let role = if state.admin_emails.contains(&email) { Role::Admin } else { Role::Customer };
+    let user = User::new(email, password_hash, role);
+
+    let session = state.blocking(move |state| {
+        let created = state.storage.insert_user(&user).map_err(ApiError::internal("Failed to create account"))?;
+        if !created {
+            return Err(ApiError::EmailTaken(user.email));
+        }
+        let mut session = state.tokens.login(state.storage.as_ref(), &user).map_err(ApiError::internal("Failed to log in"))?;
+        session.merged_cart = merge_session_cart(state, &headers, &user);
+        Ok(session)
+    }).await?;
+    Ok((StatusCode::CREATED, Json(session)))
+}
+
+// Logs in with email and password. The cart of the guest session, if one is sent, is merged into
+// the account's cart according to the configured policy.
+#[utoipa::path(
+    post,
+    path = LOGIN_ENDPOINT,
+    tag = "auth",
+    request_body = LoginPayload,
+    security((), ("session" = [])),
+    responses(
+        (status = 200, description = "New access and refresh tokens", body = AuthSession),
+        (status = 401, description = "Wrong email or password", body = ErrorBody),
+    )
+)]
+pub async fn login(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(payload): ApiJson<LoginPayload>,
+) -> Result<Json<AuthSession>, ApiError> {
+    let email = normalize_email(&payload.email);
+    let user = state.blocking(move |state| {
+        state.storage.user_by_email(&email).map_err(ApiError::internal("Failed to load user"))
+    }).await?;
+    let valid = auth::verify_password(payload.password, user.as_ref().map(|u| u.password_hash.clone()))
+        .await
+        .map_err(ApiError::internal("Failed to check password"))?;
+
+    let user = match user {
+        Some(user) if valid => user,
+        _ => return Err(ApiError::InvalidCredentials),
+    };
+    state.blocking(move |state| {
+        let mut session = state.tokens.login(state.storage.as_ref(), &user).map_err(ApiError::internal("Failed to log in"))?;
+        session.merged_cart = merge_session_cart(state, &headers, &user);
+        Ok(Json(session))
+    }).await
+}
+
+// Swaps a refresh token for a new access and refresh token. Using a refresh token a second time
+// ends its login session, since only a stolen copy would be used again.
+#[utoipa::path(
+    post,
+    path = REFRESH_ENDPOINT,
+    tag = "auth",
+    request_body = RefreshPayload,
+    responses(
+        (status = 200, description = "New access and refresh tokens", body = AuthSession),
+        (status = 401, description = "The refresh token is invalid, expired or already used", body = ErrorBody),
+    )
+)]
+pub async fn refresh(
+    State(state): State<AppState>,
+    ApiJson(payload): ApiJson<RefreshPayload>,
+) -> Result<Json<AuthSession>, ApiError> {
+    state.blocking(move |state| {
+        state.tokens
+            .refresh(state.storage.as_ref(), payload.refresh_token.trim())
+            .map_err(ApiError::internal("Failed to refresh token"))?
+            .map(Json)
+            .ok_or(ApiError::InvalidRefreshToken)
+    }).await
+}
+
+// Ends the login session the request was made with: the access token and its refresh tokens stop working
+#[utoipa::path(
+    post,
+    path = LOGOUT_ENDPOINT,
+    tag = "auth",
+    security(("bearer" = [])),
+    responses(
+        (status = 204, description = "The tokens no longer work"),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+    )
+)]
+pub async fn logout(State(state): State<AppState>, user: AuthUser) -> Result<StatusCode, ApiError> {
+    state.blocking(move |state| user.logout(state.storage.as_ref()).map_err(ApiError::internal("Failed to log out"))).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use async_trait::async_trait;
+    use axum::http::Method;
+    use serde_json::{json, Value};
+    use tokio::sync::Notify;
+    use shared::api::ADMIN_PREFIX;
+    use crate::payments::PaymentProvider;
+    use crate::test_support::{TestApp, TestOptions, TestRequest, TestResponse};
+    use super::*;
+
+    fn stock_path(product_id: u32) -> String {
+        admin(ADMIN_STOCK_PATH, &product_id.to_string())
+    }
+
+    async fn add(app: &TestApp, session: &str, product_id: u32, quantity: u32) -> TestResponse {
+        TestRequest::post(CART_ITEMS_ENDPOINT)
+            .session(session)
+            .json(json!({ "product_id": product_id, "quantity": quantity }))
+            .send(app)
+            .await
+    }
+
+    async fn stock(app: &TestApp, staff_token: &str, product_id: u32) -> Value {
+        let response = TestRequest::get(&stock_path(product_id)).bearer(staff_token).send(app).await;
+        assert_eq!(response.status, StatusCode::OK);
+        response.body
+    }
+
+    #[tokio::test]
+    async fn carts_cannot_hold_more_than_is_in_stock() {
+        let app = TestApp::new();
+        let guest = app.guest().await;
+
+        let response = add(&app, &guest, 1, 11).await;
+        assert_eq!((response.status, response.code()), (StatusCode::CONFLICT, Some("out_of_stock")));
+        assert_eq!((response.body["product_id"].as_u64(), response.body["available"].as_u64()), (Some(1), Some(10)));
+
+        assert_eq!(add(&app, &guest, 1, 4).await.status, StatusCode::OK);
+        assert_eq!(add(&app, &guest, 1, 7).await.body["available"], 10);
+        let response = TestRequest::new(Method::PATCH, &cart_item_path(1))
+            .session(&guest)
+            .json(json!({ "quantity": 10 }))
+            .send(&app)
+            .await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body["items"][0]["quantity"], 10);
+    }
+
+    #[tokio::test]
+    async fn adding_never_takes_a_line_past_the_quantity_limit() {
+        let app = TestApp::with(TestOptions { default_stock: 5000, ..TestOptions::default() });
+        let guest = app.guest().await;
+        assert_eq!(add(&app, &guest, 1, 998).await.status, StatusCode::OK);
+        assert_eq!(add(&app, &guest, 1, 1).await.body["items"][0]["quantity"], 999);
+
+        let response = add(&app, &guest, 1, 1).await;
+        assert_eq!((response.status, response.code()), (StatusCode::UNPROCESSABLE_ENTITY, Some("validation_failed")));
+        assert_eq!(response.body["fields"]["quantity"], json!(["must be at most 0 with the 999 already in the cart"]));
+        let cart = TestRequest::get(CART_ENDPOINT).session(&guest).send(&app).await;
+        assert_eq!(cart.body["items"][0]["quantity"], 999);
+    }
+
+    // Product ids and quantities of the cart in a response
+    fn lines(cart: &Value) -> Vec<(u64, u64)> {
+        let items = cart["items"].as_array().unwrap();
+        items.iter().map(|line| (line["product"]["id"].as_u64().unwrap(), line["quantity"].as_u64().unwrap())).collect()
+    }
+
+    #[tokio::test]
+    async fn cart_items_are_changed_and_removed_by_product_id() {
+        let app = TestApp::new();
+        let guest = app.guest().await;
+        add(&app, &guest, 1, 2).await;
+        assert_eq!(lines(&add(&app, &guest, 2, 1).await.body), [(1, 2), (2, 1)]);
+
+        let patch = |product_id: u32, quantity: u32| {
+            TestRequest::new(Method::PATCH, &cart_item_path(product_id)).session(&guest).json(json!({ "quantity": quantity }))
+        };
+        let response = patch(1, 5).send(&app).await;
+        assert_eq!((response.status, lines(&response.body)), (StatusCode::OK, vec![(1, 5), (2, 1)]));
+        assert_eq!(patch(3, 1).send(&app).await.code(), Some("cart_item_not_found"));
+        assert_eq!(patch(1, 0).send(&app).await.code(), Some("validation_failed"));
+
+        let remove = |product_id: u32| TestRequest::new(Method::DELETE, &cart_item_path(product_id)).session(&guest);
+        let response = remove(2).send(&app).await;
+        assert_eq!((response.status, lines(&response.body)), (StatusCode::OK, vec![(1, 5)]));
+        let response = remove(2).send(&app).await;
+        assert_eq!((response.status, response.code()), (StatusCode::NOT_FOUND, Some("cart_item_not_found")));
+    }
+
+    #[tokio::test]
+    async fn clearing_the_cart_gives_its_stock_back() {
+        let app = TestApp::new();
+        let (first, second) = (app.guest().await, app.guest().await);
+        add(&app, &first, 1, 10).await;
+        add(&app, &first, 2, 3).await;
+
+        let response = TestRequest::new(Method::DELETE, CART_ENDPOINT).session(&first).send(&app).await;
+        assert_eq!((response.status, response.body["items"].clone()), (StatusCode::OK, json!([])));
+        let cart = TestRequest::get(CART_ENDPOINT).session(&first).send(&app).await;
+        assert_eq!(cart.body["items"], json!([]));
+        assert_eq!(add(&app, &second, 1, 10).await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn deprecated_cart_routes_still_work_and_name_their_successors() {
+        let app = TestApp::new();
+        let guest = app.guest().await;
+        let call = |path: &str, body: Value| TestRequest::post(path).session(&guest).json(body).send(&app);
+        let successor = |response: &TestResponse| (response.header("deprecation"), response.header("link"));
+
+        let added = call(ADD_TO_CART_ENDPOINT, json!({ "product_id": 1, "quantity": 2 })).await;
+        assert_eq!((added.status, added.body["status"].as_str()), (StatusCode::OK, Some("added to cart")));
+        let link = format!("<{}>; rel=\"successor-version\"", CART_ITEMS_ENDPOINT);
+        assert_eq!(successor(&added), (Some("true".to_string()), Some(link)));
+
+        let edited = call(EDIT_CART_ENDPOINT, json!({ "product_id": 1, "quantity": 3 })).await;
+        assert_eq!(edited.body["status"], "quantity updated");
+        let link = format!("<{}>; rel=\"successor-version\"", cart_item_path(1));
+        assert_eq!(successor(&edited), (Some("true".to_string()), Some(link.clone())));
+        let cart = TestRequest::get(CART_ENDPOINT).session(&guest).send(&app).await;
+        assert_eq!(lines(&cart.body), [(1, 3)]);
+
+        let deleted = call(DELETE_FROM_CART_ENDPOINT, json!({ "product_id": 1 })).await;
+        assert_eq!(deleted.body["status"], "deleted from cart");
+        assert_eq!(successor(&deleted), (Some("true".to_string()), Some(link)));
+        let cart = TestRequest::get(CART_ENDPOINT).session(&guest).send(&app).await;
+        assert_eq!(cart.body["items"], json!([]));
+
+        // Errors are the same as on the new routes
+        let missing = call(DELETE_FROM_CART_ENDPOINT, json!({ "product_id": 1 })).await;
+        assert_eq!((missing.status, missing.code()), (StatusCode::NOT_FOUND, Some("cart_item_not_found")));
+    }
+
+    #[tokio::test]
+    async fn stock_in_one_cart_is_not_available_to_others() {
+        let app = TestApp::new();
+        let (first, second) = (app.guest().await, app.guest().await);
+        assert_eq!(add(&app, &first, 2, 8).await.status, StatusCode::OK);
+
+        let response = add(&app, &second, 2, 3).await;
+        assert_eq!((response.status, response.body["available"].as_u64()), (StatusCode::CONFLICT, Some(2)));
+
+        // Removing the item hands its stock back
+        let response = TestRequest::new(Method::DELETE, &cart_item_path(2)).session(&first).send(&app).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(add(&app, &second, 2, 10).await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn checkout_takes_stock_and_cancelling_puts_it_back() {
+        let app = TestApp::new();
+        let staff = app.account("staff@example.com", Role::Staff);
+        let guest = app.guest().await;
+        add(&app, &guest, 3, 3).await;
+        assert_eq!(stock(&app, &staff.access_token, 3).await, json!({ "product_id": 3, "on_hand": 10, "reserved": 3, "available": 7 }));
+
+        let order = TestRequest::post(CHECKOUT_ENDPOINT).session(&guest).send(&app).await;
+        assert_eq!(order.status, StatusCode::CREATED);
+        assert_eq!(stock(&app, &staff.access_token, 3).await, json!({ "product_id": 3, "on_hand": 7, "reserved": 0, "available": 7 }));
+
+        let order_id = order.body["id"].as_str().unwrap();
+        let cancelled = TestRequest::post(&CANCEL_ORDER_ENDPOINT.replace("{id}", order_id)).session(&guest).send(&app).await;
+        assert_eq!(cancelled.status, StatusCode::OK);
+        assert_eq!(stock(&app, &staff.access_token, 3).await["on_hand"], 10);
+    }
+
+    #[tokio::test]
+    async fn staff_can_set_and_adjust_stock() {
+        let app = TestApp::new();
+        let staff = app.account("staff@example.com", Role::Staff);
+        let update = |body: Value| TestRequest::post(&stock_path(4)).bearer(&staff.access_token).json(body);
+
+        let response = update(json!({ "adjust": -11 })).send(&app).await;
+        assert_eq!((response.status, response.code()), (StatusCode::UNPROCESSABLE_ENTITY, Some("invalid_stock_update")));
+        assert_eq!(update(json!({ "set": 2 })).send(&app).await.body["on_hand"], 2);
+        assert_eq!(update(json!({ "adjust": 1 })).send(&app).await.body["on_hand"], 3);
+
+        let guest = app.guest().await;
+        assert_eq!(add(&app, &guest, 4, 4).await.body["available"], 3);
+        let response = TestRequest::post(&stock_path(99)).bearer(&staff.access_token).json(json!({ "set": 1 })).send(&app).await;
+        assert_eq!(response.code(), Some("product_not_found"));
+    }
+
+    #[tokio::test]
+    async fn product_pages_report_the_total_in_a_header() {
+        let app = TestApp::new();
+        let response = TestRequest::get("/products?sort=-price&per_page=2&page=2").send(&app).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.header(TOTAL_COUNT_HEADER).as_deref(), Some("4"));
+        let ids: Vec<_> = response.body.as_array().unwrap().iter().map(|p| p["id"].as_u64().unwrap()).collect();
+        assert_eq!(ids, [2, 4]);
+
+        let response = TestRequest::get("/products?q=crab&max_price=200").send(&app).await;
+        assert_eq!(response.header(TOTAL_COUNT_HEADER).as_deref(), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn invalid_product_queries_are_rejected() {
+        let app = TestApp::new();
+        for query in ["page=0", "per_page=0", "per_page=101", "sort=name", "min_price=-1"] {
+            let response = TestRequest::get(&format!("/products?{}", query)).send(&app).await;
+            assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY, "{}", query);
+        }
+        assert_eq!(TestRequest::get("/products?page=first").send(&app).await.code(), Some("invalid_request"));
+
+        let response = TestRequest::get("/products?page=0&sort=name").send(&app).await;
+        assert_eq!(response.body["fields"], json!({ "page": ["must be at least 1"], "sort": ["must be price, -price, title or -title"] }));
+    }
+
+    #[tokio::test]
+    async fn single_products_are_found_by_id() {
+        let app = TestApp::new();
+        let response = TestRequest::get("/products/2").send(&app).await;
+        assert_eq!((response.status, response.body["title"].as_str()), (StatusCode::OK, Some("Borrow Checker Mug")));
+
+        let response = TestRequest::get("/products/99").send(&app).await;
+        assert_eq!((response.status, response.code()), (StatusCode::NOT_FOUND, Some("product_not_found")));
+        assert_eq!(TestRequest::get("/products/mug").send(&app).await.code(), Some("invalid_request"));
+    }
+
+    #[tokio::test]
+    async fn categories_list_their_product_counts() {
+        let app = TestApp::new();
+        let response = TestRequest::get(CATEGORIES_ENDPOINT).send(&app).await;
+        assert_eq!(response.body, json!([
+            { "name": "electronics", "count": 1 },
+            { "name": "kitchen", "count": 1 },
+            { "name": "men's clothing", "count": 1 },
+            { "name": "toys", "count": 1 },
+        ]));
+    }
+
+    #[tokio::test]
+    async fn category_products_match_the_name_without_case() {
+        let app = TestApp::new();
+        let response = TestRequest::get("/categories/Kitchen/products").send(&app).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.header(TOTAL_COUNT_HEADER).as_deref(), Some("1"));
+        assert_eq!(response.body[0]["id"], 2);
+
+        // Query parameters still apply within the category
+        let response = TestRequest::get("/categories/kitchen/products?min_price=100").send(&app).await;
+        assert_eq!((response.status, response.body), (StatusCode::OK, json!([])));
+
+        let response = TestRequest::get("/categories/garden/products").send(&app).await;
+        assert_eq!((response.status, response.code()), (StatusCode::NOT_FOUND, Some("category_not_found")));
+    }
+
+    fn admin(path: &str, id: &str) -> String {
+        format!("{}{}", ADMIN_PREFIX, path.replace("{id}", id))
+    }
+
+    #[tokio::test]
+    async fn staff_products_are_served_like_the_catalog_ones() {
+        let app = TestApp::new();
+        let staff = app.account("staff@example.com", Role::Staff);
+        let product = json!({ "title": " Trait Object Tote ", "price": { "minor": 19900, "currency": "SEK" }, "category": "bags" });
+
+        let created = TestRequest::post(&admin(ADMIN_PRODUCTS_PATH, "")).bearer(&staff.access_token).json(product).send(&app).await;
+        assert_eq!(created.status, StatusCode::CREATED);
+        assert_eq!((created.body["id"].as_u64(), created.body["title"].as_str()), (Some(5), Some("Trait Object Tote")));
+        assert_eq!(TestRequest::get("/products/5").send(&app).await.body["category"], "bags");
+
+        let changed = json!({ "title": "Borrow Checker Cup", "price": { "minor": 8900, "currency": "SEK" }, "category": "kitchen" });
+        let updated = TestRequest::new(Method::PUT, &admin(ADMIN_PRODUCT_PATH, "2")).bearer(&staff.access_token).json(changed).send(&app).await;
+        assert_eq!(updated.status, StatusCode::OK);
+        assert_eq!(TestRequest::get("/products/2").send(&app).await.body["title"], "Borrow Checker Cup");
+
+        let deleted = TestRequest::new(Method::DELETE, &admin(ADMIN_PRODUCT_PATH, "1")).bearer(&staff.access_token).send(&app).await;
+        assert_eq!(deleted.status, StatusCode::NO_CONTENT);
+        assert_eq!(TestRequest::get("/products/1").send(&app).await.status, StatusCode::NOT_FOUND);
+        let listed = TestRequest::get(PRODUCTS_ENDPOINT).send(&app).await;
+        let ids: Vec<_> = listed.body.as_array().unwrap().iter().map(|p| p["id"].as_u64().unwrap()).collect();
+        assert_eq!(ids, [2, 3, 4, 5]);
+
+        let again = TestRequest::new(Method::DELETE, &admin(ADMIN_PRODUCT_PATH, "1")).bearer(&staff.access_token).send(&app).await;
+        assert_eq!(again.code(), Some("product_not_found"));
+    }
+
+    #[tokio::test]
+    async fn staff_see_and_manage_every_order() {
+        let app = TestApp::new();
+        let staff = app.account("staff@example.com", Role::Staff);
+        let guest = app.guest().await;
+        add(&app, &guest, 1, 1).await;
+        let order = TestRequest::post(CHECKOUT_ENDPOINT).session(&guest).send(&app).await;
+        let order_id = order.body["id"].as_str().unwrap();
+
+        let listed = TestRequest::get(&admin(ADMIN_ORDERS_PATH, "")).bearer(&staff.access_token).send(&app).await;
+        assert_eq!((listed.body[0]["id"].as_str(), listed.body[0]["owner"].as_str()), (Some(order_id), Some(guest.as_str())));
+
+        let paid = TestRequest::post(&admin(ADMIN_ORDER_STATUS_PATH, order_id))
+            .bearer(&staff.access_token)
+            .json(json!({ "status": "paid" }))
+            .send(&app)
+            .await;
+        assert_eq!((paid.status, paid.body["status"].as_str()), (StatusCode::OK, Some("paid")));
+        let pending = TestRequest::get(&format!("{}?status=pending", admin(ADMIN_ORDERS_PATH, ""))).bearer(&staff.access_token).send(&app).await;
+        assert_eq!(pending.body, json!([]));
+
+        let backwards = TestRequest::post(&admin(ADMIN_ORDER_STATUS_PATH, order_id))
+            .bearer(&staff.access_token)
+            .json(json!({ "status": "pending" }))
+            .send(&app)
+            .await;
+        assert_eq!((backwards.status, backwards.code()), (StatusCode::CONFLICT, Some("invalid_order_status")));
+        let fetched = TestRequest::get(&admin(ADMIN_ORDER_PATH, order_id)).bearer(&staff.access_token).send(&app).await;
+        assert_eq!(fetched.body["status"], "paid");
+    }
+
+    // Approves charges, but only once the test lets them finish
+    #[derive(Default)]
+    struct HeldCharges {
+        charging: Notify,
+        release: Notify,
+    }
+
+    #[async_trait]
+    impl PaymentProvider for HeldCharges {
+        async fn charge(&self, _order: &Order) -> Result<PaymentOutcome, String> {
+            self.charging.notify_one();
+            self.release.notified().await;
+            Ok(PaymentOutcome::Approved { payment_id: "held".to_string() })
+        }
+
+        async fn confirm(&self, _payment_id: &str, _approve: bool) -> Result<(), String> {
+            Err("Nothing to confirm".to_string())
+        }
+
+        fn verify_webhook(&self, _signature: Option<&str>) -> bool {
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn orders_cancelled_while_being_charged_are_not_marked_paid() {
+        let payments = Arc::new(HeldCharges::default());
+        let app = TestApp::with(TestOptions { payments: payments.clone(), ..TestOptions::default() });
+        let guest = app.guest().await;
+        add(&app, &guest, 1, 2).await;
+        let order = TestRequest::post(CHECKOUT_ENDPOINT).session(&guest).send(&app).await;
+        let order_id = order.body["id"].as_str().unwrap();
+
+        let pay = TestRequest::post(&PAY_ORDER_ENDPOINT.replace("{id}", order_id)).session(&guest).send(&app);
+        let cancel = async {
+            payments.charging.notified().await;
+            let cancelled = TestRequest::post(&CANCEL_ORDER_ENDPOINT.replace("{id}", order_id)).session(&guest).send(&app).await;
+            payments.release.notify_one();
+            cancelled
+        };
+        let (paid, cancelled) = tokio::join!(pay, cancel);
+        assert_eq!(cancelled.status, StatusCode::OK);
+        assert_eq!((paid.status, paid.code()), (StatusCode::CONFLICT, Some("invalid_order_status")));
+
+        let order = TestRequest::get(&ORDER_ENDPOINT.replace("{id}", order_id)).session(&guest).send(&app).await;
+        assert_eq!((order.body["status"].as_str(), order.body["payment_id"].as_str()), (Some("cancelled"), None));
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use shared::models::{Category, Product, ProductQuery};
+use shared::Money;
+
+pub const DEFAULT_PER_PAGE: u32 = 20;
+
+// One page of the products matching a query, with how many matched in total
+pub struct ProductPage {
+    pub products: Vec<Product>,
+    pub total: usize,
+}
+
+enum SortKey {
+    Price,
+    Title,
+}
+
+// None for sorts `ProductQuery` doesn't accept
+fn parse_sort(sort: &str) -> Option<(SortKey, bool)> {
+    let (field, descending) = match sort.strip_prefix('-') {
+        Some(field) => (field, true),
+        None => (sort, false),
+    };
+    match field {
+        "price" => Some((SortKey::Price, descending)),
+        "title" => Some((SortKey::Title, descending)),
+        _ => None,
+    }
+}
+
+// Whether the price is within the bounds, which are in major units of the product's currency
+fn in_price_range(price: &Money, min: Option<f64>, max: Option<f64>) -> bool {
+    let minor = |bound: f64| Money::from_major(bound, price.currency()).minor();
+    min.is_none_or(|min| price.minor() >= minor(min)) && max.is_none_or(|max| price.minor() <= minor(max))
+}
+
+// Distinct categories of the products with their product counts, by name
+pub fn categories(products: &[Product]) -> Vec<Category> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for product in products {
+        *counts.entry(product.category.as_str()).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .map(|(name, count)| Category { name: name.to_string(), count })
+        .collect()
+}
+
+// Filters, sorts and pages the catalog. Every word of `q` must appear in the title or description.
+// The query is expected to have passed its validation.
+pub fn search(mut products: Vec<Product>, query: &ProductQuery) -> ProductPage {
+    let page = query.page.unwrap_or(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE);
+    let sort = query.sort.as_deref().and_then(parse_sort);
+
+    let terms: Vec<String> = query
+        .q
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect();
+    products.retain(|product| {
+        let text = format!("{} {}", product.title, product.description).to_lowercase();
+        terms.iter().all(|term| text.contains(term.as_str()))
+            && query.category.as_ref().is_none_or(|c| product.category.eq_ignore_ascii_case(c))
+            && in_price_range(&product.price, query.min_price, query.max_price)
+    });
+
+    if let Some((key, descending)) = sort {
+        products.sort_by(|a, b| {
+            let order = match key {
+                SortKey::Price => a.price.minor().cmp(&b.price.minor()),
+                SortKey::Title => a.title.to_lowercase().cmp(&b.title.to_lowercase()),
+            };
+            let order = if descending { order.reverse() } else { order };
+            order.then(a.id.cmp(&b.id))
+        });
+    }
+
+    let total = products.len();
+    let start = (page as usize).saturating_sub(1).saturating_mul(per_page as usize);
+    let products = products.into_iter().skip(start).take(per_page as usize).collect();
+    ProductPage { products, total }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn product(id: u32, title: &str, description: &str, category: &str, price: i64) -> Product {
+        Product {
+            id,
+            title: title.to_string(),
+            price: Money::new(price, "SEK"),
+            description: description.to_string(),
+            category: category.to_string(),
+            image: String::new(),
+            weight: None,
+        }
+    }
+
+    fn catalog() -> Vec<Product> {
+        vec![
+            product(1, "Ferris Plush Crab", "A soft crab", "toys", 14900),
+            product(2, "Borrow Checker Mug", "Holds coffee, not references", "kitchen", 9950),
+            product(3, "Crab Hoodie", "Warm and orange", "clothing", 44900),
+            product(4, "sticker pack", "Unsafe stickers", "toys", 9950),
+        ]
+    }
+
+    fn ids(query: ProductQuery) -> Vec<u32> {
+        search(catalog(), &query).products.iter().map(|p| p.id).collect()
+    }
+
+    #[test]
+    fn every_word_must_appear_in_the_title_or_description() {
+        assert_eq!(ids(ProductQuery { q: Some("crab".to_string()), ..Default::default() }), [1, 3]);
+        assert_eq!(ids(ProductQuery { q: Some("CRAB  soft".to_string()), ..Default::default() }), [1]);
+        assert_eq!(ids(ProductQuery { q: Some("references".to_string()), ..Default::default() }), [2]);
+        assert!(ids(ProductQuery { q: Some("crab mug".to_string()), ..Default::default() }).is_empty());
+    }
+
+    #[test]
+    fn filters_by_category_and_price_in_major_units() {
+        assert_eq!(ids(ProductQuery { category: Some("toys".to_string()), ..Default::default() }), [1, 4]);
+        assert_eq!(ids(ProductQuery { min_price: Some(99.5), max_price: Some(149.0), ..Default::default() }), [1, 2, 4]);
+        assert_eq!(ids(ProductQuery { min_price: Some(100.0), ..Default::default() }), [1, 3]);
+        assert_eq!(ids(ProductQuery { category: Some("Toys".to_string()), max_price: Some(100.0), ..Default::default() }), [4]);
+    }
+
+    #[test]
+    fn sorts_with_ties_broken_by_id() {
+        let sorted = |sort: &str| ids(ProductQuery { sort: Some(sort.to_string()), ..Default::default() });
+        assert_eq!(sorted("price"), [2, 4, 1, 3]);
+        assert_eq!(sorted("-price"), [3, 1, 2, 4]);
+        // Titles compare without case
+        assert_eq!(sorted("title"), [2, 3, 1, 4]);
+        assert_eq!(sorted("-title"), [4, 1, 3, 2]);
+    }
+
+    #[test]
+    fn pages_count_every_match() {
+        let page = |page: u32| {
+            let query = ProductQuery { sort: Some("price".to_string()), page: Some(page), per_page: Some(3), ..Default::default() };
+            let result = search(catalog(), &query);
+            (result.products.iter().map(|p| p.id).collect::<Vec<_>>(), result.total)
+        };
+        assert_eq!(page(1), (vec![2, 4, 1], 4));
+        assert_eq!(page(2), (vec![3], 4));
+        assert_eq!(page(3), (vec![], 4));
+        assert_eq!(search(catalog(), &ProductQuery::default()).products.len(), 4);
+    }
+
+    #[test]
+    fn categories_are_listed_by_name_with_counts() {
+        let names = categories(&catalog()).into_iter().map(|c| (c.name, c.count)).collect::<Vec<_>>();
+        let expected = [("clothing", 1), ("kitchen", 1), ("toys", 2)].map(|(name, count)| (name.to_string(), count));
+        assert_eq!(names, expected);
+    }
+}
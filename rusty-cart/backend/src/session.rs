@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use axum::{extract::{FromRequestParts, OptionalFromRequestParts, Request, State}, http::{request::Parts, HeaderValue}, middleware::Next, response::{IntoResponse, Response}};
+use uuid::Uuid;
+use shared::api::SESSION_HEADER;
+use shared::models::{Address, CartItem};
+use crate::auth::AuthUser;
+use crate::error::ApiError;
+use crate::state::AppState;
+use crate::storage::CartStorage;
+
+// Sessions that have not been used for this long are dropped together with their cart
+pub const SESSION_TTL: Duration = Duration::from_secs(30 * 60);
+
+// Session id of the caller, inserted into the request extensions by `session_layer`
+#[derive(Clone, Debug)]
+pub struct SessionId(pub String);
+
+// Whose cart and orders a request works with: the logged-in account if there is one, otherwise
+// the guest session. Only usable on routes behind `session_layer`.
+#[derive(Clone, Debug)]
+pub struct CartOwner(pub String);
+
+impl FromRequestParts<AppState> for CartOwner {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        if let Some(user) = <AuthUser as OptionalFromRequestParts<AppState>>::from_request_parts(parts, state).await? {
+            return Ok(CartOwner(user.id));
+        }
+        parts.extensions
+            .get::<SessionId>()
+            .map(|session| CartOwner(session.0.clone()))
+            .ok_or_else(|| ApiError::Internal("Cart route registered without session_layer".to_string()))
+    }
+}
+
+#[derive(Clone)]
+pub struct SessionStore {
+    storage: Arc<dyn CartStorage>,
+    // One lock per cart being changed, serializing its read-modify-write cycles so concurrent
+    // requests don't overwrite each other while other carts don't have to wait. An entry is
+    // dropped once nobody holds or waits for it.
+    cart_locks: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+}
+
+impl SessionStore {
+    pub fn new(storage: Arc<dyn CartStorage>) -> Self {
+        SessionStore {
+            storage,
+            cart_locks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Returns the requested id if it belongs to a live session, otherwise starts a new session
+    pub fn resolve(&self, requested: Option<&str>) -> Result<String, String> {
+        if let Some(id) = requested {
+            if self.storage.touch_session(id)? {
+                return Ok(id.to_string());
+            }
+        }
+
+        let id = Uuid::new_v4().to_string();
+        self.storage.create_session(&id)?;
+        Ok(id)
+    }
+
+    // Whether the id belongs to a live session; unlike `resolve` this doesn't keep the session alive
+    pub fn exists(&self, id: &str) -> Result<bool, String> {
+        self.storage.session_exists(id)
+    }
+
+    // Runs `f` against the cart of the given session and stores the result. Blocks while another
+    // request changes the same cart.
+    pub fn with_cart<R>(&self, id: &str, f: impl FnOnce(&mut Vec<CartItem>) -> R) -> Result<R, String> {
+        // The locks guard no data, so a panic while one was held leaves nothing inconsistent behind
+        let lock = self.cart_locks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(id.to_string())
+            .or_default()
+            .clone();
+        let result = {
+            let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+            self.storage.load_cart(id).and_then(|mut cart| {
+                let result = f(&mut cart);
+                self.storage.save_cart(id, &cart).map(|()| result)
+            })
+        };
+        drop(lock);
+
+        // Only the map holds the lock now unless another request got it in the meantime
+        let mut locks = self.cart_locks.lock().unwrap_or_else(PoisonError::into_inner);
+        if locks.get(id).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+            locks.remove(id);
+        }
+        result
+    }
+
+    pub fn coupon(&self, id: &str) -> Result<Option<String>, String> {
+        self.storage.load_coupon(id)
+    }
+
+    pub fn set_coupon(&self, id: &str, code: Option<&str>) -> Result<(), String> {
+        self.storage.save_coupon(id, code)
+    }
+
+    pub fn address(&self, id: &str) -> Result<Option<Address>, String> {
+        self.storage.load_address(id)
+    }
+
+    pub fn set_address(&self, id: &str, address: Option<&Address>) -> Result<(), String> {
+        self.storage.save_address(id, address)
+    }
+
+    pub fn shipping_method(&self, id: &str) -> Result<Option<String>, String> {
+        self.storage.load_shipping_method(id)
+    }
+
+    pub fn set_shipping_method(&self, id: &str, method: Option<&str>) -> Result<(), String> {
+        self.storage.save_shipping_method(id, method)
+    }
+
+    // Drops sessions idle for longer than `ttl`, returning how many were removed
+    pub fn expire_idle(&self, ttl: Duration) -> usize {
+        self.storage.expire_idle(ttl).unwrap_or_else(|e| {
+            eprintln!("Failed to expire sessions: {}", e);
+            0
+        })
+    }
+}
+
+// Resolves the caller's session from the `x-session-id` header, issuing a new one
+// when it is missing or expired, and echoes the id back on the response. Signed-in callers work
+// with their account's cart, so they get no session. Must run inside `auth_layer`.
+pub async fn session_layer(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    if req.extensions().get::<AuthUser>().is_some() {
+        return next.run(req).await;
+    }
+    let requested = req
+        .headers()
+        .get(SESSION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+
+    let resolved = state.blocking(move |state| {
+        state.sessions
+            .resolve(requested.as_deref())
+            .map_err(|e| ApiError::Internal(format!("Failed to resolve session: {}", e)))
+    }).await;
+    let id = match resolved {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+
+    req.extensions_mut().insert(SessionId(id.clone()));
+    let mut res = next.run(req).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(SESSION_HEADER, value);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+    use shared::api::{cart_item_path, CART_ENDPOINT, CART_ITEMS_ENDPOINT, ORDERS_ENDPOINT};
+    use shared::models::Role;
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::test_support::{TestApp, TestRequest};
+
+    #[test]
+    fn other_sessions_are_not_blocked_by_a_cart_change() {
+        let sessions = SessionStore::new(Arc::new(MemoryStorage::new()));
+        let (started, wait_started) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel::<()>();
+
+        let holder = {
+            let sessions = sessions.clone();
+            thread::spawn(move || {
+                sessions.with_cart("a", |_| {
+                    started.send(()).unwrap();
+                    wait_release.recv_timeout(Duration::from_secs(5)).unwrap();
+                })
+            })
+        };
+        wait_started.recv().unwrap();
+
+        // Finishes while the change to "a" is still in progress
+        sessions.with_cart("b", |cart| cart.len()).unwrap();
+        release.send(()).unwrap();
+        holder.join().unwrap().unwrap();
+        assert!(sessions.cart_locks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn changes_to_the_same_cart_are_not_lost() {
+        let sessions = SessionStore::new(Arc::new(MemoryStorage::new()));
+        let item = CartItem {
+            product: shared::models::Product {
+                id: 1,
+                title: "Mug".to_string(),
+                price: shared::money::Money::new(4900, "SEK"),
+                description: String::new(),
+                category: "kitchen".to_string(),
+                image: String::new(),
+                weight: None,
+            },
+            quantity: 0,
+        };
+        sessions.with_cart("a", |cart| cart.push(item)).unwrap();
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let sessions = sessions.clone();
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        sessions.with_cart("a", |cart| cart[0].quantity += 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(sessions.with_cart("a", |cart| cart[0].quantity).unwrap(), 200);
+    }
+
+    #[tokio::test]
+    async fn sessions_are_issued_once_and_echoed_back() {
+        let app = TestApp::new();
+        let first = TestRequest::get(CART_ENDPOINT).send(&app).await;
+        let id = first.header(SESSION_HEADER).unwrap();
+        assert!(Uuid::parse_str(&id).is_ok(), "{}", id);
+
+        let again = TestRequest::get(CART_ENDPOINT).session(&id).send(&app).await;
+        assert_eq!(again.header(SESSION_HEADER), Some(id.clone()));
+        // Error responses carry it too
+        let error = TestRequest::new(Method::DELETE, &cart_item_path(1)).session(&id).send(&app).await;
+        assert_eq!((error.status, error.header(SESSION_HEADER)), (StatusCode::NOT_FOUND, Some(id.clone())));
+
+        let unknown = TestRequest::get(CART_ENDPOINT).session("not-issued").send(&app).await;
+        let new_id = unknown.header(SESSION_HEADER).unwrap();
+        assert!(new_id != id && new_id != "not-issued");
+    }
+
+    #[tokio::test]
+    async fn idle_sessions_expire_with_their_cart() {
+        let app = TestApp::new();
+        let account = app.account("ferris@example.com", Role::Customer);
+        let add = |request: TestRequest| request.json(json!({ "product_id": 1, "quantity": 1 })).send(&app);
+        let idle = app.guest().await;
+        add(TestRequest::post(CART_ITEMS_ENDPOINT).session(&idle)).await;
+        add(TestRequest::post(CART_ITEMS_ENDPOINT).bearer(&account.access_token)).await;
+
+        // Last seen at least two whole seconds ago, so over a one second limit
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        let active = app.guest().await;
+        assert_eq!(app.state.sessions.expire_idle(Duration::from_secs(1)), 1);
+
+        let cart = TestRequest::get(CART_ENDPOINT).session(&idle).send(&app).await;
+        assert_ne!(cart.header(SESSION_HEADER), Some(idle));
+        assert_eq!(cart.body["items"], json!([]));
+        let cart = TestRequest::get(CART_ENDPOINT).session(&active).send(&app).await;
+        assert_eq!(cart.header(SESSION_HEADER), Some(active));
+        // Account carts don't expire
+        let cart = TestRequest::get(CART_ENDPOINT).bearer(&account.access_token).send(&app).await;
+        assert_eq!(cart.body["items"][0]["quantity"], 1);
+    }
+
+    #[tokio::test]
+    async fn an_account_id_is_not_a_session_id() {
+        let app = TestApp::new();
+        let account = app.account("ferris@example.com", Role::Customer);
+        let add = TestRequest::post(CART_ITEMS_ENDPOINT)
+            .bearer(&account.access_token)
+            .json(json!({ "product_id": 1, "quantity": 2 }))
+            .send(&app)
+            .await;
+        assert_eq!(add.status, StatusCode::OK);
+        // Signed-in requests don't start guest sessions
+        assert_eq!(add.header(SESSION_HEADER), None);
+
+        let cart = TestRequest::get(CART_ENDPOINT).session(&account.user.id).send(&app).await;
+        assert_eq!(cart.body["items"], json!([]));
+        assert_ne!(cart.header(SESSION_HEADER).unwrap(), account.user.id);
+        let orders = TestRequest::get(ORDERS_ENDPOINT).session(&account.user.id).send(&app).await;
+        assert_eq!(orders.body, json!([]));
+
+        let cart = TestRequest::get(CART_ENDPOINT).bearer(&account.access_token).send(&app).await;
+        assert_eq!(cart.body["items"][0]["quantity"], 2);
+    }
+}
@@ -0,0 +1,271 @@
+use std::path::Path;
+use serde::Deserialize;
+use shared::models::{CartItem, ShippingOption};
+use shared::money::{Money, DEFAULT_CURRENCY};
+
+// Weight assumed for products the catalog has no weight for, in grams
+pub const DEFAULT_ITEM_WEIGHT: u32 = 500;
+
+fn default_item_weight() -> u32 {
+    DEFAULT_ITEM_WEIGHT
+}
+
+// Carts up to `up_to` items (or grams, for weight tiers) cost `amount`
+#[derive(Deserialize, Clone, Debug)]
+pub struct RateTier {
+    pub up_to: u32,
+    pub amount: Money,
+}
+
+// How the cost of a shipping method is worked out
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ShippingRate {
+    // The same cost for every cart
+    Flat { amount: Money },
+    // Cost by number of items; carts above the last tier can't use the method
+    QuantityTiers { tiers: Vec<RateTier> },
+    // Cost by total weight in grams; carts above the last tier can't use the method
+    WeightTiers { tiers: Vec<RateTier> },
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ShippingMethod {
+    pub id: String,
+    pub name: String,
+    pub rate: ShippingRate,
+    // Country codes the method delivers to; every country when missing
+    #[serde(default)]
+    pub countries: Option<Vec<String>>,
+    // Carts worth at least this much after discounts ship for free
+    #[serde(default)]
+    pub free_over: Option<Money>,
+}
+
+// The cheapest tier that fits `measure`
+fn tier_amount(tiers: &[RateTier], measure: u64) -> Option<&Money> {
+    tiers
+        .iter()
+        .filter(|tier| measure <= tier.up_to as u64)
+        .min_by_key(|tier| tier.up_to)
+        .map(|tier| &tier.amount)
+}
+
+impl ShippingMethod {
+    // What delivering the cart to `country` costs, or why this method can't deliver it.
+    // `goods_total` is the cart total after discounts.
+    fn quote(&self, cart: &[CartItem], goods_total: &Money, country: &str, default_weight: u32) -> Result<Money, String> {
+        if let Some(countries) = &self.countries {
+            if !countries.iter().any(|c| c.eq_ignore_ascii_case(country)) {
+                return Err(format!("{} does not deliver to {}", self.name, country.to_uppercase()));
+            }
+        }
+
+        let too_large = || format!("The cart is too large for {}", self.name);
+        let cost = match &self.rate {
+            ShippingRate::Flat { amount } => amount.clone(),
+            ShippingRate::QuantityTiers { tiers } => {
+                let items: u64 = cart.iter().map(|item| item.quantity as u64).sum();
+                tier_amount(tiers, items).cloned().ok_or_else(too_large)?
+            }
+            ShippingRate::WeightTiers { tiers } => {
+                let grams: u64 = cart
+                    .iter()
+                    .map(|item| item.product.weight.unwrap_or(default_weight) as u64 * item.quantity as u64)
+                    .sum();
+                tier_amount(tiers, grams).cloned().ok_or_else(too_large)?
+            }
+        };
+        if cost.currency() != goods_total.currency() {
+            return Err(format!("{} is not available for prices in {}", self.name, goods_total.currency()));
+        }
+
+        let free = self.free_over.as_ref().is_some_and(|threshold| {
+            threshold.currency() == goods_total.currency() && goods_total.minor() >= threshold.minor()
+        });
+        Ok(if free { Money::zero(cost.currency()) } else { cost })
+    }
+}
+
+// All shipping methods the shop offers
+#[derive(Deserialize, Clone, Debug)]
+pub struct ShippingTable {
+    #[serde(default = "default_item_weight")]
+    pub default_weight: u32,
+    pub methods: Vec<ShippingMethod>,
+}
+
+impl ShippingTable {
+    // Flat-rate standard delivery that is free from 500 kr, and express delivery priced by item count
+    pub fn standard() -> Self {
+        ShippingTable {
+            default_weight: DEFAULT_ITEM_WEIGHT,
+            methods: vec![
+                ShippingMethod {
+                    id: "standard".to_string(),
+                    name: "Standard delivery".to_string(),
+                    rate: ShippingRate::Flat { amount: Money::new(4900, DEFAULT_CURRENCY) },
+                    countries: None,
+                    free_over: Some(Money::new(50000, DEFAULT_CURRENCY)),
+                },
+                ShippingMethod {
+                    id: "express".to_string(),
+                    name: "Express delivery".to_string(),
+                    rate: ShippingRate::QuantityTiers {
+                        tiers: vec![
+                            RateTier { up_to: 3, amount: Money::new(9900, DEFAULT_CURRENCY) },
+                            RateTier { up_to: 10, amount: Money::new(14900, DEFAULT_CURRENCY) },
+                        ],
+                    },
+                    countries: None,
+                    free_over: None,
+                },
+            ],
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ShippingMethod> {
+        self.methods.iter().find(|method| method.id.eq_ignore_ascii_case(id))
+    }
+
+    pub fn quote(&self, method: &ShippingMethod, cart: &[CartItem], goods_total: &Money, country: &str) -> Result<Money, String> {
+        method.quote(cart, goods_total, country, self.default_weight)
+    }
+
+    // Methods that can deliver the cart to `country`, in configuration order
+    pub fn options(&self, cart: &[CartItem], goods_total: &Money, country: &str) -> Vec<ShippingOption> {
+        self.methods
+            .iter()
+            .filter_map(|method| {
+                let cost = self.quote(method, cart, goods_total, country).ok()?;
+                Some(ShippingOption {
+                    method: method.id.clone(),
+                    name: method.name.clone(),
+                    cost,
+                })
+            })
+            .collect()
+    }
+
+    // Loads the methods from a JSON or YAML file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let is_yaml = matches!(path.extension().and_then(|ext| ext.to_str()), Some("yaml") | Some("yml"));
+        if is_yaml {
+            serde_yaml::from_str(&contents).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+        } else {
+            serde_json::from_str(&contents).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+        }
+    }
+}
+
+// Reads shipping methods from the configured file, or uses the standard ones
+pub fn from_config(file: Option<&Path>) -> Result<ShippingTable, String> {
+    match file {
+        Some(path) => ShippingTable::load(path),
+        None => Ok(ShippingTable::standard()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::models::Product;
+    use super::*;
+
+    fn item(quantity: u32, weight: Option<u32>) -> CartItem {
+        CartItem {
+            product: Product {
+                id: 1,
+                title: "Parcel".to_string(),
+                price: Money::new(1000, "SEK"),
+                description: String::new(),
+                category: "misc".to_string(),
+                image: String::new(),
+                weight,
+            },
+            quantity,
+        }
+    }
+
+    fn sek(minor: i64) -> Money {
+        Money::new(minor, "SEK")
+    }
+
+    fn tiers(tiers: &[(u32, i64)]) -> Vec<RateTier> {
+        tiers.iter().map(|(up_to, amount)| RateTier { up_to: *up_to, amount: sek(*amount) }).collect()
+    }
+
+    fn method(rate: ShippingRate) -> ShippingMethod {
+        ShippingMethod {
+            id: "test".to_string(),
+            name: "Test".to_string(),
+            rate,
+            countries: None,
+            free_over: None,
+        }
+    }
+
+    fn quote(method: &ShippingMethod, cart: &[CartItem]) -> Result<i64, String> {
+        method.quote(cart, &sek(1000), "SE", DEFAULT_ITEM_WEIGHT).map(|cost| cost.minor())
+    }
+
+    #[test]
+    fn flat_rates_cost_the_same_for_every_cart() {
+        let flat = method(ShippingRate::Flat { amount: sek(4900) });
+        assert_eq!(quote(&flat, &[item(1, None)]), Ok(4900));
+        assert_eq!(quote(&flat, &[item(100, Some(20_000))]), Ok(4900));
+    }
+
+    #[test]
+    fn quantity_tiers_use_the_smallest_tier_that_fits() {
+        // Tiers don't have to be listed in order
+        let tiered = method(ShippingRate::QuantityTiers { tiers: tiers(&[(10, 14900), (3, 9900)]) });
+        assert_eq!(quote(&tiered, &[item(2, None), item(1, None)]), Ok(9900));
+        assert_eq!(quote(&tiered, &[item(4, None)]), Ok(14900));
+        assert_eq!(quote(&tiered, &[item(10, None), item(1, None)]), Err("The cart is too large for Test".to_string()));
+    }
+
+    #[test]
+    fn weight_tiers_fill_in_missing_weights_with_the_default() {
+        let tiered = method(ShippingRate::WeightTiers { tiers: tiers(&[(1000, 5900), (5000, 9900)]) });
+        assert_eq!(quote(&tiered, &[item(2, Some(250))]), Ok(5900));
+        // 2 × 500 g assumed
+        assert_eq!(quote(&tiered, &[item(2, None)]), Ok(5900));
+        assert_eq!(quote(&tiered, &[item(2, None), item(1, Some(1))]), Ok(9900));
+        assert_eq!(tiered.quote(&[item(2, None)], &sek(1000), "SE", 600).map(|cost| cost.minor()), Ok(9900));
+        assert!(quote(&tiered, &[item(1, Some(5001))]).is_err());
+
+        let table: ShippingTable = serde_json::from_str(r#"{"methods": []}"#).unwrap();
+        assert_eq!(table.default_weight, DEFAULT_ITEM_WEIGHT);
+    }
+
+    #[test]
+    fn carts_worth_enough_ship_for_free() {
+        let mut flat = method(ShippingRate::Flat { amount: sek(4900) });
+        flat.free_over = Some(sek(50000));
+        let cart = [item(1, None)];
+        assert_eq!(flat.quote(&cart, &sek(49999), "SE", DEFAULT_ITEM_WEIGHT), Ok(sek(4900)));
+        assert_eq!(flat.quote(&cart, &sek(50000), "SE", DEFAULT_ITEM_WEIGHT), Ok(sek(0)));
+    }
+
+    #[test]
+    fn methods_only_deliver_to_their_countries() {
+        let mut flat = method(ShippingRate::Flat { amount: sek(4900) });
+        flat.countries = Some(vec!["SE".to_string(), "no".to_string()]);
+        let cart = [item(1, None)];
+        assert!(flat.quote(&cart, &sek(1000), "NO", DEFAULT_ITEM_WEIGHT).is_ok());
+        assert_eq!(flat.quote(&cart, &sek(1000), "dk", DEFAULT_ITEM_WEIGHT), Err("Test does not deliver to DK".to_string()));
+
+        let table = ShippingTable { default_weight: DEFAULT_ITEM_WEIGHT, methods: vec![flat, ShippingTable::standard().methods[1].clone()] };
+        let options: Vec<_> = table.options(&cart, &sek(1000), "DK").into_iter().map(|option| option.method).collect();
+        assert_eq!(options, ["express"]);
+    }
+
+    #[test]
+    fn prices_in_another_currency_are_not_quoted() {
+        let flat = method(ShippingRate::Flat { amount: sek(4900) });
+        let result = flat.quote(&[item(1, None)], &Money::new(1000, "EUR"), "SE", DEFAULT_ITEM_WEIGHT);
+        assert_eq!(result, Err("Test is not available for prices in EUR".to_string()));
+    }
+}
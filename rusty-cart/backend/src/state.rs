@@ -1,15 +1,74 @@
-use std::sync::{Arc, Mutex};
-use crate::models::CartItem;
+use std::sync::Arc;
+use crate::catalog_cache::CachedCatalog;
+use crate::auth::TokenIssuer;
+use crate::config::CartMergePolicy;
+use crate::coupons::CouponBook;
+use crate::error::ApiError;
+use crate::inventory::Inventory;
+use crate::shipping::ShippingTable;
+use crate::tax::TaxTable;
+use crate::payments::PaymentProvider;
+use crate::rate_limit::RateLimiter;
+use crate::session::SessionStore;
+use crate::storage::Storage;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub cart: Arc<Mutex<Vec<CartItem>>>,
+    pub catalog: CachedCatalog,
+    pub sessions: SessionStore,
+    pub storage: Arc<dyn Storage>,
+    pub payments: Arc<dyn PaymentProvider>,
+    pub coupons: Arc<CouponBook>,
+    pub shipping: Arc<ShippingTable>,
+    pub tax: Arc<TaxTable>,
+    pub inventory: Inventory,
+    pub tokens: Arc<TokenIssuer>,
+    pub cart_merge: CartMergePolicy,
+    // Normalized emails of the accounts that are admins from the start
+    pub admin_emails: Arc<Vec<String>>,
+    pub rate_limiter: RateLimiter,
 }
 
 impl AppState {
-    pub fn new() -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        catalog: CachedCatalog,
+        storage: Arc<dyn Storage>,
+        payments: Arc<dyn PaymentProvider>,
+        coupons: CouponBook,
+        shipping: ShippingTable,
+        tax: TaxTable,
+        inventory: Inventory,
+        tokens: TokenIssuer,
+        cart_merge: CartMergePolicy,
+        admin_emails: Vec<String>,
+        rate_limiter: RateLimiter,
+    ) -> Self {
         AppState {
-            cart: Arc::new(Mutex::new(Vec::new())),
+            catalog,
+            sessions: SessionStore::new(storage.clone()),
+            storage,
+            payments,
+            coupons: Arc::new(coupons),
+            shipping: Arc::new(shipping),
+            tax: Arc::new(tax),
+            inventory,
+            tokens: Arc::new(tokens),
+            cart_merge,
+            admin_emails: Arc::new(admin_emails),
+            rate_limiter,
         }
     }
+
+    // Runs `f` on Tokio's blocking thread pool. Storage calls block on SQLite, so handlers make
+    // them through here rather than on the worker threads every other request runs on.
+    pub async fn blocking<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&AppState) -> Result<R, ApiError> + Send + 'static,
+    ) -> Result<R, ApiError> {
+        let state = self.clone();
+        tokio::task::spawn_blocking(move || f(&state))
+            .await
+            .map_err(|e| ApiError::Internal(format!("Blocking task failed: {}", e)))?
+    }
 }
@@ -0,0 +1,446 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+use shared::models::{Address, CartItem, Order, OrderStatus, Product, Role, StockLevel};
+use crate::auth::User;
+use super::{now_secs, CartStorage, CouponStorage, InventoryStorage, OrderStorage, ProductStorage, UserStorage};
+
+#[derive(Default)]
+struct SessionRow {
+    cart: Vec<CartItem>,
+    coupon: Option<String>,
+    address: Option<Address>,
+    shipping_method: Option<String>,
+    last_seen: u64,
+}
+
+#[derive(Default)]
+struct Inventory {
+    on_hand: HashMap<u32, u32>,
+    // (session id, product id) -> (quantity, expires at)
+    reservations: HashMap<(String, u32), (u32, u64)>,
+}
+
+impl Inventory {
+    // Quantity of the product held by unexpired reservations, leaving out `except_session`'s
+    fn reserved(&self, product_id: u32, except_session: Option<&str>, now: u64) -> u32 {
+        self.reservations
+            .iter()
+            .filter(|((session, product), (_, expires_at))| {
+                *product == product_id && *expires_at >= now && except_session != Some(session.as_str())
+            })
+            .map(|(_, (quantity, _))| quantity)
+            .sum()
+    }
+
+    fn available(&self, session_id: &str, product_id: u32, now: u64) -> u32 {
+        let on_hand = self.on_hand.get(&product_id).copied().unwrap_or(0);
+        on_hand.saturating_sub(self.reserved(product_id, Some(session_id), now))
+    }
+}
+
+// Keeps everything in process memory; lost on restart
+#[derive(Default)]
+pub struct MemoryStorage {
+    sessions: Mutex<HashMap<String, SessionRow>>,
+    // (owner, order) in insertion order
+    orders: Mutex<Vec<(String, Order)>>,
+    coupon_uses: Mutex<HashMap<String, u32>>,
+    inventory: Mutex<Inventory>,
+    // Keyed by user id
+    users: Mutex<HashMap<String, User>>,
+    // Token hash -> refresh token
+    refresh_tokens: Mutex<HashMap<String, RefreshToken>>,
+    // Access token id -> expires at
+    revoked_access_tokens: Mutex<HashMap<String, u64>>,
+    // Product id -> product, None if deleted
+    product_edits: Mutex<BTreeMap<u32, Option<Product>>>,
+}
+
+struct RefreshToken {
+    family: String,
+    user_id: String,
+    expires_at: u64,
+    revoked: bool,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    // Account carts live next to the guest sessions, keyed by the account id
+    fn is_user(&self, id: &str) -> Result<bool, String> {
+        Ok(self.users.lock().map_err(|e| e.to_string())?.contains_key(id))
+    }
+}
+
+impl CartStorage for MemoryStorage {
+    fn create_session(&self, id: &str) -> Result<(), String> {
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        sessions.insert(id.to_string(), SessionRow { last_seen: now_secs(), ..SessionRow::default() });
+        Ok(())
+    }
+
+    fn touch_session(&self, id: &str) -> Result<bool, String> {
+        if self.is_user(id)? {
+            return Ok(false);
+        }
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        match sessions.get_mut(id) {
+            Some(session) => {
+                session.last_seen = now_secs();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn session_exists(&self, id: &str) -> Result<bool, String> {
+        if self.is_user(id)? {
+            return Ok(false);
+        }
+        let sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        Ok(sessions.contains_key(id))
+    }
+
+    fn load_cart(&self, session_id: &str) -> Result<Vec<CartItem>, String> {
+        let sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        Ok(sessions.get(session_id).map(|s| s.cart.clone()).unwrap_or_default())
+    }
+
+    fn save_cart(&self, session_id: &str, cart: &[CartItem]) -> Result<(), String> {
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        let session = sessions
+            .entry(session_id.to_string())
+            .or_default();
+        session.cart = cart.to_vec();
+        session.last_seen = now_secs();
+        Ok(())
+    }
+
+    fn load_coupon(&self, session_id: &str) -> Result<Option<String>, String> {
+        let sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        Ok(sessions.get(session_id).and_then(|s| s.coupon.clone()))
+    }
+
+    fn save_coupon(&self, session_id: &str, code: Option<&str>) -> Result<(), String> {
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        let session = sessions.entry(session_id.to_string()).or_default();
+        session.coupon = code.map(str::to_string);
+        session.last_seen = now_secs();
+        Ok(())
+    }
+
+    fn load_address(&self, session_id: &str) -> Result<Option<Address>, String> {
+        let sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        Ok(sessions.get(session_id).and_then(|s| s.address.clone()))
+    }
+
+    fn save_address(&self, session_id: &str, address: Option<&Address>) -> Result<(), String> {
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        let session = sessions.entry(session_id.to_string()).or_default();
+        session.address = address.cloned();
+        session.last_seen = now_secs();
+        Ok(())
+    }
+
+    fn load_shipping_method(&self, session_id: &str) -> Result<Option<String>, String> {
+        let sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        Ok(sessions.get(session_id).and_then(|s| s.shipping_method.clone()))
+    }
+
+    fn save_shipping_method(&self, session_id: &str, method: Option<&str>) -> Result<(), String> {
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        let session = sessions.entry(session_id.to_string()).or_default();
+        session.shipping_method = method.map(str::to_string);
+        session.last_seen = now_secs();
+        Ok(())
+    }
+
+    fn expire_idle(&self, ttl: Duration) -> Result<usize, String> {
+        let now = now_secs();
+        self.refresh_tokens.lock().map_err(|e| e.to_string())?.retain(|_, token| token.expires_at >= now);
+        self.revoked_access_tokens.lock().map_err(|e| e.to_string())?.retain(|_, expires_at| *expires_at >= now);
+
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        let users = self.users.lock().map_err(|e| e.to_string())?;
+        let cutoff = now.saturating_sub(ttl.as_secs());
+        let len_before = sessions.len();
+        sessions.retain(|id, session| session.last_seen >= cutoff || users.contains_key(id));
+
+        let mut inventory = self.inventory.lock().map_err(|e| e.to_string())?;
+        inventory.reservations.retain(|(session, _), _| sessions.contains_key(session));
+        Ok(len_before - sessions.len())
+    }
+}
+
+impl OrderStorage for MemoryStorage {
+    fn insert_order(&self, owner: &str, order: &Order) -> Result<(), String> {
+        let mut orders = self.orders.lock().map_err(|e| e.to_string())?;
+        orders.push((owner.to_string(), order.clone()));
+        Ok(())
+    }
+
+    fn orders_for(&self, owner: &str) -> Result<Vec<Order>, String> {
+        let orders = self.orders.lock().map_err(|e| e.to_string())?;
+        Ok(orders
+            .iter()
+            .rev()
+            .filter(|(o, _)| o == owner)
+            .map(|(_, order)| order.clone())
+            .collect())
+    }
+
+    fn all_orders(&self) -> Result<Vec<(String, Order)>, String> {
+        let orders = self.orders.lock().map_err(|e| e.to_string())?;
+        Ok(orders.iter().rev().cloned().collect())
+    }
+
+    fn get_order(&self, id: &str) -> Result<Option<(String, Order)>, String> {
+        let orders = self.orders.lock().map_err(|e| e.to_string())?;
+        Ok(orders.iter().find(|(_, order)| order.id == id).cloned())
+    }
+
+    fn update_order(&self, order: &Order, expected: OrderStatus) -> Result<bool, String> {
+        let mut orders = self.orders.lock().map_err(|e| e.to_string())?;
+        match orders.iter_mut().find(|(_, o)| o.id == order.id) {
+            Some((_, stored)) if stored.status != expected => Ok(false),
+            Some((_, stored)) => {
+                *stored = order.clone();
+                Ok(true)
+            }
+            None => Err(format!("Order {} does not exist", order.id)),
+        }
+    }
+}
+
+impl CouponStorage for MemoryStorage {
+    fn coupon_uses(&self, code: &str) -> Result<u32, String> {
+        let uses = self.coupon_uses.lock().map_err(|e| e.to_string())?;
+        Ok(uses.get(code).copied().unwrap_or(0))
+    }
+
+    fn record_coupon_use(&self, code: &str, max_uses: Option<u32>) -> Result<bool, String> {
+        let mut uses = self.coupon_uses.lock().map_err(|e| e.to_string())?;
+        let used = uses.entry(code.to_string()).or_insert(0);
+        if max_uses.is_some_and(|max| *used >= max) {
+            return Ok(false);
+        }
+        *used += 1;
+        Ok(true)
+    }
+
+    fn release_coupon_use(&self, code: &str) -> Result<(), String> {
+        let mut uses = self.coupon_uses.lock().map_err(|e| e.to_string())?;
+        if let Some(used) = uses.get_mut(code) {
+            *used = used.saturating_sub(1);
+        }
+        Ok(())
+    }
+}
+
+impl InventoryStorage for MemoryStorage {
+    fn init_stock(&self, product_id: u32, on_hand: u32) -> Result<(), String> {
+        let mut inventory = self.inventory.lock().map_err(|e| e.to_string())?;
+        inventory.on_hand.entry(product_id).or_insert(on_hand);
+        Ok(())
+    }
+
+    fn stock_level(&self, product_id: u32, now: u64) -> Result<Option<StockLevel>, String> {
+        let inventory = self.inventory.lock().map_err(|e| e.to_string())?;
+        Ok(inventory.on_hand.get(&product_id).map(|on_hand| {
+            let reserved = inventory.reserved(product_id, None, now);
+            StockLevel {
+                product_id,
+                on_hand: *on_hand,
+                reserved,
+                available: on_hand.saturating_sub(reserved),
+            }
+        }))
+    }
+
+    fn set_stock(&self, product_id: u32, on_hand: u32) -> Result<(), String> {
+        let mut inventory = self.inventory.lock().map_err(|e| e.to_string())?;
+        inventory.on_hand.insert(product_id, on_hand);
+        Ok(())
+    }
+
+    fn adjust_stock(&self, product_id: u32, delta: i64) -> Result<Option<u32>, String> {
+        let mut inventory = self.inventory.lock().map_err(|e| e.to_string())?;
+        let on_hand = inventory.on_hand.entry(product_id).or_insert(0);
+        let Ok(updated) = u32::try_from(*on_hand as i64 + delta) else {
+            return Ok(None);
+        };
+        *on_hand = updated;
+        Ok(Some(updated))
+    }
+
+    fn available(&self, session_id: &str, product_id: u32, now: u64) -> Result<u32, String> {
+        let inventory = self.inventory.lock().map_err(|e| e.to_string())?;
+        Ok(inventory.available(session_id, product_id, now))
+    }
+
+    fn reserve(&self, session_id: &str, product_id: u32, quantity: u32, expires_at: u64, now: u64) -> Result<bool, String> {
+        let mut inventory = self.inventory.lock().map_err(|e| e.to_string())?;
+        let key = (session_id.to_string(), product_id);
+        if quantity == 0 {
+            inventory.reservations.remove(&key);
+            return Ok(true);
+        }
+        if inventory.available(session_id, product_id, now) < quantity {
+            return Ok(false);
+        }
+        inventory.reservations.insert(key, (quantity, expires_at));
+        Ok(true)
+    }
+
+    fn commit_stock(&self, session_id: &str, items: &[(u32, u32)], now: u64) -> Result<Option<u32>, String> {
+        let mut inventory = self.inventory.lock().map_err(|e| e.to_string())?;
+        if let Some((product_id, _)) = items
+            .iter()
+            .find(|(product_id, quantity)| inventory.available(session_id, *product_id, now) < *quantity)
+        {
+            return Ok(Some(*product_id));
+        }
+
+        for (product_id, quantity) in items {
+            if let Some(on_hand) = inventory.on_hand.get_mut(product_id) {
+                *on_hand -= quantity;
+            }
+        }
+        inventory.reservations.retain(|(session, _), _| session != session_id);
+        Ok(None)
+    }
+}
+
+impl UserStorage for MemoryStorage {
+    fn insert_user(&self, user: &User) -> Result<bool, String> {
+        {
+            let mut users = self.users.lock().map_err(|e| e.to_string())?;
+            if users.values().any(|u| u.email == user.email) {
+                return Ok(false);
+            }
+            users.insert(user.id.clone(), user.clone());
+        }
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        sessions.entry(user.id.clone()).or_insert_with(|| SessionRow { last_seen: now_secs(), ..SessionRow::default() });
+        Ok(true)
+    }
+
+    fn user_by_email(&self, email: &str) -> Result<Option<User>, String> {
+        let users = self.users.lock().map_err(|e| e.to_string())?;
+        Ok(users.values().find(|u| u.email == email).cloned())
+    }
+
+    fn user_by_id(&self, id: &str) -> Result<Option<User>, String> {
+        let users = self.users.lock().map_err(|e| e.to_string())?;
+        Ok(users.get(id).cloned())
+    }
+
+    fn users(&self) -> Result<Vec<User>, String> {
+        let users = self.users.lock().map_err(|e| e.to_string())?;
+        let mut users: Vec<User> = users.values().cloned().collect();
+        users.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.email.cmp(&b.email)));
+        Ok(users)
+    }
+
+    fn set_role(&self, id: &str, role: Role) -> Result<bool, String> {
+        let mut users = self.users.lock().map_err(|e| e.to_string())?;
+        match users.get_mut(id) {
+            Some(user) => {
+                user.role = role;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn insert_refresh_token(&self, token_hash: &str, family: &str, user_id: &str, expires_at: u64) -> Result<(), String> {
+        let mut tokens = self.refresh_tokens.lock().map_err(|e| e.to_string())?;
+        tokens.insert(token_hash.to_string(), RefreshToken {
+            family: family.to_string(),
+            user_id: user_id.to_string(),
+            expires_at,
+            revoked: false,
+        });
+        Ok(())
+    }
+
+    fn rotate_refresh_token(&self, token_hash: &str, new_hash: &str, expires_at: u64, now: u64) -> Result<Option<(String, String)>, String> {
+        let mut tokens = self.refresh_tokens.lock().map_err(|e| e.to_string())?;
+        let Some(token) = tokens.get_mut(token_hash) else {
+            return Ok(None);
+        };
+        if token.revoked {
+            let family = token.family.clone();
+            tokens.values_mut().filter(|t| t.family == family).for_each(|t| t.revoked = true);
+            return Ok(None);
+        }
+        if token.expires_at < now {
+            return Ok(None);
+        }
+
+        token.revoked = true;
+        let (user_id, family) = (token.user_id.clone(), token.family.clone());
+        tokens.insert(new_hash.to_string(), RefreshToken {
+            family: family.clone(),
+            user_id: user_id.clone(),
+            expires_at,
+            revoked: false,
+        });
+        Ok(Some((user_id, family)))
+    }
+
+    fn revoke_refresh_family(&self, family: &str) -> Result<(), String> {
+        let mut tokens = self.refresh_tokens.lock().map_err(|e| e.to_string())?;
+        tokens.values_mut().filter(|t| t.family == family).for_each(|t| t.revoked = true);
+        Ok(())
+    }
+
+    fn revoke_access_token(&self, token_id: &str, expires_at: u64) -> Result<(), String> {
+        let mut revoked = self.revoked_access_tokens.lock().map_err(|e| e.to_string())?;
+        revoked.insert(token_id.to_string(), expires_at);
+        Ok(())
+    }
+
+    fn is_access_token_revoked(&self, token_id: &str) -> Result<bool, String> {
+        let revoked = self.revoked_access_tokens.lock().map_err(|e| e.to_string())?;
+        Ok(revoked.contains_key(token_id))
+    }
+}
+
+impl ProductStorage for MemoryStorage {
+    fn product_edits(&self) -> Result<Vec<(u32, Option<Product>)>, String> {
+        let edits = self.product_edits.lock().map_err(|e| e.to_string())?;
+        Ok(edits.iter().map(|(id, product)| (*id, product.clone())).collect())
+    }
+
+    fn save_product(&self, product: &Product) -> Result<(), String> {
+        let mut edits = self.product_edits.lock().map_err(|e| e.to_string())?;
+        edits.insert(product.id, Some(product.clone()));
+        Ok(())
+    }
+
+    fn delete_product(&self, id: u32) -> Result<(), String> {
+        let mut edits = self.product_edits.lock().map_err(|e| e.to_string())?;
+        edits.insert(id, None);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::conformance;
+
+    #[test]
+    fn passes_the_storage_round_trips() {
+        conformance::carts(&MemoryStorage::new());
+        conformance::orders(&MemoryStorage::new());
+        conformance::coupons(&MemoryStorage::new());
+        conformance::inventory(&MemoryStorage::new());
+        conformance::users_and_tokens(&MemoryStorage::new());
+        conformance::product_edits(&MemoryStorage::new());
+    }
+}
@@ -0,0 +1,371 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use shared::models::{Address, CartItem, Order, OrderStatus, Product, Role, StockLevel};
+use crate::auth::User;
+use crate::config::{StorageBackend, StorageConfig};
+
+mod memory;
+mod sqlite;
+
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+pub const DEFAULT_SQLITE_PATH: &str = "rusty-cart.db";
+
+// Persistence for sessions and their carts. An account's cart is kept under the account's id
+// and, unlike a guest session's, never expires.
+pub trait CartStorage: Send + Sync {
+    fn create_session(&self, id: &str) -> Result<(), String>;
+
+    // Marks the guest session as used now; returns false if it does not exist. Account ids are
+    // never guest sessions, even though their carts are stored the same way.
+    fn touch_session(&self, id: &str) -> Result<bool, String>;
+
+    // Whether the guest session exists, without counting this as a use
+    fn session_exists(&self, id: &str) -> Result<bool, String>;
+
+    fn load_cart(&self, session_id: &str) -> Result<Vec<CartItem>, String>;
+
+    fn save_cart(&self, session_id: &str, cart: &[CartItem]) -> Result<(), String>;
+
+    // Code of the coupon attached to the session's cart
+    fn load_coupon(&self, session_id: &str) -> Result<Option<String>, String>;
+
+    fn save_coupon(&self, session_id: &str, code: Option<&str>) -> Result<(), String>;
+
+    // Where the session's cart should be delivered
+    fn load_address(&self, session_id: &str) -> Result<Option<Address>, String>;
+
+    fn save_address(&self, session_id: &str, address: Option<&Address>) -> Result<(), String>;
+
+    // Id of the shipping method chosen for the session's cart
+    fn load_shipping_method(&self, session_id: &str) -> Result<Option<String>, String>;
+
+    fn save_shipping_method(&self, session_id: &str, method: Option<&str>) -> Result<(), String>;
+
+    // Removes guest sessions (and their carts) not used for longer than `ttl`, returning how many
+    // were removed. Expired refresh tokens and revocation list entries are dropped at the same time.
+    fn expire_idle(&self, ttl: Duration) -> Result<usize, String>;
+}
+
+// Persistence for orders. The owner is whoever placed the order: the account id, or the session id
+// of a guest.
+pub trait OrderStorage: Send + Sync {
+    fn insert_order(&self, owner: &str, order: &Order) -> Result<(), String>;
+
+    // Orders placed by `owner`, newest first
+    fn orders_for(&self, owner: &str) -> Result<Vec<Order>, String>;
+
+    // Every order with its owner, newest first
+    fn all_orders(&self) -> Result<Vec<(String, Order)>, String>;
+
+    // The order with the given id together with its owner
+    fn get_order(&self, id: &str) -> Result<Option<(String, Order)>, String>;
+
+    // Replaces a previously inserted order, e.g. after a status change, as long as the stored
+    // order still has status `expected`. Returns false, leaving it untouched, if it doesn't.
+    fn update_order(&self, order: &Order, expected: OrderStatus) -> Result<bool, String>;
+}
+
+// How many orders have used each coupon
+pub trait CouponStorage: Send + Sync {
+    fn coupon_uses(&self, code: &str) -> Result<u32, String>;
+
+    // Counts one more use unless that would exceed `max_uses`; returns whether it was counted
+    fn record_coupon_use(&self, code: &str, max_uses: Option<u32>) -> Result<bool, String>;
+
+    // Takes back a use recorded for an order that couldn't be placed after all
+    fn release_coupon_use(&self, code: &str) -> Result<(), String>;
+}
+
+// Stock levels and the quantities held by carts. A reservation belongs to a session and
+// only counts until `expires_at` (seconds since the Unix epoch).
+pub trait InventoryStorage: Send + Sync {
+    // Gives the product a stock level of `on_hand` unless it already has one
+    fn init_stock(&self, product_id: u32, on_hand: u32) -> Result<(), String>;
+
+    fn stock_level(&self, product_id: u32, now: u64) -> Result<Option<StockLevel>, String>;
+
+    fn set_stock(&self, product_id: u32, on_hand: u32) -> Result<(), String>;
+
+    // Changes the stock by `delta`; returns the new level, or None if it would drop below zero
+    fn adjust_stock(&self, product_id: u32, delta: i64) -> Result<Option<u32>, String>;
+
+    // How much of the product the session could hold, i.e. stock not reserved by other sessions
+    fn available(&self, session_id: &str, product_id: u32, now: u64) -> Result<u32, String>;
+
+    // Holds `quantity` for the session, replacing its earlier reservation of the product
+    // (0 releases it); returns false without changing anything if not enough is available
+    fn reserve(&self, session_id: &str, product_id: u32, quantity: u32, expires_at: u64, now: u64) -> Result<bool, String>;
+
+    // Takes (product id, quantity) pairs out of stock and drops the session's reservations.
+    // If any product is short, nothing changes and its id is returned.
+    fn commit_stock(&self, session_id: &str, items: &[(u32, u32)], now: u64) -> Result<Option<u32>, String>;
+}
+
+// Accounts, their refresh tokens and the revocation list of access tokens. Refresh tokens are
+// only stored as hashes and belong to a family: the login session they were issued for.
+pub trait UserStorage: Send + Sync {
+    // Stores a new account together with its empty cart; returns false if the email is taken
+    fn insert_user(&self, user: &User) -> Result<bool, String>;
+
+    fn user_by_email(&self, email: &str) -> Result<Option<User>, String>;
+
+    fn user_by_id(&self, id: &str) -> Result<Option<User>, String>;
+
+    // Every account, oldest first
+    fn users(&self) -> Result<Vec<User>, String>;
+
+    // Returns false if there is no such account
+    fn set_role(&self, id: &str, role: Role) -> Result<bool, String>;
+
+    fn insert_refresh_token(&self, token_hash: &str, family: &str, user_id: &str, expires_at: u64) -> Result<(), String>;
+
+    // Replaces a refresh token with `new_hash` in the same family and returns (user id, family).
+    // None if the token is unknown, expired or revoked. A token that was already replaced has
+    // probably leaked, so presenting it again also revokes the rest of its family.
+    fn rotate_refresh_token(&self, token_hash: &str, new_hash: &str, expires_at: u64, now: u64) -> Result<Option<(String, String)>, String>;
+
+    // Revokes every refresh token of the family, e.g. on logout
+    fn revoke_refresh_family(&self, family: &str) -> Result<(), String>;
+
+    // Puts an access token on the revocation list until it would have expired anyway
+    fn revoke_access_token(&self, token_id: &str, expires_at: u64) -> Result<(), String>;
+
+    fn is_access_token_revoked(&self, token_id: &str) -> Result<bool, String>;
+}
+
+// Products created, changed or deleted through the admin API. They are laid over the products of
+// the catalog source: an edit replaces the source's product with the same id, and an edit without
+// a product hides it.
+pub trait ProductStorage: Send + Sync {
+    // (product id, product or None if deleted), by id
+    fn product_edits(&self) -> Result<Vec<(u32, Option<Product>)>, String>;
+
+    fn save_product(&self, product: &Product) -> Result<(), String>;
+
+    fn delete_product(&self, id: u32) -> Result<(), String>;
+}
+
+// Everything the backend persists
+pub trait Storage: CartStorage + OrderStorage + CouponStorage + InventoryStorage + UserStorage + ProductStorage {}
+
+impl<T: CartStorage + OrderStorage + CouponStorage + InventoryStorage + UserStorage + ProductStorage> Storage for T {}
+
+// Seconds since the Unix epoch, used for timestamps that have to survive restarts
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Opens the storage chosen in the configuration
+pub fn from_config(config: &StorageConfig) -> Result<Arc<dyn Storage>, String> {
+    match config.backend {
+        StorageBackend::Sqlite => {
+            let path = config.path
+                .to_str()
+                .ok_or_else(|| format!("SQLite path {} is not valid UTF-8", config.path.display()))?;
+            Ok(Arc::new(SqliteStorage::open(path)?))
+        }
+        StorageBackend::Memory => Ok(Arc::new(MemoryStorage::new())),
+    }
+}
+
+// Round trips every backend has to pass, run by the tests next to each implementation
+#[cfg(test)]
+pub(crate) mod conformance {
+    use shared::models::OrderLine;
+    use shared::money::Money;
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+    const LATER: u64 = NOW + 3600;
+
+    fn product(id: u32) -> Product {
+        Product {
+            id,
+            title: format!("Product {}", id),
+            price: Money::new(1990, "SEK"),
+            description: "Soft".to_string(),
+            category: "toys".to_string(),
+            image: String::new(),
+            weight: Some(250),
+        }
+    }
+
+    fn order(id: &str, created_at: u64) -> Order {
+        Order {
+            id: id.to_string(),
+            lines: vec![OrderLine {
+                product_id: 1,
+                title: "Product 1".to_string(),
+                unit_price: Money::new(1990, "SEK"),
+                quantity: 2,
+                line_total: Money::new(3980, "SEK"),
+                discount: None,
+            }],
+            coupon: Some("WELCOME10".to_string()),
+            discount: None,
+            tax: Some(Money::new(796, "SEK")),
+            address: None,
+            shipping: None,
+            total: Money::new(3980, "SEK"),
+            status: OrderStatus::Pending,
+            payment_id: None,
+            created_at,
+            updated_at: created_at,
+        }
+    }
+
+    pub fn carts(storage: &dyn Storage) {
+        assert!(!storage.touch_session("s1").unwrap());
+        assert!(!storage.session_exists("s1").unwrap());
+        storage.create_session("s1").unwrap();
+        assert!(storage.touch_session("s1").unwrap());
+        assert!(storage.session_exists("s1").unwrap());
+        assert!(storage.load_cart("s1").unwrap().is_empty());
+
+        let cart = vec![CartItem { product: product(2), quantity: 3 }, CartItem { product: product(1), quantity: 1 }];
+        storage.save_cart("s1", &cart).unwrap();
+        assert_eq!(storage.load_cart("s1").unwrap(), cart);
+        storage.save_cart("s1", &cart[1..]).unwrap();
+        assert_eq!(storage.load_cart("s1").unwrap(), &cart[1..]);
+
+        let address = Address {
+            name: "Ferris".to_string(),
+            street: "Crab Lane 1".to_string(),
+            postal_code: "111 22".to_string(),
+            city: "Stockholm".to_string(),
+            country: "SE".to_string(),
+        };
+        storage.save_coupon("s1", Some("WELCOME10")).unwrap();
+        storage.save_address("s1", Some(&address)).unwrap();
+        storage.save_shipping_method("s1", Some("express")).unwrap();
+        assert_eq!(storage.load_coupon("s1").unwrap().as_deref(), Some("WELCOME10"));
+        assert_eq!(storage.load_address("s1").unwrap(), Some(address));
+        assert_eq!(storage.load_shipping_method("s1").unwrap().as_deref(), Some("express"));
+
+        storage.save_coupon("s1", None).unwrap();
+        storage.save_address("s1", None).unwrap();
+        assert_eq!(storage.load_coupon("s1").unwrap(), None);
+        assert_eq!(storage.load_address("s1").unwrap(), None);
+        // Other sessions are not affected
+        assert!(storage.load_cart("s2").unwrap().is_empty());
+        assert_eq!(storage.load_shipping_method("s2").unwrap(), None);
+
+        // An account's cart is not a guest session
+        let user = User::new("ferris@example.com".to_string(), String::new(), Role::Customer);
+        storage.insert_user(&user).unwrap();
+        storage.save_cart(&user.id, &cart).unwrap();
+        assert!(!storage.touch_session(&user.id).unwrap());
+        assert!(!storage.session_exists(&user.id).unwrap());
+        assert_eq!(storage.load_cart(&user.id).unwrap(), cart);
+    }
+
+    pub fn orders(storage: &dyn Storage) {
+        storage.insert_order("alice", &order("o1", NOW)).unwrap();
+        storage.insert_order("bob", &order("o2", NOW + 5)).unwrap();
+        storage.insert_order("alice", &order("o3", NOW + 10)).unwrap();
+
+        let ids = |orders: Vec<Order>| orders.into_iter().map(|o| o.id).collect::<Vec<_>>();
+        assert_eq!(ids(storage.orders_for("alice").unwrap()), ["o3", "o1"]);
+        assert_eq!(ids(storage.all_orders().unwrap().into_iter().map(|(_, o)| o).collect()), ["o3", "o2", "o1"]);
+
+        let mut paid = order("o1", NOW);
+        paid.status = OrderStatus::Paid;
+        paid.payment_id = Some("pay_1".to_string());
+        assert!(storage.update_order(&paid, OrderStatus::Pending).unwrap());
+        assert_eq!(storage.get_order("o1").unwrap(), Some(("alice".to_string(), paid.clone())));
+        // The order has moved on, so a change based on the pending order is refused
+        let mut cancelled = order("o1", NOW);
+        cancelled.status = OrderStatus::Cancelled;
+        assert!(!storage.update_order(&cancelled, OrderStatus::Pending).unwrap());
+        assert_eq!(storage.get_order("o1").unwrap(), Some(("alice".to_string(), paid)));
+        assert!(storage.update_order(&order("missing", NOW), OrderStatus::Pending).is_err());
+        assert_eq!(storage.get_order("missing").unwrap(), None);
+    }
+
+    pub fn coupons(storage: &dyn Storage) {
+        assert_eq!(storage.coupon_uses("WELCOME10").unwrap(), 0);
+        assert!(storage.record_coupon_use("WELCOME10", Some(2)).unwrap());
+        assert!(storage.record_coupon_use("WELCOME10", Some(2)).unwrap());
+        assert!(!storage.record_coupon_use("WELCOME10", Some(2)).unwrap());
+        assert!(storage.record_coupon_use("UNLIMITED", None).unwrap());
+        assert_eq!(storage.coupon_uses("WELCOME10").unwrap(), 2);
+
+        storage.release_coupon_use("WELCOME10").unwrap();
+        assert_eq!(storage.coupon_uses("WELCOME10").unwrap(), 1);
+        assert!(storage.record_coupon_use("WELCOME10", Some(2)).unwrap());
+        // Never below zero
+        storage.release_coupon_use("UNUSED").unwrap();
+        assert_eq!(storage.coupon_uses("UNUSED").unwrap(), 0);
+    }
+
+    pub fn inventory(storage: &dyn Storage) {
+        storage.create_session("a").unwrap();
+        storage.create_session("b").unwrap();
+        storage.init_stock(1, 10).unwrap();
+        // An existing stock level is kept
+        storage.init_stock(1, 99).unwrap();
+
+        assert!(storage.reserve("a", 1, 4, LATER, NOW).unwrap());
+        assert_eq!(storage.available("b", 1, NOW).unwrap(), 6);
+        assert!(!storage.reserve("b", 1, 7, LATER, NOW).unwrap());
+        assert_eq!(storage.stock_level(1, NOW).unwrap(), Some(StockLevel { product_id: 1, on_hand: 10, reserved: 4, available: 6 }));
+        // Expired reservations don't hold stock
+        assert_eq!(storage.available("b", 1, LATER + 1).unwrap(), 10);
+
+        assert_eq!(storage.commit_stock("a", &[(1, 4)], NOW).unwrap(), None);
+        assert_eq!(storage.stock_level(1, NOW).unwrap(), Some(StockLevel { product_id: 1, on_hand: 6, reserved: 0, available: 6 }));
+        assert_eq!(storage.commit_stock("b", &[(1, 7)], NOW).unwrap(), Some(1));
+
+        assert_eq!(storage.adjust_stock(1, -7).unwrap(), None);
+        assert_eq!(storage.adjust_stock(1, -6).unwrap(), Some(0));
+        storage.set_stock(1, 3).unwrap();
+        assert_eq!(storage.stock_level(1, NOW).unwrap().map(|level| level.on_hand), Some(3));
+        assert_eq!(storage.stock_level(2, NOW).unwrap(), None);
+    }
+
+    pub fn users_and_tokens(storage: &dyn Storage) {
+        let user = User::new("ferris@example.com".to_string(), "hash".to_string(), Role::Customer);
+        assert!(storage.insert_user(&user).unwrap());
+        let taken = User::new("ferris@example.com".to_string(), "other".to_string(), Role::Customer);
+        assert!(!storage.insert_user(&taken).unwrap());
+
+        let loaded = storage.user_by_email("ferris@example.com").unwrap().unwrap();
+        assert_eq!((loaded.id.as_str(), loaded.password_hash.as_str(), loaded.role), (user.id.as_str(), "hash", Role::Customer));
+        assert!(storage.set_role(&user.id, Role::Staff).unwrap());
+        assert!(!storage.set_role("missing", Role::Staff).unwrap());
+        assert_eq!(storage.user_by_id(&user.id).unwrap().map(|u| u.role), Some(Role::Staff));
+        assert_eq!(storage.users().unwrap().len(), 1);
+
+        storage.insert_refresh_token("t1", "family", &user.id, LATER).unwrap();
+        let rotated = storage.rotate_refresh_token("t1", "t2", LATER, NOW).unwrap();
+        assert_eq!(rotated, Some((user.id.clone(), "family".to_string())));
+        // Presenting the replaced token again ends the whole family
+        assert_eq!(storage.rotate_refresh_token("t1", "t3", LATER, NOW).unwrap(), None);
+        assert_eq!(storage.rotate_refresh_token("t2", "t4", LATER, NOW).unwrap(), None);
+
+        storage.insert_refresh_token("t5", "other", &user.id, NOW - 1).unwrap();
+        assert_eq!(storage.rotate_refresh_token("t5", "t6", LATER, NOW).unwrap(), None);
+        storage.insert_refresh_token("t7", "logout", &user.id, LATER).unwrap();
+        storage.revoke_refresh_family("logout").unwrap();
+        assert_eq!(storage.rotate_refresh_token("t7", "t8", LATER, NOW).unwrap(), None);
+
+        assert!(!storage.is_access_token_revoked("jti").unwrap());
+        storage.revoke_access_token("jti", LATER).unwrap();
+        assert!(storage.is_access_token_revoked("jti").unwrap());
+    }
+
+    pub fn product_edits(storage: &dyn Storage) {
+        storage.save_product(&product(3)).unwrap();
+        storage.save_product(&product(1)).unwrap();
+        storage.delete_product(2).unwrap();
+        let mut changed = product(3);
+        changed.title = "Renamed".to_string();
+        storage.save_product(&changed).unwrap();
+        assert_eq!(storage.product_edits().unwrap(), [(1, Some(product(1))), (2, None), (3, Some(changed))]);
+    }
+}
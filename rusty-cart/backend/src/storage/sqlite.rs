@@ -0,0 +1,774 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use shared::models::{Address, CartItem, Order, OrderStatus, Product, Role, StockLevel};
+use crate::auth::User;
+use super::{now_secs, CartStorage, CouponStorage, InventoryStorage, OrderStorage, ProductStorage, UserStorage};
+
+// Schema migrations, applied in order. The index of the last applied one is kept in `PRAGMA user_version`,
+// so new migrations must only ever be appended.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE sessions (
+        id TEXT PRIMARY KEY,
+        last_seen INTEGER NOT NULL
+    );
+    CREATE TABLE cart_items (
+        session_id TEXT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        product TEXT NOT NULL,
+        quantity INTEGER NOT NULL,
+        PRIMARY KEY (session_id, position)
+    );",
+    "CREATE TABLE orders (
+        id TEXT PRIMARY KEY,
+        owner TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        body TEXT NOT NULL
+    );
+    CREATE INDEX orders_owner ON orders (owner, created_at);",
+    "ALTER TABLE sessions ADD COLUMN coupon TEXT;
+    CREATE TABLE coupon_uses (
+        code TEXT PRIMARY KEY,
+        uses INTEGER NOT NULL
+    );",
+    "ALTER TABLE sessions ADD COLUMN address TEXT;
+    ALTER TABLE sessions ADD COLUMN shipping_method TEXT;",
+    "CREATE TABLE stock (
+        product_id INTEGER PRIMARY KEY,
+        on_hand INTEGER NOT NULL
+    );
+    CREATE TABLE reservations (
+        session_id TEXT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+        product_id INTEGER NOT NULL,
+        quantity INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL,
+        PRIMARY KEY (session_id, product_id)
+    );",
+    "CREATE TABLE users (
+        id TEXT PRIMARY KEY,
+        email TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE refresh_tokens (
+        token_hash TEXT PRIMARY KEY,
+        family TEXT NOT NULL,
+        user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        expires_at INTEGER NOT NULL,
+        revoked INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX refresh_tokens_family ON refresh_tokens (family);
+    CREATE TABLE revoked_access_tokens (
+        token_id TEXT PRIMARY KEY,
+        expires_at INTEGER NOT NULL
+    );",
+    "ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'customer';
+    CREATE TABLE product_edits (
+        id INTEGER PRIMARY KEY,
+        -- NULL when the product was deleted
+        product TEXT
+    );",
+];
+
+// Stores sessions and carts in an SQLite database so they survive restarts
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let mut conn = Connection::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;").map_err(|e| e.to_string())?;
+        migrate(&mut conn)?;
+        Ok(SqliteStorage { conn: Mutex::new(conn) })
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), String> {
+    let version: usize = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        tx.execute_batch(migration).map_err(|e| format!("Migration {} failed: {}", i + 1, e))?;
+        tx.pragma_update(None, "user_version", i + 1).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Stock of the product not held by other sessions' unexpired reservations
+fn available(tx: &Transaction, session_id: &str, product_id: u32, now: u64) -> Result<u32, String> {
+    tx.query_row(
+        "SELECT COALESCE((SELECT on_hand FROM stock WHERE product_id = ?2), 0)
+              - COALESCE((SELECT SUM(quantity) FROM reservations
+                          WHERE product_id = ?2 AND session_id != ?1 AND expires_at >= ?3), 0)",
+        params![session_id, product_id, now],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|available| available.max(0) as u32)
+    .map_err(|e| e.to_string())
+}
+
+impl CartStorage for SqliteStorage {
+    fn create_session(&self, id: &str) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT OR REPLACE INTO sessions (id, last_seen) VALUES (?1, ?2)",
+            params![id, now_secs()],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn touch_session(&self, id: &str) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let updated = conn.execute(
+            "UPDATE sessions SET last_seen = ?2 WHERE id = ?1 AND id NOT IN (SELECT id FROM users)",
+            params![id, now_secs()],
+        ).map_err(|e| e.to_string())?;
+        Ok(updated > 0)
+    }
+
+    fn session_exists(&self, id: &str) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.query_row(
+            "SELECT 1 FROM sessions WHERE id = ?1 AND id NOT IN (SELECT id FROM users)",
+            params![id],
+            |_| Ok(()),
+        )
+            .optional()
+            .map(|row| row.is_some())
+            .map_err(|e| e.to_string())
+    }
+
+    fn load_cart(&self, session_id: &str) -> Result<Vec<CartItem>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn
+            .prepare("SELECT product, quantity FROM cart_items WHERE session_id = ?1 ORDER BY position")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params![session_id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?))
+            })
+            .map_err(|e| e.to_string())?;
+
+        let mut cart = Vec::new();
+        for row in rows {
+            let (product, quantity) = row.map_err(|e| e.to_string())?;
+            let product = serde_json::from_str(&product).map_err(|e| format!("Corrupt cart item: {}", e))?;
+            cart.push(CartItem { product, quantity });
+        }
+        Ok(cart)
+    }
+
+    fn save_cart(&self, session_id: &str, cart: &[CartItem]) -> Result<(), String> {
+        let mut conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+
+        tx.execute(
+            "INSERT INTO sessions (id, last_seen) VALUES (?1, ?2)
+             ON CONFLICT(id) DO UPDATE SET last_seen = excluded.last_seen",
+            params![session_id, now_secs()],
+        ).map_err(|e| e.to_string())?;
+
+        tx.execute("DELETE FROM cart_items WHERE session_id = ?1", params![session_id])
+            .map_err(|e| e.to_string())?;
+        for (position, item) in cart.iter().enumerate() {
+            let product = serde_json::to_string(&item.product).map_err(|e| e.to_string())?;
+            tx.execute(
+                "INSERT INTO cart_items (session_id, position, product, quantity) VALUES (?1, ?2, ?3, ?4)",
+                params![session_id, position, product, item.quantity],
+            ).map_err(|e| e.to_string())?;
+        }
+
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    fn load_coupon(&self, session_id: &str) -> Result<Option<String>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.query_row("SELECT coupon FROM sessions WHERE id = ?1", params![session_id], |row| row.get(0))
+            .optional()
+            .map(Option::flatten)
+            .map_err(|e| e.to_string())
+    }
+
+    fn save_coupon(&self, session_id: &str, code: Option<&str>) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT INTO sessions (id, last_seen, coupon) VALUES (?1, ?2, ?3)
+             ON CONFLICT(id) DO UPDATE SET last_seen = excluded.last_seen, coupon = excluded.coupon",
+            params![session_id, now_secs(), code],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn load_address(&self, session_id: &str) -> Result<Option<Address>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let address: Option<String> = conn
+            .query_row("SELECT address FROM sessions WHERE id = ?1", params![session_id], |row| row.get(0))
+            .optional()
+            .map(Option::flatten)
+            .map_err(|e| e.to_string())?;
+        address
+            .map(|address| serde_json::from_str(&address).map_err(|e| format!("Corrupt address: {}", e)))
+            .transpose()
+    }
+
+    fn save_address(&self, session_id: &str, address: Option<&Address>) -> Result<(), String> {
+        let address = address
+            .map(|address| serde_json::to_string(address).map_err(|e| e.to_string()))
+            .transpose()?;
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT INTO sessions (id, last_seen, address) VALUES (?1, ?2, ?3)
+             ON CONFLICT(id) DO UPDATE SET last_seen = excluded.last_seen, address = excluded.address",
+            params![session_id, now_secs(), address],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn load_shipping_method(&self, session_id: &str) -> Result<Option<String>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.query_row("SELECT shipping_method FROM sessions WHERE id = ?1", params![session_id], |row| row.get(0))
+            .optional()
+            .map(Option::flatten)
+            .map_err(|e| e.to_string())
+    }
+
+    fn save_shipping_method(&self, session_id: &str, method: Option<&str>) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT INTO sessions (id, last_seen, shipping_method) VALUES (?1, ?2, ?3)
+             ON CONFLICT(id) DO UPDATE SET last_seen = excluded.last_seen, shipping_method = excluded.shipping_method",
+            params![session_id, now_secs(), method],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn expire_idle(&self, ttl: Duration) -> Result<usize, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let now = now_secs();
+        let cutoff = now.saturating_sub(ttl.as_secs());
+        conn.execute("DELETE FROM refresh_tokens WHERE expires_at < ?1", params![now])
+            .map_err(|e| e.to_string())?;
+        conn.execute("DELETE FROM revoked_access_tokens WHERE expires_at < ?1", params![now])
+            .map_err(|e| e.to_string())?;
+        conn.execute(
+            "DELETE FROM sessions WHERE last_seen < ?1 AND id NOT IN (SELECT id FROM users)",
+            params![cutoff],
+        ).map_err(|e| e.to_string())
+    }
+}
+
+impl OrderStorage for SqliteStorage {
+    fn insert_order(&self, owner: &str, order: &Order) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let body = serde_json::to_string(order).map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT INTO orders (id, owner, created_at, body) VALUES (?1, ?2, ?3, ?4)",
+            params![order.id, owner, order.created_at, body],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn orders_for(&self, owner: &str) -> Result<Vec<Order>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn
+            .prepare("SELECT body FROM orders WHERE owner = ?1 ORDER BY created_at DESC, rowid DESC")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params![owner], |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?;
+
+        let mut orders = Vec::new();
+        for row in rows {
+            let body = row.map_err(|e| e.to_string())?;
+            orders.push(serde_json::from_str(&body).map_err(|e| format!("Corrupt order: {}", e))?);
+        }
+        Ok(orders)
+    }
+
+    fn all_orders(&self) -> Result<Vec<(String, Order)>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn
+            .prepare("SELECT owner, body FROM orders ORDER BY created_at DESC, rowid DESC")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(|e| e.to_string())?;
+
+        let mut orders = Vec::new();
+        for row in rows {
+            let (owner, body) = row.map_err(|e| e.to_string())?;
+            let order = serde_json::from_str(&body).map_err(|e| format!("Corrupt order: {}", e))?;
+            orders.push((owner, order));
+        }
+        Ok(orders)
+    }
+
+    fn get_order(&self, id: &str) -> Result<Option<(String, Order)>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let row = conn
+            .query_row(
+                "SELECT owner, body FROM orders WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+
+        match row {
+            Some((owner, body)) => {
+                let order = serde_json::from_str(&body).map_err(|e| format!("Corrupt order: {}", e))?;
+                Ok(Some((owner, order)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn update_order(&self, order: &Order, expected: OrderStatus) -> Result<bool, String> {
+        let mut conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        let stored: Option<String> = tx
+            .query_row("SELECT body FROM orders WHERE id = ?1", params![order.id], |row| row.get(0))
+            .optional()
+            .map_err(|e| e.to_string())?;
+        let Some(stored) = stored else {
+            return Err(format!("Order {} does not exist", order.id));
+        };
+        let stored: Order = serde_json::from_str(&stored).map_err(|e| format!("Corrupt order: {}", e))?;
+        if stored.status != expected {
+            return Ok(false);
+        }
+
+        let body = serde_json::to_string(order).map_err(|e| e.to_string())?;
+        tx.execute("UPDATE orders SET body = ?2 WHERE id = ?1", params![order.id, body])
+            .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+}
+
+impl CouponStorage for SqliteStorage {
+    fn coupon_uses(&self, code: &str) -> Result<u32, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.query_row("SELECT uses FROM coupon_uses WHERE code = ?1", params![code], |row| row.get(0))
+            .optional()
+            .map(|uses| uses.unwrap_or(0))
+            .map_err(|e| e.to_string())
+    }
+
+    fn record_coupon_use(&self, code: &str, max_uses: Option<u32>) -> Result<bool, String> {
+        if max_uses == Some(0) {
+            return Ok(false);
+        }
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let counted = conn.execute(
+            "INSERT INTO coupon_uses (code, uses) VALUES (?1, 1)
+             ON CONFLICT(code) DO UPDATE SET uses = uses + 1
+             WHERE ?2 IS NULL OR uses < ?2",
+            params![code, max_uses],
+        ).map_err(|e| e.to_string())?;
+        Ok(counted > 0)
+    }
+
+    fn release_coupon_use(&self, code: &str) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute("UPDATE coupon_uses SET uses = uses - 1 WHERE code = ?1 AND uses > 0", params![code])
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+impl InventoryStorage for SqliteStorage {
+    fn init_stock(&self, product_id: u32, on_hand: u32) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT OR IGNORE INTO stock (product_id, on_hand) VALUES (?1, ?2)",
+            params![product_id, on_hand],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn stock_level(&self, product_id: u32, now: u64) -> Result<Option<StockLevel>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.query_row(
+            "SELECT on_hand, COALESCE((SELECT SUM(quantity) FROM reservations
+                                       WHERE product_id = ?1 AND expires_at >= ?2), 0)
+             FROM stock WHERE product_id = ?1",
+            params![product_id, now],
+            |row| {
+                let on_hand: u32 = row.get(0)?;
+                let reserved: u32 = row.get(1)?;
+                Ok(StockLevel {
+                    product_id,
+                    on_hand,
+                    reserved,
+                    available: on_hand.saturating_sub(reserved),
+                })
+            },
+        )
+        .optional()
+        .map_err(|e| e.to_string())
+    }
+
+    fn set_stock(&self, product_id: u32, on_hand: u32) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT INTO stock (product_id, on_hand) VALUES (?1, ?2)
+             ON CONFLICT(product_id) DO UPDATE SET on_hand = excluded.on_hand",
+            params![product_id, on_hand],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn adjust_stock(&self, product_id: u32, delta: i64) -> Result<Option<u32>, String> {
+        let mut conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        let on_hand: i64 = tx
+            .query_row("SELECT on_hand FROM stock WHERE product_id = ?1", params![product_id], |row| row.get(0))
+            .optional()
+            .map_err(|e| e.to_string())?
+            .unwrap_or(0);
+        let Ok(updated) = u32::try_from(on_hand + delta) else {
+            return Ok(None);
+        };
+        tx.execute(
+            "INSERT INTO stock (product_id, on_hand) VALUES (?1, ?2)
+             ON CONFLICT(product_id) DO UPDATE SET on_hand = excluded.on_hand",
+            params![product_id, updated],
+        ).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(Some(updated))
+    }
+
+    fn available(&self, session_id: &str, product_id: u32, now: u64) -> Result<u32, String> {
+        let mut conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        available(&tx, session_id, product_id, now)
+    }
+
+    fn reserve(&self, session_id: &str, product_id: u32, quantity: u32, expires_at: u64, now: u64) -> Result<bool, String> {
+        let mut conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        if quantity == 0 {
+            tx.execute(
+                "DELETE FROM reservations WHERE session_id = ?1 AND product_id = ?2",
+                params![session_id, product_id],
+            ).map_err(|e| e.to_string())?;
+        } else {
+            if available(&tx, session_id, product_id, now)? < quantity {
+                return Ok(false);
+            }
+            tx.execute(
+                "INSERT INTO reservations (session_id, product_id, quantity, expires_at) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(session_id, product_id) DO UPDATE SET quantity = excluded.quantity, expires_at = excluded.expires_at",
+                params![session_id, product_id, quantity, expires_at],
+            ).map_err(|e| e.to_string())?;
+        }
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+
+    fn commit_stock(&self, session_id: &str, items: &[(u32, u32)], now: u64) -> Result<Option<u32>, String> {
+        let mut conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        for (product_id, quantity) in items {
+            if available(&tx, session_id, *product_id, now)? < *quantity {
+                return Ok(Some(*product_id));
+            }
+            tx.execute(
+                "UPDATE stock SET on_hand = on_hand - ?2 WHERE product_id = ?1",
+                params![product_id, quantity],
+            ).map_err(|e| e.to_string())?;
+        }
+        tx.execute("DELETE FROM reservations WHERE session_id = ?1", params![session_id])
+            .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(None)
+    }
+}
+
+const USER_COLUMNS: &str = "id, email, password_hash, role, created_at";
+
+fn user_from_row(row: &rusqlite::Row) -> rusqlite::Result<User> {
+    let role: String = row.get(3)?;
+    Ok(User {
+        id: row.get(0)?,
+        email: row.get(1)?,
+        password_hash: row.get(2)?,
+        role: Role::parse(&role).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, format!("Unknown role {}", role).into())
+        })?,
+        created_at: row.get(4)?,
+    })
+}
+
+impl UserStorage for SqliteStorage {
+    fn insert_user(&self, user: &User) -> Result<bool, String> {
+        let mut conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        let inserted = tx.execute(
+            "INSERT INTO users (id, email, password_hash, role, created_at) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(email) DO NOTHING",
+            params![user.id, user.email, user.password_hash, user.role.as_str(), user.created_at],
+        ).map_err(|e| e.to_string())?;
+        if inserted == 0 {
+            return Ok(false);
+        }
+        tx.execute(
+            "INSERT OR IGNORE INTO sessions (id, last_seen) VALUES (?1, ?2)",
+            params![user.id, now_secs()],
+        ).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+
+    fn user_by_email(&self, email: &str) -> Result<Option<User>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.query_row(
+            &format!("SELECT {} FROM users WHERE email = ?1", USER_COLUMNS),
+            params![email],
+            user_from_row,
+        )
+        .optional()
+        .map_err(|e| e.to_string())
+    }
+
+    fn user_by_id(&self, id: &str) -> Result<Option<User>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.query_row(
+            &format!("SELECT {} FROM users WHERE id = ?1", USER_COLUMNS),
+            params![id],
+            user_from_row,
+        )
+        .optional()
+        .map_err(|e| e.to_string())
+    }
+
+    fn users(&self) -> Result<Vec<User>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn
+            .prepare(&format!("SELECT {} FROM users ORDER BY created_at, rowid", USER_COLUMNS))
+            .map_err(|e| e.to_string())?;
+        let rows = stmt.query_map([], user_from_row).map_err(|e| e.to_string())?;
+
+        let mut users = Vec::new();
+        for row in rows {
+            users.push(row.map_err(|e| e.to_string())?);
+        }
+        Ok(users)
+    }
+
+    fn set_role(&self, id: &str, role: Role) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute("UPDATE users SET role = ?2 WHERE id = ?1", params![id, role.as_str()])
+            .map(|updated| updated > 0)
+            .map_err(|e| e.to_string())
+    }
+
+    fn insert_refresh_token(&self, token_hash: &str, family: &str, user_id: &str, expires_at: u64) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT INTO refresh_tokens (token_hash, family, user_id, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            params![token_hash, family, user_id, expires_at],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn rotate_refresh_token(&self, token_hash: &str, new_hash: &str, expires_at: u64, now: u64) -> Result<Option<(String, String)>, String> {
+        let mut conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        let row = tx
+            .query_row(
+                "SELECT family, user_id, expires_at, revoked FROM refresh_tokens WHERE token_hash = ?1",
+                params![token_hash],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, u64>(2)?, row.get::<_, bool>(3)?)),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+
+        let result = match row {
+            Some((family, _, _, true)) => {
+                tx.execute("UPDATE refresh_tokens SET revoked = 1 WHERE family = ?1", params![family])
+                    .map_err(|e| e.to_string())?;
+                None
+            }
+            Some((family, user_id, token_expires_at, false)) if token_expires_at >= now => {
+                tx.execute("UPDATE refresh_tokens SET revoked = 1 WHERE token_hash = ?1", params![token_hash])
+                    .map_err(|e| e.to_string())?;
+                tx.execute(
+                    "INSERT INTO refresh_tokens (token_hash, family, user_id, expires_at) VALUES (?1, ?2, ?3, ?4)",
+                    params![new_hash, family, user_id, expires_at],
+                ).map_err(|e| e.to_string())?;
+                Some((user_id, family))
+            }
+            _ => None,
+        };
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(result)
+    }
+
+    fn revoke_refresh_family(&self, family: &str) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute("UPDATE refresh_tokens SET revoked = 1 WHERE family = ?1", params![family])
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn revoke_access_token(&self, token_id: &str, expires_at: u64) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT OR IGNORE INTO revoked_access_tokens (token_id, expires_at) VALUES (?1, ?2)",
+            params![token_id, expires_at],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn is_access_token_revoked(&self, token_id: &str) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM revoked_access_tokens WHERE token_id = ?1)",
+            params![token_id],
+            |row| row.get(0),
+        )
+        .map_err(|e| e.to_string())
+    }
+}
+
+impl ProductStorage for SqliteStorage {
+    fn product_edits(&self) -> Result<Vec<(u32, Option<Product>)>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn
+            .prepare("SELECT id, product FROM product_edits ORDER BY id")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, Option<String>>(1)?)))
+            .map_err(|e| e.to_string())?;
+
+        let mut edits = Vec::new();
+        for row in rows {
+            let (id, product) = row.map_err(|e| e.to_string())?;
+            let product = product
+                .map(|json| serde_json::from_str(&json).map_err(|e| format!("Corrupt product {}: {}", id, e)))
+                .transpose()?;
+            edits.push((id, product));
+        }
+        Ok(edits)
+    }
+
+    fn save_product(&self, product: &Product) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let json = serde_json::to_string(product).map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT OR REPLACE INTO product_edits (id, product) VALUES (?1, ?2)",
+            params![product.id, json],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn delete_product(&self, id: u32) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute("INSERT OR REPLACE INTO product_edits (id, product) VALUES (?1, NULL)", params![id])
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::conformance;
+
+    fn in_memory() -> SqliteStorage {
+        SqliteStorage::open(":memory:").unwrap()
+    }
+
+    fn user_version(conn: &Connection) -> usize {
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap()
+    }
+
+    // Removes the database file when the test ends, even if it fails
+    struct TempDb(String);
+
+    impl TempDb {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("rusty-cart-test-{}.db", uuid::Uuid::new_v4()));
+            TempDb(path.to_str().unwrap().to_string())
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn passes_the_storage_round_trips() {
+        conformance::carts(&in_memory());
+        conformance::orders(&in_memory());
+        conformance::coupons(&in_memory());
+        conformance::inventory(&in_memory());
+        conformance::users_and_tokens(&in_memory());
+        conformance::product_edits(&in_memory());
+    }
+
+    #[test]
+    fn a_new_database_gets_every_migration() {
+        let storage = in_memory();
+        assert_eq!(user_version(&storage.conn.lock().unwrap()), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn reopening_keeps_the_data() {
+        let db = TempDb::new();
+        let storage = SqliteStorage::open(&db.0).unwrap();
+        conformance::carts(&storage);
+        let cart = storage.load_cart("s1").unwrap();
+        drop(storage);
+
+        let storage = SqliteStorage::open(&db.0).unwrap();
+        assert_eq!(user_version(&storage.conn.lock().unwrap()), MIGRATIONS.len());
+        assert_eq!(storage.load_cart("s1").unwrap(), cart);
+        assert_eq!(storage.load_shipping_method("s1").unwrap().as_deref(), Some("express"));
+    }
+
+    #[test]
+    fn databases_from_older_versions_are_upgraded() {
+        let db = TempDb::new();
+        let item = CartItem {
+            product: Product {
+                id: 1,
+                title: "Backpack".to_string(),
+                price: shared::money::Money::new(10995, "SEK"),
+                description: String::new(),
+                category: "bags".to_string(),
+                image: String::new(),
+                weight: None,
+            },
+            quantity: 2,
+        };
+        {
+            // A database as the first release left it
+            let conn = Connection::open(&db.0).unwrap();
+            conn.execute_batch(MIGRATIONS[0]).unwrap();
+            conn.pragma_update(None, "user_version", 1).unwrap();
+            conn.execute("INSERT INTO sessions (id, last_seen) VALUES ('old', ?1)", params![now_secs()]).unwrap();
+            conn.execute(
+                "INSERT INTO cart_items (session_id, position, product, quantity) VALUES ('old', 0, ?1, 2)",
+                params![serde_json::to_string(&item.product).unwrap()],
+            )
+            .unwrap();
+        }
+
+        let storage = SqliteStorage::open(&db.0).unwrap();
+        assert_eq!(user_version(&storage.conn.lock().unwrap()), MIGRATIONS.len());
+        assert_eq!(storage.load_cart("old").unwrap(), [item]);
+        assert_eq!(storage.load_coupon("old").unwrap(), None);
+        storage.save_coupon("old", Some("WELCOME10")).unwrap();
+        assert_eq!(storage.load_coupon("old").unwrap().as_deref(), Some("WELCOME10"));
+        conformance::users_and_tokens(&storage);
+    }
+}
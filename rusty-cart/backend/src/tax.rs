@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::path::Path;
+use serde::Deserialize;
+use shared::models::TaxMode;
+use shared::Money;
+
+// Tax rates (in percent) for one shipping region
+#[derive(Deserialize, Clone, Debug)]
+pub struct RegionRates {
+    pub default_rate: u32,
+    // Product category -> rate, for categories taxed differently from the default
+    #[serde(default)]
+    pub categories: HashMap<String, u32>,
+}
+
+// Net, tax and gross parts of an amount
+pub struct TaxSplit {
+    pub net: Money,
+    pub tax: Money,
+    pub gross: Money,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct TaxTable {
+    // Whether catalog prices already include tax
+    pub mode: TaxMode,
+    // Region used when the cart has no destination
+    pub default_region: String,
+    // Region code (e.g. "SE") -> rates
+    pub regions: HashMap<String, RegionRates>,
+}
+
+impl TaxTable {
+    // Swedish VAT: 25 % standard, 12 % food, 6 % books; catalog prices include VAT
+    pub fn swedish() -> Self {
+        TaxTable {
+            mode: TaxMode::Inclusive,
+            default_region: "SE".to_string(),
+            regions: HashMap::from([(
+                "SE".to_string(),
+                RegionRates {
+                    default_rate: 25,
+                    categories: HashMap::from([
+                        ("food".to_string(), 12),
+                        ("books".to_string(), 6),
+                    ]),
+                },
+            )]),
+        }
+    }
+
+    fn rates(&self, region: &str) -> Result<&RegionRates, String> {
+        self.regions
+            .get(&region.to_uppercase())
+            .ok_or_else(|| format!("No tax rates configured for region {}", region))
+    }
+
+    pub fn has_region(&self, region: &str) -> bool {
+        self.rates(region).is_ok()
+    }
+
+    // Rate for anything that isn't in a special category, e.g. shipping
+    pub fn standard_rate(&self, region: &str) -> Result<u32, String> {
+        Ok(self.rates(region)?.default_rate)
+    }
+
+    pub fn rate_for(&self, region: &str, category: &str) -> Result<u32, String> {
+        let rates = self.rates(region)?;
+        Ok(rates
+            .categories
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(category))
+            .map(|(_, rate)| *rate)
+            .unwrap_or(rates.default_rate))
+    }
+
+    // Splits a price (as it appears in the catalog, so gross or net depending on the mode) at `rate` percent
+    pub fn split(&self, amount: &Money, rate: u32) -> TaxSplit {
+        match self.mode {
+            TaxMode::Inclusive => {
+                let tax = amount.mul_ratio(rate as i64, 100 + rate as i64);
+                TaxSplit {
+                    net: Money::new(amount.minor() - tax.minor(), amount.currency()),
+                    tax,
+                    gross: amount.clone(),
+                }
+            }
+            TaxMode::Exclusive => {
+                let tax = amount.mul_ratio(rate as i64, 100);
+                TaxSplit {
+                    net: amount.clone(),
+                    gross: Money::new(amount.minor() + tax.minor(), amount.currency()),
+                    tax,
+                }
+            }
+        }
+    }
+
+    // Loads the table from a JSON or YAML file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let is_yaml = matches!(path.extension().and_then(|ext| ext.to_str()), Some("yaml") | Some("yml"));
+        let mut table: TaxTable = if is_yaml {
+            serde_yaml::from_str(&contents).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?
+        } else {
+            serde_json::from_str(&contents).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?
+        };
+        table.regions = table.regions.into_iter().map(|(code, rates)| (code.to_uppercase(), rates)).collect();
+        if !table.regions.contains_key(&table.default_region.to_uppercase()) {
+            return Err(format!("Default tax region {} has no rates", table.default_region));
+        }
+        Ok(table)
+    }
+}
+
+// Reads the tax table from the configured file, or uses Swedish VAT
+pub fn from_config(file: Option<&Path>) -> Result<TaxTable, String> {
+    match file {
+        Some(path) => TaxTable::load(path),
+        None => Ok(TaxTable::swedish()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(table: &TaxTable, minor: i64, rate: u32) -> (i64, i64, i64) {
+        let split = table.split(&Money::new(minor, "SEK"), rate);
+        (split.net.minor(), split.tax.minor(), split.gross.minor())
+    }
+
+    fn load(name: &str, contents: &str) -> Result<TaxTable, String> {
+        let path = std::env::temp_dir().join(format!("{}-{}", uuid::Uuid::new_v4(), name));
+        std::fs::write(&path, contents).unwrap();
+        let table = TaxTable::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        table
+    }
+
+    #[test]
+    fn inclusive_prices_are_split_into_net_and_tax() {
+        let table = TaxTable::swedish();
+        assert_eq!(split(&table, 12500, 25), (10000, 2500, 12500));
+        // 199.8 rounds to 200, and net takes what is left so the parts add up
+        assert_eq!(split(&table, 999, 25), (799, 200, 999));
+        assert_eq!(split(&table, 999, 0), (999, 0, 999));
+    }
+
+    #[test]
+    fn exclusive_prices_get_tax_added() {
+        let table = TaxTable { mode: TaxMode::Exclusive, ..TaxTable::swedish() };
+        assert_eq!(split(&table, 10000, 25), (10000, 2500, 12500));
+        // 249.75 rounds to 250
+        assert_eq!(split(&table, 999, 25), (999, 250, 1249));
+        assert_eq!(split(&table, 101, 6), (101, 6, 107));
+    }
+
+    #[test]
+    fn categories_can_override_the_region_rate() {
+        let table = TaxTable::swedish();
+        assert_eq!(table.rate_for("SE", "Books"), Ok(6));
+        assert_eq!(table.rate_for("se", "food"), Ok(12));
+        assert_eq!(table.rate_for("SE", "toys"), Ok(25));
+        assert_eq!(table.standard_rate("SE"), Ok(25));
+        assert_eq!(table.rate_for("NO", "toys"), Err("No tax rates configured for region NO".to_string()));
+        assert!(!table.has_region("NO"));
+    }
+
+    #[test]
+    fn loaded_tables_need_rates_for_the_default_region() {
+        let table = load("tax.yaml", "mode: exclusive\ndefault_region: dk\nregions:\n  dk:\n    default_rate: 25\n    categories:\n      food: 15\n").unwrap();
+        assert_eq!(table.mode, TaxMode::Exclusive);
+        assert_eq!(table.rate_for("DK", "food"), Ok(15));
+
+        let missing = load("tax.json", r#"{"mode": "inclusive", "default_region": "NO", "regions": {"SE": {"default_rate": 25}}}"#);
+        assert_eq!(missing.err(), Some("Default tax region NO has no rates".to_string()));
+    }
+}
@@ -0,0 +1,165 @@
+// The whole app on in-memory storage, for tests that go through the HTTP API
+use std::net::SocketAddr;
+use std::sync::Arc;
+use axum::body::{to_bytes, Body};
+use axum::extract::ConnectInfo;
+use axum::http::{header, HeaderMap, Method, Request, StatusCode};
+use axum::Router;
+use serde_json::Value;
+use tower::ServiceExt;
+use shared::api::SESSION_HEADER;
+use shared::models::{AuthSession, Role};
+use crate::auth::{TokenIssuer, User};
+use crate::catalog::InMemoryCatalog;
+use crate::catalog_cache::{CachedCatalog, DEFAULT_TTL};
+use crate::config::{AuthConfig, CartMergePolicy, CorsConfig, RateLimitConfig};
+use crate::coupons::CouponBook;
+use crate::inventory::{Inventory, RESERVATION_TTL};
+use crate::payments::{FakeBehavior, FakePaymentProvider, PaymentProvider};
+use crate::rate_limit::RateLimiter;
+use crate::shipping::ShippingTable;
+use crate::state::AppState;
+use crate::storage::MemoryStorage;
+use crate::tax::TaxTable;
+
+// Settings the tests vary; everything else is the default configuration
+pub struct TestOptions {
+    pub default_stock: u32,
+    pub cart_merge: CartMergePolicy,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub payments: Arc<dyn PaymentProvider>,
+}
+
+impl Default for TestOptions {
+    fn default() -> Self {
+        TestOptions {
+            default_stock: 10,
+            cart_merge: CartMergePolicy::Sum,
+            auth: AuthConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            payments: Arc::new(FakePaymentProvider::new(FakeBehavior::Approve, "http://127.0.0.1:9/webhook", "secret")),
+        }
+    }
+}
+
+pub struct TestApp {
+    pub router: Router,
+    pub state: AppState,
+}
+
+impl TestApp {
+    pub fn new() -> Self {
+        TestApp::with(TestOptions::default())
+    }
+
+    // Serves the products of `fixtures/products.json`
+    pub fn with(options: TestOptions) -> Self {
+        let products = serde_json::from_str(include_str!("../fixtures/products.json")).unwrap();
+        let storage = Arc::new(MemoryStorage::new());
+        let state = AppState::new(
+            CachedCatalog::new(Arc::new(InMemoryCatalog::new(products)), storage.clone(), DEFAULT_TTL),
+            storage.clone(),
+            options.payments,
+            CouponBook::default(),
+            ShippingTable::standard(),
+            TaxTable::swedish(),
+            Inventory::new(storage, options.default_stock, RESERVATION_TTL),
+            TokenIssuer::new(&options.auth),
+            options.cart_merge,
+            options.auth.admin_emails.clone(),
+            RateLimiter::new(&options.rate_limit),
+        );
+        TestApp {
+            router: crate::app(state.clone(), &CorsConfig::default()),
+            state,
+        }
+    }
+
+    // Creates an account with `role` and logs it in, skipping the slow password hashing
+    pub fn account(&self, email: &str, role: Role) -> AuthSession {
+        let user = User::new(email.to_string(), String::new(), role);
+        assert!(self.state.storage.insert_user(&user).unwrap());
+        self.state.tokens.login(self.state.storage.as_ref(), &user).unwrap()
+    }
+
+    // Starts a guest session and returns its id
+    pub async fn guest(&self) -> String {
+        let response = TestRequest::new(Method::GET, shared::api::CART_ENDPOINT).send(self).await;
+        response.header(SESSION_HEADER).unwrap()
+    }
+}
+
+pub struct TestRequest {
+    request: Request<Body>,
+}
+
+impl TestRequest {
+    pub fn new(method: Method, uri: &str) -> Self {
+        let request = Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+        TestRequest { request }
+    }
+
+    pub fn get(uri: &str) -> Self {
+        TestRequest::new(Method::GET, uri)
+    }
+
+    pub fn post(uri: &str) -> Self {
+        TestRequest::new(Method::POST, uri)
+    }
+
+    pub fn header(mut self, name: &'static str, value: &str) -> Self {
+        self.request.headers_mut().insert(name, value.parse().unwrap());
+        self
+    }
+
+    pub fn session(self, id: &str) -> Self {
+        self.header(SESSION_HEADER, id)
+    }
+
+    pub fn bearer(self, token: &str) -> Self {
+        self.header(header::AUTHORIZATION.as_str(), &format!("Bearer {}", token))
+    }
+
+    // Makes the request come from `ip`, as the server's `ConnectInfo` would
+    pub fn client_ip(mut self, ip: &str) -> Self {
+        let addr = SocketAddr::new(ip.parse().unwrap(), 50000);
+        self.request.extensions_mut().insert(ConnectInfo(addr));
+        self
+    }
+
+    pub fn json(mut self, body: Value) -> Self {
+        *self.request.body_mut() = Body::from(body.to_string());
+        self.header(header::CONTENT_TYPE.as_str(), "application/json")
+    }
+
+    pub async fn send(self, app: &TestApp) -> TestResponse {
+        let response = app.router.clone().oneshot(self.request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        TestResponse {
+            status,
+            headers,
+            body: serde_json::from_slice(&body).unwrap_or(Value::Null),
+        }
+    }
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    // Null if the body isn't JSON
+    pub body: Value,
+}
+
+impl TestResponse {
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string)
+    }
+
+    // The error code, if the response is an error
+    pub fn code(&self) -> Option<&str> {
+        self.body["code"].as_str()
+    }
+}
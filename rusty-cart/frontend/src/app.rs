@@ -1,23 +1,43 @@
 use yew::prelude::*;
 use wasm_bindgen_futures::spawn_local;
-use gloo_net::http::Request;
 use gloo::console::{log, error};
+use gloo_net::http::Response;
 
-use crate::models::{Product, CartItem, EditCartItemPayload, DeleteCartItemPayload};
+use shared::api::{PRODUCTS_ENDPOINT, CATEGORIES_ENDPOINT, CATEGORY_PRODUCTS_ENDPOINT, CART_ENDPOINT, CART_ITEMS_ENDPOINT, cart_item_path};
+use shared::models::{Product, Category, CartItem, CartSummary, AddToCartPayload, UpdateCartItemPayload};
 use crate::components::{ProductCard, CartItemCard};
-use crate::utils::{fetch_json, format_price};
+use crate::utils::{fetch_json, fetch_page, post_json, patch_json, delete, format_price, encode_path_segment};
 
 const BACKEND_URL: &str = "http://127.0.0.1:3000";
-const PRODUCTS_ENDPOINT: &str = "/products";
-const CART_ENDPOINT: &str = "/cart";
-const ADD_TO_CART_ENDPOINT: &str = "/cart/add";
-const EDIT_CART_ENDPOINT: &str = "/cart/edit";
-const DELETE_FROM_CART_ENDPOINT: &str = "/cart/delete";
+const PRODUCTS_PER_PAGE: usize = 12;
+
+// The updated cart sent back by the cart item routes
+async fn read_cart(resp: Result<Response, gloo_net::Error>) -> Result<CartSummary, String> {
+    match resp {
+        Ok(resp) if resp.ok() => resp.json::<CartSummary>().await.map_err(|e| format!("JSON parse error: {:?}", e)),
+        Ok(resp) => {
+            let text = resp.text().await.unwrap_or_default();
+            Err(format!("Server error {}: {}", resp.status(), text))
+        }
+        Err(e) => Err(format!("Network error: {:?}", e)),
+    }
+}
+
+// Shows the cart as priced by the backend, with coupons and tax applied
+fn show_cart_summary(cart: &UseStateHandle<Vec<CartItem>>, summary: &UseStateHandle<Option<CartSummary>>, data: CartSummary) {
+    cart.set(data.items.iter().map(|line| line.item.clone()).collect());
+    summary.set(Some(data));
+}
 
 #[function_component(RustyCart)]
 pub fn rusty_cart() -> Html {
-    let products = use_state(|| Vec::<Product>::new());
-    let cart = use_state(|| Vec::<CartItem>::new());
+    let products = use_state(Vec::<Product>::new);
+    let total_products = use_state(|| 0usize);
+    let page = use_state(|| 1usize);
+    let categories = use_state(Vec::<Category>::new);
+    let category = use_state(|| None::<String>);
+    let cart = use_state(Vec::<CartItem>::new);
+    let summary = use_state(|| None::<CartSummary>);
     let show_cart = use_state(|| false);
 
     let hover = use_state(|| false);
@@ -30,16 +50,37 @@ pub fn rusty_cart() -> Html {
         Callback::from(move |_| hover.set(false))
     };
 
-    // Fetch products
+    // Fetch categories for the navigation
     {
-        let products = products.clone();
+        let categories = categories.clone();
         use_effect_with((), move |_| {
             spawn_local(async move {
-                let url = format!("{BACKEND_URL}{PRODUCTS_ENDPOINT}");
-                match fetch_json::<Vec<Product>>(&url).await {
-                    Ok(data) => {
-                        log!(format!("Fetched {} products", data.len()));
+                let url = format!("{BACKEND_URL}{CATEGORIES_ENDPOINT}");
+                match fetch_json::<Vec<Category>>(&url).await {
+                    Ok(data) => categories.set(data),
+                    Err(err) => error!(format!("Failed to fetch categories: {}", err)),
+                }
+            });
+            || ()
+        });
+    }
+
+    // Fetch the current page of products, limited to the selected category if any
+    {
+        let products = products.clone();
+        let total_products = total_products.clone();
+        use_effect_with((*page, (*category).clone()), move |(page, category)| {
+            let path = match category {
+                Some(name) => CATEGORY_PRODUCTS_ENDPOINT.replace("{name}", &encode_path_segment(name)),
+                None => PRODUCTS_ENDPOINT.to_string(),
+            };
+            let url = format!("{BACKEND_URL}{path}?page={page}&per_page={PRODUCTS_PER_PAGE}");
+            spawn_local(async move {
+                match fetch_page::<Vec<Product>>(&url).await {
+                    Ok((data, total)) => {
+                        log!(format!("Fetched {} of {} products", data.len(), total));
                         products.set(data);
+                        total_products.set(total);
                     }
                     Err(err) => error!(format!("Failed to fetch products: {}", err)),
                 }
@@ -51,13 +92,14 @@ pub fn rusty_cart() -> Html {
     // Fetch cart
     {
         let cart = cart.clone();
+        let summary = summary.clone();
         use_effect_with((), move |_| {
             spawn_local(async move {
                 let url = format!("{BACKEND_URL}{CART_ENDPOINT}");
-                match fetch_json::<Vec<CartItem>>(&url).await {
+                match fetch_json::<CartSummary>(&url).await {
                     Ok(data) => {
-                        log!(format!("Fetched cart with {} items", data.len()));
-                        cart.set(data);
+                        log!(format!("Fetched cart with {} items", data.items.len()));
+                        show_cart_summary(&cart, &summary, data);
                     }
                     Err(err) => error!(format!("Failed to fetch cart: {}", err)),
                 }
@@ -66,6 +108,33 @@ pub fn rusty_cart() -> Html {
         });
     }
 
+    let page_count = total_products.div_ceil(PRODUCTS_PER_PAGE).max(1);
+    let prev_page = {
+        let page = page.clone();
+        Callback::from(move |_| page.set((*page - 1).max(1)))
+    };
+    let next_page = {
+        let page = page.clone();
+        Callback::from(move |_| page.set((*page + 1).min(page_count)))
+    };
+
+    let select_category = {
+        let category = category.clone();
+        let page = page.clone();
+        move |name: Option<String>| {
+            let category = category.clone();
+            let page = page.clone();
+            Callback::from(move |_| {
+                category.set(name.clone());
+                page.set(1);
+            })
+        }
+    };
+    let category_style = |selected: bool| format!(
+        "padding: 6px 12px; border-radius: 16px; border: 1px solid #ccc; cursor: pointer; background: {};",
+        if selected { "#ffd8b0" } else { "#fff" }
+    );
+
     let toggle_cart = {
         let show_cart = show_cart.clone();
         Callback::from(move |_| show_cart.set(!*show_cart))
@@ -73,30 +142,19 @@ pub fn rusty_cart() -> Html {
 
     let add_to_cart = {
         let cart = cart.clone();
+        let summary = summary.clone();
         move |product: Product| {
             let cart = cart.clone();
+            let summary = summary.clone();
             spawn_local(async move {
-                let url = format!("{BACKEND_URL}{ADD_TO_CART_ENDPOINT}");
-                let req = Request::post(&url)
-                    .header("Content-Type", "application/json")
-                    .body(serde_json::to_string(&product).unwrap())
-                    .unwrap();
-
-                match req.send().await {
-                    Ok(resp) if resp.ok() => {
+                let url = format!("{BACKEND_URL}{CART_ITEMS_ENDPOINT}");
+                let payload = AddToCartPayload { product_id: product.id, quantity: None };
+                match read_cart(post_json(&url, &payload).await).await {
+                    Ok(data) => {
                         log!(format!("Added product {} to cart", product.title));
-                        cart.set({
-                            let mut new_cart = (*cart).clone();
-                            if let Some(item) = new_cart.iter_mut().find(|i| i.product.id == product.id) {
-                                item.quantity += 1;
-                            } else {
-                                new_cart.push(CartItem { product: product.clone(), quantity: 1 });
-                            }
-                            new_cart
-                        });
+                        show_cart_summary(&cart, &summary, data);
                     }
-                    Ok(resp) => error!(format!("Failed to add product {}: server returned {}", product.title, resp.status())),
-                    Err(err) => error!(format!("Failed to add product {}: {:?}", product.title, err)),
+                    Err(err) => error!(format!("Failed to add product {}: {}", product.title, err)),
                 }
             });
         }
@@ -105,28 +163,19 @@ pub fn rusty_cart() -> Html {
     // Edit cart item quantity
     let edit_cart_item = {
         let cart = cart.clone();
+        let summary = summary.clone();
         move |(product_id, quantity): (u32, u32)| {
             let cart = cart.clone();
+            let summary = summary.clone();
             spawn_local(async move {
-                let payload = EditCartItemPayload { product_id, quantity };
-                let req = Request::post(&format!("{BACKEND_URL}{EDIT_CART_ENDPOINT}"))
-                    .header("Content-Type", "application/json")
-                    .body(serde_json::to_string(&payload).unwrap())
-                    .unwrap();
-
-                match req.send().await {
-                    Ok(resp) if resp.ok() => {
+                let url = format!("{BACKEND_URL}{}", cart_item_path(product_id));
+                let payload = UpdateCartItemPayload { quantity };
+                match read_cart(patch_json(&url, &payload).await).await {
+                    Ok(data) => {
                         log!(format!("Updated product {} quantity to {}", product_id, quantity));
-                        cart.set({
-                            let mut new_cart = (*cart).clone();
-                            if let Some(item) = new_cart.iter_mut().find(|i| i.product.id == product_id) {
-                                item.quantity = quantity;
-                            }
-                            new_cart
-                        });
+                        show_cart_summary(&cart, &summary, data);
                     }
-                    Ok(resp) => error!(format!("Failed to update product {}: server returned {}", product_id, resp.status())),
-                    Err(err) => error!(format!("Failed to update product {}: {:?}", product_id, err)),
+                    Err(err) => error!(format!("Failed to update product {}: {}", product_id, err)),
                 }
             });
         }
@@ -134,31 +183,30 @@ pub fn rusty_cart() -> Html {
 
     let delete_cart_item = {
         let cart = cart.clone();
+        let summary = summary.clone();
         move |product_id: u32| {
             let cart = cart.clone();
+            let summary = summary.clone();
             spawn_local(async move {
-                let payload = DeleteCartItemPayload { product_id };
-                let req = Request::post(&format!("{BACKEND_URL}{DELETE_FROM_CART_ENDPOINT}"))
-                    .header("Content-Type", "application/json")
-                    .body(serde_json::to_string(&payload).unwrap())
-                    .unwrap();
-
-                match req.send().await {
-                    Ok(resp) if resp.ok() => {
-                        cart.set({
-                            let mut new_cart = (*cart).clone();
-                            new_cart.retain(|i| i.product.id != product_id);
-                            new_cart
-                        });
-                    }
-                    Ok(resp) => error!(format!("Failed to delete product {}: server returned {}", product_id, resp.status())),
-                    Err(err) => error!(format!("Failed to delete product {}: {:?}", product_id, err)),
+                let url = format!("{BACKEND_URL}{}", cart_item_path(product_id));
+                match read_cart(delete(&url).await).await {
+                    Ok(data) => show_cart_summary(&cart, &summary, data),
+                    Err(err) => error!(format!("Failed to delete product {}: {}", product_id, err)),
                 }
             });
         }
     };
 
-    let total: f64 = (*cart).iter().map(|item| item.product.price * item.quantity as f64).sum();
+    // Totals come from the backend, which applies coupons and tax
+    let totals = match &*summary {
+        Some(s) => format!(
+            "💰 Net: {} · VAT: {} · Total: {}",
+            format_price(&s.net),
+            format_price(&s.tax),
+            format_price(&s.gross)
+        ),
+        None => "💰 Total: –".to_string(),
+    };
 
     html! {
         <div style="max-width: 900px; margin: auto; padding: 20px;">
@@ -166,8 +214,19 @@ pub fn rusty_cart() -> Html {
             <h1 style="margin-bottom: 24px; font-size: 2em;">{ "🦀 RustyCart" }</h1>
 
             <h2 style="margin-bottom: 16px;">
-                { format!("Products ({} available)", products.len()) }
+                { format!("Products ({} available)", *total_products) }
             </h2>
+            <div style="display: flex; flex-wrap: wrap; gap: 8px; margin-bottom: 16px;">
+                <button style={category_style(category.is_none())} onclick={select_category(None)}>{ "All" }</button>
+                { for categories.iter().map(|c| html! {
+                    <button
+                        style={category_style(category.as_deref() == Some(c.name.as_str()))}
+                        onclick={select_category(Some(c.name.clone()))}
+                    >
+                        { format!("{} ({})", c.name, c.count) }
+                    </button>
+                }) }
+            </div>
             <div style="display: grid; grid-template-columns: repeat(auto-fill, minmax(220px, 1fr)); gap: 16px;">
                 { for (*products).iter().map(|product| {
                     html! {
@@ -176,6 +235,14 @@ pub fn rusty_cart() -> Html {
                 }) }
             </div>
 
+            if page_count > 1 {
+                <div style="margin-top: 16px; display: flex; gap: 12px; justify-content: center; align-items: center;">
+                    <button onclick={prev_page} disabled={*page <= 1}>{ "◀ Previous" }</button>
+                    <span>{ format!("Page {} of {}", *page, page_count) }</span>
+                    <button onclick={next_page} disabled={*page >= page_count}>{ "Next ▶" }</button>
+                </div>
+            }
+
            <div
                 onclick={toggle_cart.clone()}
                 onmouseover={on_mouse_over.clone()}
@@ -194,7 +261,7 @@ pub fn rusty_cart() -> Html {
                 )}
             >
                 <span>{ format!("🛒 {} items", cart.len()) }</span>
-                <span>{ format!("💰 Total: {}", format_price(total)) }</span>
+                <span>{ totals }</span>
             </div>
 
             if *show_cart {
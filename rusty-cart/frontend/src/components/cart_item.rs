@@ -1,6 +1,6 @@
 use yew::prelude::*;
 use web_sys::HtmlInputElement;
-use crate::models::CartItem;
+use shared::models::CartItem;
 use crate::utils::format_price;
 
 #[derive(Properties, PartialEq, Clone)]
@@ -21,7 +21,7 @@ pub fn cart_item_card(props: &CartItemCardProps) -> Html {
     let input_value = use_state(|| item.quantity.to_string());
 
     let hover = use_state(|| false);
-    let bg_color = if props.index % 2 == 0 { "#C5C6D0" } else { "#ADADC9" };
+    let bg_color = if props.index.is_multiple_of(2) { "#C5C6D0" } else { "#ADADC9" };
 
     let increase = {
         let on_edit = on_edit.clone();
@@ -164,7 +164,7 @@ pub fn cart_item_card(props: &CartItemCardProps) -> Html {
                 overflow: hidden;
                 text-overflow: ellipsis;
             ">
-                { format_price(item.product.price * item.quantity as f64) }
+                { format_price(&item.line_total()) }
             </span>
 
             <span
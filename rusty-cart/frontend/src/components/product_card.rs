@@ -1,5 +1,6 @@
 use yew::prelude::*;
-use crate::models::Product;
+use shared::models::Product;
+use crate::utils::format_price;
 
 #[derive(Properties, PartialEq, Clone)]
 pub struct ProductCardProps {
@@ -66,7 +67,7 @@ pub fn product_card(props: &ProductCardProps) -> Html {
             </h3>
 
             <p style="margin: 0 0 12px 0; font-size: 0.9em; color: #333;">
-                { format!("{} kr", props.product.price) }
+                { format_price(&props.product.price) }
             </p>
 
             <button
@@ -1,5 +1,4 @@
 mod app;
-mod models;
 mod components;
 mod utils;
 
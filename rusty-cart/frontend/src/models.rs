@@ -1,28 +0,0 @@
-use serde::{Deserialize, Serialize};
-
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub struct Product {
-    pub id: u32,
-    pub title: String,
-    pub price: f64,
-    pub description: String,
-    pub category: String,
-    pub image: String,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub struct CartItem {
-    pub product: Product,
-    pub quantity: u32,
-}
-
-#[derive(Serialize, Clone)]
-pub struct EditCartItemPayload {
-    pub product_id: u32,
-    pub quantity: u32,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct DeleteCartItemPayload {
-    pub product_id: u32,
-}
\ No newline at end of file
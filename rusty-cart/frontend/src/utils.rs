@@ -1,8 +1,32 @@
-use gloo_net::http::Request;
+use gloo::storage::{LocalStorage, Storage};
+use gloo_net::http::{Request, RequestBuilder, Response};
+use serde::Serialize;
+use shared::api::{SESSION_HEADER, TOTAL_COUNT_HEADER};
+use shared::Money;
+
+const SESSION_STORAGE_KEY: &str = "rusty-cart-session";
+
+// Attaches the stored session id, if any, so the backend serves this browser's cart
+fn with_session(req: RequestBuilder) -> RequestBuilder {
+    match LocalStorage::get::<String>(SESSION_STORAGE_KEY) {
+        Ok(id) => req.header(SESSION_HEADER, &id),
+        Err(_) => req,
+    }
+}
+
+// Stores the session id issued (or re-issued) by the backend
+fn remember_session(resp: &Response) {
+    if let Some(id) = resp.headers().get(SESSION_HEADER) {
+        let _ = LocalStorage::set(SESSION_STORAGE_KEY, id);
+    }
+}
 
 pub async fn fetch_json<T: serde::de::DeserializeOwned>(url: &str) -> Result<T, String> {
-    match Request::get(url).send().await {
-        Ok(resp) if resp.ok() => resp.json::<T>().await.map_err(|e| format!("JSON parse error: {:?}", e)),
+    match with_session(Request::get(url)).send().await {
+        Ok(resp) if resp.ok() => {
+            remember_session(&resp);
+            resp.json::<T>().await.map_err(|e| format!("JSON parse error: {:?}", e))
+        }
         Ok(resp) => {
             let text = resp.text().await.unwrap_or_default();
             Err(format!("Server error {}: {}", resp.status(), text))
@@ -11,10 +35,61 @@ pub async fn fetch_json<T: serde::de::DeserializeOwned>(url: &str) -> Result<T,
     }
 }
 
-pub fn format_price(price: f64) -> String {
-    format!("{:.2} kr", price)
+// Like `fetch_json` for paged lists, also returning the total number of items across all pages
+pub async fn fetch_page<T: serde::de::DeserializeOwned>(url: &str) -> Result<(T, usize), String> {
+    match with_session(Request::get(url)).send().await {
+        Ok(resp) if resp.ok() => {
+            remember_session(&resp);
+            let total = resp.headers().get(TOTAL_COUNT_HEADER).and_then(|v| v.parse().ok()).unwrap_or(0);
+            let data = resp.json::<T>().await.map_err(|e| format!("JSON parse error: {:?}", e))?;
+            Ok((data, total))
+        }
+        Ok(resp) => {
+            let text = resp.text().await.unwrap_or_default();
+            Err(format!("Server error {}: {}", resp.status(), text))
+        }
+        Err(e) => Err(format!("Network error: {:?}", e)),
+    }
+}
+
+async fn send_json<T: Serialize>(req: RequestBuilder, body: &T) -> Result<Response, gloo_net::Error> {
+    let resp = with_session(req)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(body).unwrap())?
+        .send()
+        .await?;
+    remember_session(&resp);
+    Ok(resp)
+}
+
+pub async fn post_json<T: Serialize>(url: &str, body: &T) -> Result<Response, gloo_net::Error> {
+    send_json(Request::post(url), body).await
+}
+
+pub async fn patch_json<T: Serialize>(url: &str, body: &T) -> Result<Response, gloo_net::Error> {
+    send_json(Request::patch(url), body).await
 }
 
-/*pub fn format_price_value(price: f64) -> String {
-    format!("{:.2}", price)
-}*/
\ No newline at end of file
+pub async fn delete(url: &str) -> Result<Response, gloo_net::Error> {
+    let resp = with_session(Request::delete(url)).send().await?;
+    remember_session(&resp);
+    Ok(resp)
+}
+
+// Percent-encodes a value for use as one segment of a URL path, e.g. a category name
+pub fn encode_path_segment(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub fn format_price(price: &Money) -> String {
+    match price.currency() {
+        "SEK" => format!("{} kr", price.format_major()),
+        currency => format!("{} {}", price.format_major(), currency),
+    }
+}
@@ -0,0 +1,50 @@
+// Paths served by the backend router and called by the frontend
+pub const PRODUCTS_ENDPOINT: &str = "/products";
+pub const PRODUCT_ENDPOINT: &str = "/products/{id}";
+pub const CATEGORIES_ENDPOINT: &str = "/categories";
+pub const CATEGORY_PRODUCTS_ENDPOINT: &str = "/categories/{name}/products";
+pub const CART_ENDPOINT: &str = "/cart";
+pub const CART_ITEMS_ENDPOINT: &str = "/cart/items";
+pub const CART_ITEM_ENDPOINT: &str = "/cart/items/{product_id}";
+// CART_ITEM_ENDPOINT for one product
+pub fn cart_item_path(product_id: u32) -> String {
+    CART_ITEM_ENDPOINT.replace("{product_id}", &product_id.to_string())
+}
+// Deprecated aliases of the cart item routes, kept for one release
+pub const ADD_TO_CART_ENDPOINT: &str = "/cart/add";
+pub const EDIT_CART_ENDPOINT: &str = "/cart/edit";
+pub const DELETE_FROM_CART_ENDPOINT: &str = "/cart/delete";
+pub const CART_COUPON_ENDPOINT: &str = "/cart/coupon";
+pub const CART_ADDRESS_ENDPOINT: &str = "/cart/address";
+pub const CART_SHIPPING_ENDPOINT: &str = "/cart/shipping";
+pub const CHECKOUT_ENDPOINT: &str = "/checkout";
+pub const ORDERS_ENDPOINT: &str = "/orders";
+pub const ORDER_ENDPOINT: &str = "/orders/{id}";
+pub const CANCEL_ORDER_ENDPOINT: &str = "/orders/{id}/cancel";
+pub const PAY_ORDER_ENDPOINT: &str = "/orders/{id}/pay";
+pub const CONFIRM_PAYMENT_ENDPOINT: &str = "/payments/{payment_id}/confirm";
+pub const PAYMENT_WEBHOOK_ENDPOINT: &str = "/payments/webhook";
+pub const REGISTER_ENDPOINT: &str = "/auth/register";
+pub const LOGIN_ENDPOINT: &str = "/auth/login";
+pub const LOGOUT_ENDPOINT: &str = "/auth/logout";
+pub const REFRESH_ENDPOINT: &str = "/auth/refresh";
+
+// Staff and admin routes. The backend nests them under ADMIN_PREFIX, so the paths below are
+// relative to it, e.g. ADMIN_PREFIX + ADMIN_PRODUCTS_PATH is `/admin/products`.
+pub const ADMIN_PREFIX: &str = "/admin";
+pub const ADMIN_CATALOG_REFRESH_PATH: &str = "/catalog/refresh";
+pub const ADMIN_PRODUCTS_PATH: &str = "/products";
+pub const ADMIN_PRODUCT_PATH: &str = "/products/{id}";
+pub const ADMIN_STOCK_PATH: &str = "/products/{id}/stock";
+pub const ADMIN_ORDERS_PATH: &str = "/orders";
+pub const ADMIN_ORDER_PATH: &str = "/orders/{id}";
+pub const ADMIN_ORDER_STATUS_PATH: &str = "/orders/{id}/status";
+pub const ADMIN_USERS_PATH: &str = "/users";
+pub const ADMIN_USER_ROLE_PATH: &str = "/users/{id}/role";
+
+// Header used to carry the session id between the frontend and the backend
+pub const SESSION_HEADER: &str = "x-session-id";
+// Header carrying the shared secret on payment webhook calls
+pub const PAYMENT_SIGNATURE_HEADER: &str = "x-payment-signature";
+// Number of products matching a `GET /products` query across all pages
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
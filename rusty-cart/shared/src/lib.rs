@@ -0,0 +1,8 @@
+// API types and constants used by both the backend and the frontend.
+// The frontend builds it for wasm32, so dependencies are kept to pure-Rust crates that support
+// that target: serde, validator for the request rules and utoipa for the OpenAPI schemas.
+pub mod api;
+pub mod models;
+pub mod money;
+
+pub use money::Money;
@@ -0,0 +1,532 @@
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use validator::{Validate, ValidationError};
+use crate::money::Money;
+
+// Most of one product a cart line can hold
+pub const MAX_ITEM_QUANTITY: u32 = 999;
+// Largest page size of product listings
+pub const MAX_PER_PAGE: u32 = 100;
+// Length limits for account passwords
+pub const MIN_PASSWORD_LENGTH: u64 = 8;
+pub const MAX_PASSWORD_LENGTH: u64 = 128;
+
+fn not_blank(value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        return Err(ValidationError::new("blank").with_message("must not be blank".into()));
+    }
+    Ok(())
+}
+
+fn not_negative(amount: &Money) -> Result<(), ValidationError> {
+    if amount.minor() < 0 {
+        return Err(ValidationError::new("negative").with_message("must not be negative".into()));
+    }
+    Ok(())
+}
+
+fn known_sort(sort: &str) -> Result<(), ValidationError> {
+    if !matches!(sort, "price" | "-price" | "title" | "-title") {
+        return Err(ValidationError::new("sort").with_message("must be price, -price, title or -title".into()));
+    }
+    Ok(())
+}
+
+fn one_stock_change(payload: &UpdateStockPayload) -> Result<(), ValidationError> {
+    if payload.set.is_some() == payload.adjust.is_some() {
+        return Err(ValidationError::new("stock_change").with_message("send either set or adjust".into()));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+pub struct Product {
+    pub id: u32,
+    #[validate(custom(function = "not_blank"), length(max = 200))]
+    pub title: String,
+    #[validate(custom(function = "not_negative"))]
+    pub price: Money,
+    pub description: String,
+    pub category: String,
+    pub image: String,
+    // Shipping weight in grams; not every catalog provides it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+}
+
+// A product category and how many products are in it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct Category {
+    pub name: String,
+    pub count: usize,
+}
+
+// Query parameters of `GET /products`. Prices are in major units, e.g. `min_price=99.50`;
+// `sort` is `price`, `-price`, `title` or `-title`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Validate, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ProductQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(length(max = 200))]
+    pub q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(length(max = 100))]
+    pub category: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 0.0))]
+    pub min_price: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 0.0))]
+    pub max_price: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(custom(function = "known_sort"))]
+    pub sort: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 1))]
+    pub page: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 1, max = MAX_PER_PAGE))]
+    pub per_page: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct CartItem {
+    pub product: Product,
+    pub quantity: u32,
+}
+
+impl CartItem {
+    pub fn line_total(&self) -> Money {
+        self.product.price.times(self.quantity)
+    }
+}
+
+// Whether catalog prices include tax (gross) or have it added on top (net)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TaxMode {
+    Inclusive,
+    Exclusive,
+}
+
+// A cart item with its price after discounts, split into net, tax and gross.
+// `subtotal`, `discount` and `total` are in catalog prices, i.e. gross or net depending on the tax mode.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct CartLine {
+    #[serde(flatten)]
+    pub item: CartItem,
+    pub subtotal: Money,
+    pub discount: Money,
+    pub total: Money,
+    // Percent
+    pub tax_rate: u32,
+    pub net: Money,
+    pub tax: Money,
+    pub gross: Money,
+}
+
+// The coupon attached to the cart; `applied` is false (with a `reason`) when it currently doesn't qualify
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct AppliedCoupon {
+    pub code: String,
+    pub applied: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+// Delivery address of a cart or order. `country` is a code such as "SE" and decides the tax region.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+pub struct Address {
+    #[validate(custom(function = "not_blank"), length(max = 100))]
+    pub name: String,
+    #[validate(custom(function = "not_blank"), length(max = 200))]
+    pub street: String,
+    #[validate(custom(function = "not_blank"), length(max = 20))]
+    pub postal_code: String,
+    #[validate(custom(function = "not_blank"), length(max = 100))]
+    pub city: String,
+    #[validate(custom(function = "not_blank"), length(max = 10))]
+    pub country: String,
+}
+
+// A shipping method that can deliver the cart, with what it would cost
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct ShippingOption {
+    pub method: String,
+    pub name: String,
+    pub cost: Money,
+}
+
+// The shipping method chosen for the cart; `available` is false (with a `reason`) when it can't
+// deliver the cart as it is now. `cost` is in catalog prices like the cart lines and split into
+// net, tax and gross the same way.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct SelectedShipping {
+    pub method: String,
+    pub name: String,
+    pub available: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub cost: Money,
+    // Percent
+    pub tax_rate: u32,
+    pub net: Money,
+    pub tax: Money,
+    pub gross: Money,
+}
+
+// The cart as returned by `GET /cart`, priced by the backend. `subtotal` and `discount` cover the
+// items only, while `net`, `tax` and `gross` include shipping. `total` is the amount to pay,
+// which is always the gross amount.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct CartSummary {
+    pub items: Vec<CartLine>,
+    pub coupon: Option<AppliedCoupon>,
+    pub address: Option<Address>,
+    // Methods that can deliver the cart to `address`; empty until an address is set
+    pub shipping_options: Vec<ShippingOption>,
+    pub shipping: Option<SelectedShipping>,
+    pub region: String,
+    pub tax_mode: TaxMode,
+    pub subtotal: Money,
+    pub discount: Money,
+    pub net: Money,
+    pub tax: Money,
+    pub gross: Money,
+    pub total: Money,
+}
+
+// Payload structs for cart operations
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+pub struct AddToCartPayload {
+    pub product_id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 1, max = MAX_ITEM_QUANTITY))]
+    pub quantity: Option<u32>,
+}
+
+// Body of `PATCH /cart/items/{product_id}`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+pub struct UpdateCartItemPayload {
+    #[validate(range(min = 1, max = MAX_ITEM_QUANTITY))]
+    pub quantity: u32,
+}
+
+// Bodies of the deprecated `POST /cart/edit` and `POST /cart/delete`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+pub struct EditCartItemPayload {
+    pub product_id: u32,
+    #[validate(range(min = 1, max = MAX_ITEM_QUANTITY))]
+    pub quantity: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+pub struct DeleteCartItemPayload {
+    pub product_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+pub struct ApplyCouponPayload {
+    #[validate(custom(function = "not_blank"), length(max = 64))]
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+pub struct SelectShippingPayload {
+    #[validate(custom(function = "not_blank"), length(max = 64))]
+    pub method: String,
+}
+
+// Stock of one product; `reserved` is held by carts and not available to others
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct StockLevel {
+    pub product_id: u32,
+    pub on_hand: u32,
+    pub reserved: u32,
+    pub available: u32,
+}
+
+// Either sets the stock to a new level or changes it by `adjust` (which may be negative)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+#[validate(schema(function = "one_stock_change"))]
+pub struct UpdateStockPayload {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub set: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adjust: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderStatus {
+    Pending,
+    Paid,
+    Shipped,
+    Cancelled,
+}
+
+impl OrderStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OrderStatus::Pending => "pending",
+            OrderStatus::Paid => "paid",
+            OrderStatus::Shipped => "shipped",
+            OrderStatus::Cancelled => "cancelled",
+        }
+    }
+
+    // pending -> paid | cancelled, paid -> shipped | cancelled; shipped and cancelled are final
+    pub fn can_transition_to(self, next: OrderStatus) -> bool {
+        matches!(
+            (self, next),
+            (OrderStatus::Pending, OrderStatus::Paid)
+                | (OrderStatus::Pending, OrderStatus::Cancelled)
+                | (OrderStatus::Paid, OrderStatus::Shipped)
+                | (OrderStatus::Paid, OrderStatus::Cancelled)
+        )
+    }
+
+    // Customers may only cancel orders they haven't paid for; a paid order needs a refund, which
+    // staff handle before cancelling it
+    pub fn customer_can_cancel(self) -> bool {
+        self == OrderStatus::Pending
+    }
+}
+
+// A cart item frozen at checkout time; later catalog price changes don't affect it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct OrderLine {
+    pub product_id: u32,
+    pub title: String,
+    pub unit_price: Money,
+    pub quantity: u32,
+    pub line_total: Money,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discount: Option<Money>,
+}
+
+// How an order is delivered; `cost` includes tax
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct OrderShipping {
+    pub method: String,
+    pub name: String,
+    pub cost: Money,
+}
+
+// Timestamps are seconds since the Unix epoch
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct Order {
+    pub id: String,
+    pub lines: Vec<OrderLine>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coupon: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discount: Option<Money>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tax: Option<Money>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Address>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shipping: Option<OrderShipping>,
+    pub total: Money,
+    pub status: OrderStatus,
+    // Set once a payment has been started for the order
+    #[serde(default)]
+    pub payment_id: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+pub struct UpdateOrderStatusPayload {
+    pub status: OrderStatus,
+}
+
+// Result of charging an order
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum PaymentOutcome {
+    Approved { payment_id: String },
+    Declined { payment_id: String, reason: String },
+    // The customer has to confirm the payment (3-D Secure style) at `confirmation_url`;
+    // the final result is reported to the payment webhook
+    RequiresConfirmation { payment_id: String, confirmation_url: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct PayOrderResponse {
+    pub order: Order,
+    pub payment: PaymentOutcome,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentEventKind {
+    Succeeded,
+    Failed,
+}
+
+// Sent by the payment provider to the webhook when a payment completes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+pub struct PaymentEvent {
+    #[validate(custom(function = "not_blank"))]
+    pub payment_id: String,
+    #[validate(custom(function = "not_blank"))]
+    pub order_id: String,
+    pub kind: PaymentEventKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+pub struct ConfirmPaymentPayload {
+    pub approve: bool,
+}
+
+// Body of `POST /auth/register`. Not Debug, so the password can't end up in a log line.
+#[derive(Serialize, Deserialize, Clone, Validate, ToSchema)]
+pub struct RegisterPayload {
+    #[validate(email(message = "must be an email address"), length(max = 254))]
+    pub email: String,
+    #[validate(length(min = MIN_PASSWORD_LENGTH, max = MAX_PASSWORD_LENGTH))]
+    pub password: String,
+}
+
+// Body of `POST /auth/login`. Only the upper length limit is checked so older, shorter
+// passwords keep working if the rules get stricter.
+#[derive(Serialize, Deserialize, Clone, Validate, ToSchema)]
+pub struct LoginPayload {
+    #[validate(custom(function = "not_blank"), length(max = 254))]
+    pub email: String,
+    #[validate(length(min = 1, max = MAX_PASSWORD_LENGTH))]
+    pub password: String,
+}
+
+// What an account may do. Staff manage the catalog, stock and orders; admins also manage accounts.
+// Ordered by privilege, so `role >= Role::Staff` means staff or admin.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Customer,
+    Staff,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Customer => "customer",
+            Role::Staff => "staff",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Role> {
+        match value {
+            "customer" => Some(Role::Customer),
+            "staff" => Some(Role::Staff),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+// An account as shown to its owner and to admins
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct UserProfile {
+    pub id: String,
+    pub email: String,
+    pub role: Role,
+    pub created_at: u64,
+}
+
+// Body of `POST /admin/users/{id}/role`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+pub struct UpdateRolePayload {
+    pub role: Role,
+}
+
+// Returned on registration, login and refresh. The access token is sent as
+// `Authorization: Bearer <access_token>` until `expires_at` (Unix seconds); then the refresh token
+// buys a new pair from `POST /auth/refresh`. Each refresh token works once, until `refresh_expires_at`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct AuthSession {
+    pub access_token: String,
+    // Always "Bearer"
+    pub token_type: String,
+    pub expires_at: u64,
+    pub refresh_token: String,
+    pub refresh_expires_at: u64,
+    pub user: UserProfile,
+    // What was moved from the guest cart into the account's cart on login; empty on refresh or
+    // when there was no guest cart
+    #[serde(default)]
+    pub merged_cart: Vec<MergedCartItem>,
+}
+
+// A product of the guest cart merged into the account's cart. `quantity` is what the account's
+// cart holds afterwards; `limited_by_stock` is set when the merge policy asked for more than was in stock.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct MergedCartItem {
+    pub product_id: u32,
+    pub title: String,
+    pub guest_quantity: u32,
+    pub account_quantity: u32,
+    pub quantity: u32,
+    pub limited_by_stock: bool,
+}
+
+// Body of `POST /auth/refresh`
+#[derive(Serialize, Deserialize, Clone, Validate, ToSchema)]
+pub struct RefreshPayload {
+    #[validate(custom(function = "not_blank"), length(max = 128))]
+    pub refresh_token: String,
+}
+
+// Body of `POST /admin/products` and `PUT /admin/products/{id}`. The id is assigned by the backend
+// on creation and taken from the path on update.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema)]
+pub struct ProductPayload {
+    #[validate(custom(function = "not_blank"), length(max = 200))]
+    pub title: String,
+    #[validate(custom(function = "not_negative"))]
+    pub price: Money,
+    #[serde(default)]
+    #[validate(length(max = 5000))]
+    pub description: String,
+    #[validate(custom(function = "not_blank"), length(max = 100))]
+    pub category: String,
+    #[serde(default)]
+    #[validate(length(max = 500))]
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+}
+
+impl ProductPayload {
+    pub fn into_product(self, id: u32) -> Product {
+        Product {
+            id,
+            title: self.title.trim().to_string(),
+            price: self.price,
+            description: self.description,
+            category: self.category.trim().to_string(),
+            image: self.image,
+            weight: self.weight,
+        }
+    }
+}
+
+// An order as listed for staff, with whoever placed it: an account id or a guest session id
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct AdminOrder {
+    pub owner: String,
+    #[serde(flatten)]
+    pub order: Order,
+}
+
+// Query parameters of `GET /admin/orders`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Validate, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AdminOrderQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<OrderStatus>,
+}
@@ -0,0 +1,187 @@
+use std::fmt;
+use serde::{Deserialize, Deserializer, Serialize};
+use utoipa::ToSchema;
+
+pub const DEFAULT_CURRENCY: &str = "SEK";
+
+// An amount of money stored as integer minor units (öre, cents) with an ISO 4217 currency code.
+//
+// Rounding rules:
+//   - Amounts given in major units as floats (e.g. prices from the Fake Store API) are rounded
+//     to the nearest minor unit, halves away from zero, once when they enter the system.
+//   - All arithmetic after that is done on integers, so summing line items is exact.
+//   - Fractions of an amount (percentages, tax shares) are rounded to the nearest minor unit,
+//     halves away from zero, each time they are taken.
+//   - Amounts in different currencies are never added together.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq, Hash, ToSchema)]
+pub struct Money {
+    minor: i64,
+    currency: String,
+}
+
+impl Money {
+    pub fn new(minor: i64, currency: &str) -> Self {
+        Money {
+            minor,
+            currency: currency.to_uppercase(),
+        }
+    }
+
+    pub fn zero(currency: &str) -> Self {
+        Money::new(0, currency)
+    }
+
+    // Converts an amount in major units, rounding to the nearest minor unit (halves away from zero)
+    pub fn from_major(amount: f64, currency: &str) -> Self {
+        Money::new((amount * 100.0).round() as i64, currency)
+    }
+
+    pub fn minor(&self) -> i64 {
+        self.minor
+    }
+
+    pub fn currency(&self) -> &str {
+        &self.currency
+    }
+
+    // Price of `quantity` units at this unit price
+    pub fn times(&self, quantity: u32) -> Money {
+        Money::new(self.minor.saturating_mul(quantity as i64), &self.currency)
+    }
+
+    // Adds two amounts; `None` if the currencies differ or the sum overflows
+    pub fn checked_add(&self, other: &Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        Some(Money::new(self.minor.checked_add(other.minor)?, &self.currency))
+    }
+
+    // Subtracts `other`; `None` if the currencies differ or the result overflows
+    pub fn checked_sub(&self, other: &Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        Some(Money::new(self.minor.checked_sub(other.minor)?, &self.currency))
+    }
+
+    // `numerator / denominator` of this amount, rounded to the nearest minor unit (halves away from zero).
+    // E.g. 25 % is `mul_ratio(25, 100)`.
+    pub fn mul_ratio(&self, numerator: i64, denominator: i64) -> Money {
+        let product = self.minor as i128 * numerator as i128;
+        let denominator = denominator as i128;
+        let mut quotient = product / denominator;
+        let remainder = product % denominator;
+        if remainder.abs() * 2 >= denominator.abs() {
+            quotient += if (product < 0) != (denominator < 0) { -1 } else { 1 };
+        }
+        Money::new(quotient as i64, &self.currency)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.minor == 0
+    }
+
+    // Sums amounts that must all be in `currency`; `None` on a mismatch or overflow
+    pub fn sum(amounts: impl IntoIterator<Item = Money>, currency: &str) -> Option<Money> {
+        amounts
+            .into_iter()
+            .try_fold(Money::zero(currency), |total, amount| total.checked_add(&amount))
+    }
+
+    // The amount in major units with two decimals, e.g. "109.95"
+    pub fn format_major(&self) -> String {
+        let sign = if self.minor < 0 { "-" } else { "" };
+        let abs = self.minor.unsigned_abs();
+        format!("{}{}.{:02}", sign, abs / 100, abs % 100)
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.format_major(), self.currency)
+    }
+}
+
+// Accepts both `{ "minor": 10995, "currency": "SEK" }` and a bare number in major units
+// (the Fake Store API format), which is taken to be in the default currency
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Minor { minor: i64, currency: String },
+            Major(f64),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Minor { minor, currency } => Money::new(minor, &currency),
+            Repr::Major(amount) => Money::from_major(amount, DEFAULT_CURRENCY),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summing_many_line_items_is_exact() {
+        let items = (0..10_000).map(|_| Money::from_major(0.10, "SEK"));
+        let total = Money::sum(items, "SEK").unwrap();
+        assert_eq!(total.minor(), 100_000);
+        assert_eq!(total.format_major(), "1000.00");
+
+        let float_total: f64 = (0..10_000).map(|_| 0.10).sum();
+        assert_ne!(float_total, 1000.0);
+    }
+
+    #[test]
+    fn line_totals_multiply_quantity_exactly() {
+        let price = Money::from_major(109.95, "SEK");
+        let lines = vec![price.times(3), Money::from_major(22.3, "SEK").times(7), Money::from_major(0.01, "SEK")];
+        assert_eq!(Money::sum(lines, "SEK").unwrap(), Money::new(32985 + 15610 + 1, "SEK"));
+    }
+
+    #[test]
+    fn from_major_rounds_half_away_from_zero() {
+        assert_eq!(Money::from_major(0.005, "SEK").minor(), 1);
+        assert_eq!(Money::from_major(-0.005, "SEK").minor(), -1);
+        assert_eq!(Money::from_major(12.344, "SEK").minor(), 1234);
+        assert_eq!(Money::from_major(12.346, "SEK").minor(), 1235);
+    }
+
+    #[test]
+    fn mul_ratio_rounds_half_away_from_zero() {
+        assert_eq!(Money::new(1000, "SEK").mul_ratio(25, 100).minor(), 250);
+        assert_eq!(Money::new(2, "SEK").mul_ratio(1, 4).minor(), 1);
+        assert_eq!(Money::new(1, "SEK").mul_ratio(1, 4).minor(), 0);
+        assert_eq!(Money::new(-2, "SEK").mul_ratio(1, 4).minor(), -1);
+        assert_eq!(Money::new(12500, "SEK").mul_ratio(25, 125).minor(), 2500);
+        assert_eq!(Money::new(999, "SEK").mul_ratio(12, 112).minor(), 107);
+    }
+
+    #[test]
+    fn mixed_currencies_are_not_added() {
+        let lines = vec![Money::new(100, "SEK"), Money::new(100, "EUR")];
+        assert_eq!(Money::sum(lines, "SEK"), None);
+    }
+
+    #[test]
+    fn formats_negative_amounts() {
+        assert_eq!(Money::new(-5, "SEK").format_major(), "-0.05");
+        assert_eq!(Money::new(-1234, "SEK").to_string(), "-12.34 SEK");
+    }
+
+    #[test]
+    fn deserializes_both_representations() {
+        let from_number: Money = serde_json::from_str("109.95").unwrap();
+        assert_eq!(from_number, Money::new(10995, DEFAULT_CURRENCY));
+
+        let from_object: Money = serde_json::from_str(r#"{"minor":10995,"currency":"eur"}"#).unwrap();
+        assert_eq!(from_object, Money::new(10995, "EUR"));
+
+        let round_trip: Money = serde_json::from_str(&serde_json::to_string(&from_object).unwrap()).unwrap();
+        assert_eq!(round_trip, from_object);
+    }
+}